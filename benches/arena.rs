@@ -0,0 +1,74 @@
+//! BoxのASTとアリーナのASTで、大きな式の構築と破棄にかかる時間を比べる
+//!
+//! `cargo bench --bench arena` で実行する
+
+use std::{hint::black_box, time::{Duration, Instant}};
+
+use parser::Loc;
+use parser::arena::{AstArena, NodeId};
+use parser::parser::{Ast, BinOp};
+
+// 1つの式の葉の数
+const LEAVES: u64 = 1 << 16;
+// 計測の繰り返し回数
+const ROUNDS: u32 = 50;
+
+/// lo..hiの葉を足し合わせる平衡二分木をBoxで作る
+fn build_boxed(lo: u64, hi: u64) -> Ast {
+    if hi - lo == 1 {
+        return Ast::num(lo, Loc(0, 0));
+    }
+    let mid = (lo + hi) / 2;
+    Ast::binop(BinOp::add(Loc(0, 0)), build_boxed(lo, mid), build_boxed(mid, hi), Loc(0, 0))
+}
+
+/// 同じ木をアリーナに作る
+fn build_arena(arena: &mut AstArena, lo: u64, hi: u64) -> NodeId {
+    if hi - lo == 1 {
+        return arena.num(lo, Loc(0, 0));
+    }
+    let mid = (lo + hi) / 2;
+    let l = build_arena(arena, lo, mid);
+    let r = build_arena(arena, mid, hi);
+    arena.binop(BinOp::add(Loc(0, 0)), l, r, Loc(0, 0))
+}
+
+fn measure(name: &str, mut f: impl FnMut()) {
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        f();
+        total += start.elapsed();
+    }
+    println!("{:<28} {:>10.3?} / round", name, total / ROUNDS);
+}
+
+fn main() {
+    println!("{} leaves, {} nodes", LEAVES, 2 * LEAVES - 1);
+
+    measure("boxed: build + drop", || {
+        drop(black_box(build_boxed(0, LEAVES)));
+    });
+    measure("arena: build + drop", || {
+        let mut arena = AstArena::new();
+        black_box(build_arena(&mut arena, 0, LEAVES));
+        drop(black_box(arena));
+    });
+    // Vecを使い回せば確保は最初の1回だけになる
+    let mut arena = AstArena::with_capacity(2 * LEAVES as usize);
+    measure("arena: build (reused)", || {
+        arena.clear();
+        black_box(build_arena(&mut arena, 0, LEAVES));
+    });
+
+    let boxed = build_boxed(0, LEAVES);
+    measure("boxed -> arena", || {
+        let mut arena = AstArena::new();
+        black_box(arena.alloc_ast(&boxed));
+    });
+    let mut arena = AstArena::new();
+    let root = arena.alloc_ast(&boxed);
+    measure("arena -> boxed", || {
+        black_box(arena.to_ast(root));
+    });
+}
@@ -0,0 +1,50 @@
+#include <stdint.h>
+
+#ifndef EXPR_OK
+#define EXPR_OK 0
+#define EXPR_DIVISION_BY_ZERO 1
+#define EXPR_OVERFLOW 2
+#define EXPR_NEGATIVE_FACTORIAL 3
+
+static inline int expr_factorial(int64_t n, int64_t *result)
+{
+    int64_t r = 1;
+    if (n < 0) return EXPR_NEGATIVE_FACTORIAL;
+    for (int64_t i = 2; i <= n; i++)
+        if (__builtin_mul_overflow(r, i, &r)) return EXPR_OVERFLOW;
+    *result = r;
+    return EXPR_OK;
+}
+
+static inline int expr_pow(int64_t l, int64_t r, int64_t *result)
+{
+    int64_t n = 1;
+    if (l == 0) {
+        if (r < 0) return EXPR_DIVISION_BY_ZERO;
+        *result = r == 0;
+        return EXPR_OK;
+    }
+    if (l == 1 || l == -1) {
+        *result = l == -1 && r % 2 != 0 ? -1 : 1;
+        return EXPR_OK;
+    }
+    for (; r > 0; r--)
+        if (__builtin_mul_overflow(n, l, &n)) return EXPR_OVERFLOW;
+    /* a negative exponent gives 1 / l^(-r), which truncates to 0 */
+    *result = r < 0 ? 0 : n;
+    return EXPR_OK;
+}
+#endif
+
+int f(int64_t v_x, int64_t v_y, int64_t *result)
+{
+    int64_t t0, t1, t2, t3;
+    if (__builtin_add_overflow(v_x, INT64_C(1), &t0)) return EXPR_OVERFLOW;
+    if (__builtin_mul_overflow(t0, v_y, &t1)) return EXPR_OVERFLOW;
+    if (__builtin_sub_overflow(v_x, v_y, &t2)) return EXPR_OVERFLOW;
+    if (t2 == 0) return EXPR_DIVISION_BY_ZERO;
+    if (t1 == INT64_MIN && t2 == -1) return EXPR_OVERFLOW;
+    t3 = t1 / t2;
+    *result = t3;
+    return EXPR_OK;
+}
@@ -0,0 +1,146 @@
+(module
+  (global $error (export "error") (mut i32) (i32.const 0))
+
+  ;; keep only the first error that occurred
+  (func $fail (param $code i32)
+    (if (i32.eqz (global.get $error))
+      (then (global.set $error (local.get $code)))))
+
+  (func $add (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (local.set $n (i64.add (local.get $l) (local.get $r)))
+    ;; overflow if the sign of the result differs from both operands
+    (if (i64.lt_s (i64.and (i64.xor (local.get $l) (local.get $n))
+                           (i64.xor (local.get $r) (local.get $n)))
+                  (i64.const 0))
+      (then (call $fail (i32.const 2))))
+    (local.get $n))
+
+  (func $sub (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (local.set $n (i64.sub (local.get $l) (local.get $r)))
+    ;; overflow if the operand signs differ and the result sign differs from the left
+    (if (i64.lt_s (i64.and (i64.xor (local.get $l) (local.get $r))
+                           (i64.xor (local.get $l) (local.get $n)))
+                  (i64.const 0))
+      (then (call $fail (i32.const 2))))
+    (local.get $n))
+
+  (func $mul (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (local.set $n (i64.mul (local.get $l) (local.get $r)))
+    ;; overflow if dividing back does not give the operand; check -1 * MIN first since its division traps
+    (if (i64.eq (local.get $l) (i64.const -1))
+      (then
+        (if (i64.eq (local.get $r) (i64.const 0x8000000000000000))
+          (then (call $fail (i32.const 2)))))
+      (else
+        (if (i64.ne (local.get $l) (i64.const 0))
+          (then
+            (if (i64.ne (i64.div_s (local.get $n) (local.get $l)) (local.get $r))
+              (then (call $fail (i32.const 2))))))))
+    (local.get $n))
+
+  (func $div (param $l i64) (param $r i64) (result i64)
+    (if (i64.eqz (local.get $r))
+      (then
+        (call $fail (i32.const 1))
+        (return (i64.const 0))))
+    (if (i32.and (i64.eq (local.get $l) (i64.const 0x8000000000000000))
+                 (i64.eq (local.get $r) (i64.const -1)))
+      (then
+        (call $fail (i32.const 2))
+        (return (i64.const 0))))
+    (i64.div_s (local.get $l) (local.get $r)))
+
+  ;; arithmetic with a percent right operand, checked in the same order as a +- (a * b)%, (a * b)% and a * 100 / b
+  (func $mul_pct (param $l i64) (param $r i64) (result i64)
+    (i64.div_s (call $mul (local.get $l) (local.get $r)) (i64.const 100)))
+
+  (func $add_pct (param $l i64) (param $r i64) (result i64)
+    (call $add (local.get $l) (call $mul_pct (local.get $l) (local.get $r))))
+
+  (func $sub_pct (param $l i64) (param $r i64) (result i64)
+    (call $sub (local.get $l) (call $mul_pct (local.get $l) (local.get $r))))
+
+  (func $div_pct (param $l i64) (param $r i64) (result i64)
+    (call $div (call $mul (local.get $l) (i64.const 100)) (local.get $r)))
+
+  ;; a literal that does not fit in i64
+  (func $overflow (result i64)
+    (call $fail (i32.const 2))
+    (i64.const 0))
+
+  (func $neg (param $n i64) (result i64)
+    (if (i64.eq (local.get $n) (i64.const 0x8000000000000000))
+      (then
+        (call $fail (i32.const 2))
+        (return (i64.const 0))))
+    (i64.sub (i64.const 0) (local.get $n)))
+
+  (func $fact (param $n i64) (result i64)
+    (local $i i64)
+    (local $r i64)
+    (if (i64.lt_s (local.get $n) (i64.const 0))
+      (then
+        (call $fail (i32.const 3))
+        (return (i64.const 0))))
+    (local.set $i (i64.const 2))
+    (local.set $r (i64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i64.gt_s (local.get $i) (local.get $n)))
+        ;; check that r * i does not exceed the maximum before multiplying
+        (if (i64.gt_s (local.get $r) (i64.div_s (i64.const 0x7fffffffffffffff) (local.get $i)))
+          (then
+            (call $fail (i32.const 2))
+            (return (i64.const 0))))
+        (local.set $r (i64.mul (local.get $r) (local.get $i)))
+        (local.set $i (i64.add (local.get $i) (i64.const 1)))
+        (br $next)))
+    (local.get $r))
+
+  (func $pow (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (if (i64.eqz (local.get $l))
+      (then
+        (if (i64.lt_s (local.get $r) (i64.const 0))
+          (then
+            (call $fail (i32.const 1))
+            (return (i64.const 0))))
+        (return (i64.extend_i32_u (i64.eqz (local.get $r))))))
+    (if (i64.eq (local.get $l) (i64.const 1))
+      (then (return (i64.const 1))))
+    (if (i64.eq (local.get $l) (i64.const -1))
+      (then
+        (return (select (i64.const -1) (i64.const 1)
+                        (i32.wrap_i64 (i64.and (local.get $r) (i64.const 1)))))))
+    ;; a negative exponent gives 1 / l^(-r), which truncates to 0
+    (if (i64.lt_s (local.get $r) (i64.const 0))
+      (then (return (i64.const 0))))
+    (local.set $n (i64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i64.eqz (local.get $r)))
+        ;; overflow if dividing back does not give the operand; |l| >= 2 so the division cannot trap
+        (if (i64.ne (i64.div_s (i64.mul (local.get $n) (local.get $l)) (local.get $l)) (local.get $n))
+          (then
+            (call $fail (i32.const 2))
+            (return (i64.const 0))))
+        (local.set $n (i64.mul (local.get $n) (local.get $l)))
+        (local.set $r (i64.sub (local.get $r) (i64.const 1)))
+        (br $next)))
+    (local.get $n))
+
+  (func (export "eval") (param $x i64) (param $y i64) (result i64)
+    (global.set $error (i32.const 0))
+    (call $div
+      (call $mul
+        (call $add
+          (local.get $x)
+          (i64.const 1))
+        (local.get $y))
+      (call $sub
+        (local.get $x)
+        (local.get $y))))
+)
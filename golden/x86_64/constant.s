@@ -0,0 +1,68 @@
+	.text
+	.globl	main
+main:
+	pushq	%rbp
+	movq	%rsp, %rbp
+	subq	$16, %rsp
+	movq	%rsi, -8(%rbp)
+	cmpl	$1, %edi
+	jne	.Lusage
+	movq	$1, %rax
+	pushq	%rax
+	movq	$2, %rax
+	pushq	%rax
+	movq	$3, %rax
+	movq	%rax, %rcx
+	popq	%rax
+	imulq	%rcx, %rax
+	jo	.Loverflow
+	movq	%rax, %rcx
+	popq	%rax
+	addq	%rcx, %rax
+	jo	.Loverflow
+	pushq	%rax
+	movq	$10, %rax
+	negq	%rax
+	jo	.Loverflow
+	movq	%rax, %rcx
+	popq	%rax
+	subq	%rcx, %rax
+	jo	.Loverflow
+	movq	%rax, %rsi
+	leaq	.Lfmt(%rip), %rdi
+	xorl	%eax, %eax
+	call	printf@PLT
+	xorl	%eax, %eax
+	leave
+	ret
+.Ldivzero:
+	leaq	.Ldivzero_msg(%rip), %rdi
+	jmp	.Lfail
+.Loverflow:
+	leaq	.Loverflow_msg(%rip), %rdi
+	jmp	.Lfail
+.Lnegfact:
+	leaq	.Lnegfact_msg(%rip), %rdi
+	jmp	.Lfail
+.Lusage:
+	leaq	.Lusage_msg(%rip), %rdi
+.Lfail:
+	leaq	-16(%rbp), %rsp
+	movq	stderr@GOTPCREL(%rip), %rax
+	movq	(%rax), %rsi
+	call	fputs@PLT
+	movl	$1, %eax
+	leave
+	ret
+	.section	.rodata
+.Lfmt:
+	.string	"%ld\n"
+.Ldivzero_msg:
+	.string	"division by zero\n"
+.Loverflow_msg:
+	.string	"integer overflow\n"
+.Lnegfact_msg:
+	.string	"factorial of a negative number\n"
+.Lusage_msg:
+	.string	"usage: PROGRAM \n"
+	.section	.note.GNU-stack,"",@progbits
@@ -0,0 +1,115 @@
+	.text
+	.globl	main
+main:
+	pushq	%rbp
+	movq	%rsp, %rbp
+	subq	$32, %rsp
+	movq	%rsi, -8(%rbp)
+	cmpl	$3, %edi
+	jne	.Lusage
+	# x = strtoll(argv[1], NULL, 10)
+	movq	-8(%rbp), %rax
+	movq	8(%rax), %rdi
+	xorl	%esi, %esi
+	movl	$10, %edx
+	call	strtoll@PLT
+	movq	%rax, -16(%rbp)
+	# y = strtoll(argv[2], NULL, 10)
+	movq	-8(%rbp), %rax
+	movq	16(%rax), %rdi
+	xorl	%esi, %esi
+	movl	$10, %edx
+	call	strtoll@PLT
+	movq	%rax, -24(%rbp)
+	movq	-16(%rbp), %rax
+	pushq	%rax
+	movq	-24(%rbp), %rax
+	movq	%rax, %rcx
+	popq	%rax
+	call	.Lpow
+	negq	%rax
+	jo	.Loverflow
+	pushq	%rax
+	movq	-16(%rbp), %rax
+	notq	%rax
+	testq	%rax, %rax
+	sete	%al
+	movzbl	%al, %eax
+	movq	%rax, %rcx
+	popq	%rax
+	addq	%rcx, %rax
+	jo	.Loverflow
+	movq	%rax, %rsi
+	leaq	.Lfmt(%rip), %rdi
+	xorl	%eax, %eax
+	call	printf@PLT
+	xorl	%eax, %eax
+	leave
+	ret
+.Ldivzero:
+	leaq	.Ldivzero_msg(%rip), %rdi
+	jmp	.Lfail
+.Loverflow:
+	leaq	.Loverflow_msg(%rip), %rdi
+	jmp	.Lfail
+.Lnegfact:
+	leaq	.Lnegfact_msg(%rip), %rdi
+	jmp	.Lfail
+.Lusage:
+	leaq	.Lusage_msg(%rip), %rdi
+.Lfail:
+	leaq	-32(%rbp), %rsp
+	movq	stderr@GOTPCREL(%rip), %rax
+	movq	(%rax), %rsi
+	call	fputs@PLT
+	movl	$1, %eax
+	leave
+	ret
+.Lpow:
+	testq	%rax, %rax
+	jne	.Lpow_unit
+	# 0^0 is 1, 0 to a negative power is a division by zero
+	testq	%rcx, %rcx
+	js	.Ldivzero
+	sete	%al
+	ret
+.Lpow_unit:
+	cmpq	$1, %rax
+	je	.Lpow_ret
+	cmpq	$-1, %rax
+	jne	.Lpow_frac
+	# -1 to an odd power is -1, to an even power 1
+	testb	$1, %cl
+	jne	.Lpow_ret
+	negq	%rax
+	ret
+.Lpow_frac:
+	# a negative exponent gives 1 / l^(-r), which truncates to 0
+	testq	%rcx, %rcx
+	jns	.Lpow_start
+	xorl	%eax, %eax
+	ret
+.Lpow_start:
+	movq	%rax, %rdx
+	movl	$1, %eax
+.Lpow_loop:
+	testq	%rcx, %rcx
+	je	.Lpow_ret
+	imulq	%rdx, %rax
+	jo	.Loverflow
+	decq	%rcx
+	jmp	.Lpow_loop
+.Lpow_ret:
+	ret
+	.section	.rodata
+.Lfmt:
+	.string	"%ld\n"
+.Ldivzero_msg:
+	.string	"division by zero\n"
+.Loverflow_msg:
+	.string	"integer overflow\n"
+.Lnegfact_msg:
+	.string	"factorial of a negative number\n"
+.Lusage_msg:
+	.string	"usage: PROGRAM x y\n"
+	.section	.note.GNU-stack,"",@progbits
@@ -0,0 +1,94 @@
+	.text
+	.globl	main
+main:
+	pushq	%rbp
+	movq	%rsp, %rbp
+	subq	$32, %rsp
+	movq	%rsi, -8(%rbp)
+	cmpl	$3, %edi
+	jne	.Lusage
+	# x = strtoll(argv[1], NULL, 10)
+	movq	-8(%rbp), %rax
+	movq	8(%rax), %rdi
+	xorl	%esi, %esi
+	movl	$10, %edx
+	call	strtoll@PLT
+	movq	%rax, -16(%rbp)
+	# y = strtoll(argv[2], NULL, 10)
+	movq	-8(%rbp), %rax
+	movq	16(%rax), %rdi
+	xorl	%esi, %esi
+	movl	$10, %edx
+	call	strtoll@PLT
+	movq	%rax, -24(%rbp)
+	movq	-16(%rbp), %rax
+	pushq	%rax
+	movq	$1, %rax
+	movq	%rax, %rcx
+	popq	%rax
+	addq	%rcx, %rax
+	jo	.Loverflow
+	pushq	%rax
+	movq	-24(%rbp), %rax
+	movq	%rax, %rcx
+	popq	%rax
+	imulq	%rcx, %rax
+	jo	.Loverflow
+	pushq	%rax
+	movq	-16(%rbp), %rax
+	pushq	%rax
+	movq	-24(%rbp), %rax
+	movq	%rax, %rcx
+	popq	%rax
+	subq	%rcx, %rax
+	jo	.Loverflow
+	movq	%rax, %rcx
+	popq	%rax
+	testq	%rcx, %rcx
+	je	.Ldivzero
+	cmpq	$-1, %rcx
+	jne	.L0
+	movabsq	$-9223372036854775808, %rdx
+	cmpq	%rdx, %rax
+	je	.Loverflow
+.L0:
+	cqto
+	idivq	%rcx
+	movq	%rax, %rsi
+	leaq	.Lfmt(%rip), %rdi
+	xorl	%eax, %eax
+	call	printf@PLT
+	xorl	%eax, %eax
+	leave
+	ret
+.Ldivzero:
+	leaq	.Ldivzero_msg(%rip), %rdi
+	jmp	.Lfail
+.Loverflow:
+	leaq	.Loverflow_msg(%rip), %rdi
+	jmp	.Lfail
+.Lnegfact:
+	leaq	.Lnegfact_msg(%rip), %rdi
+	jmp	.Lfail
+.Lusage:
+	leaq	.Lusage_msg(%rip), %rdi
+.Lfail:
+	leaq	-32(%rbp), %rsp
+	movq	stderr@GOTPCREL(%rip), %rax
+	movq	(%rax), %rsi
+	call	fputs@PLT
+	movl	$1, %eax
+	leave
+	ret
+	.section	.rodata
+.Lfmt:
+	.string	"%ld\n"
+.Ldivzero_msg:
+	.string	"division by zero\n"
+.Loverflow_msg:
+	.string	"integer overflow\n"
+.Lnegfact_msg:
+	.string	"factorial of a negative number\n"
+.Lusage_msg:
+	.string	"usage: PROGRAM x y\n"
+	.section	.note.GNU-stack,"",@progbits
@@ -0,0 +1,233 @@
+use std::ops::Index;
+
+use crate::utils::{Annot, Loc};
+use crate::parser::{Ast, AstKind, UniOp, BinOp};
+
+/// アリーナ上の節点を指す番号
+/// 節点を作ったアリーナでだけ意味を持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// アリーナ上のASTの節点。子はBoxではなくNodeIdで指す
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    /// 数値
+    Num(u64),
+    /// 変数
+    Var(String),
+    /// 単項演算
+    UniOp {op: UniOp, e: NodeId},
+    /// 二項演算
+    BinOp {op: BinOp, l: NodeId, r: NodeId},
+    /// 組
+    Tuple(Vec<NodeId>),
+}
+
+pub type Node = Annot<NodeKind>;
+
+/// ASTの節点を1つのVecにまとめて持つアリーナ
+///
+/// 節点ごとにBoxを確保せず、捨てるときも再帰しない。
+/// 子は必ず親より先に作られるので、子のNodeIdは親のNodeIdより小さい
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct AstArena {
+    nodes: Vec<Node>,
+}
+
+impl AstArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// n個の節点を確保し直さずに持てるアリーナを作る
+    pub fn with_capacity(n: usize) -> Self {
+        AstArena { nodes: Vec::with_capacity(n) }
+    }
+
+    /// 全ての節点を捨てる。確保した領域は使い回す
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    /// 節点の数
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// 確保した順に並んだ全ての節点。子は必ず親より前にある
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    fn alloc(&mut self, value: NodeKind, loc: Loc) -> NodeId {
+        let id = NodeId(u32::try_from(self.nodes.len()).expect("too many nodes"));
+        self.nodes.push(Node::new(value, loc));
+        id
+    }
+
+    pub fn num(&mut self, n: u64, loc: Loc) -> NodeId {
+        self.alloc(NodeKind::Num(n), loc)
+    }
+
+    pub fn var(&mut self, name: &str, loc: Loc) -> NodeId {
+        self.alloc(NodeKind::Var(name.to_string()), loc)
+    }
+
+    pub fn uniop(&mut self, op: UniOp, e: NodeId, loc: Loc) -> NodeId {
+        self.alloc(NodeKind::UniOp {op, e}, loc)
+    }
+
+    pub fn binop(&mut self, op: BinOp, l: NodeId, r: NodeId, loc: Loc) -> NodeId {
+        self.alloc(NodeKind::BinOp {op, l, r}, loc)
+    }
+
+    pub fn tuple(&mut self, es: Vec<NodeId>, loc: Loc) -> NodeId {
+        self.alloc(NodeKind::Tuple(es), loc)
+    }
+
+    /// BoxのASTをアリーナに移し、その根を返す
+    /// 節点は後行順（子を左から順に確保してから親）に確保する。深い木でも再帰しない
+    pub fn alloc_ast(&mut self, expr: &Ast) -> NodeId {
+        // 子をたどる前か、たどった後か
+        enum Visit<'a> {
+            Enter(&'a Ast),
+            Exit(&'a Ast),
+        }
+
+        let mut stack = vec![Visit::Enter(expr)];
+        // 確保済みで、まだ親に渡していない節点
+        let mut ids: Vec<NodeId> = Vec::new();
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(e) => {
+                    stack.push(Visit::Exit(e));
+                    // 左の子を先に処理するので右の子から積む
+                    stack.extend(e.children().rev().map(Visit::Enter));
+                },
+                Visit::Exit(e) => {
+                    let loc = e.loc.clone();
+                    let id = match e.value {
+                        AstKind::Num(n) => self.num(n, loc),
+                        AstKind::Var(ref name) => self.var(name, loc),
+                        AstKind::UniOp { ref op, .. } => {
+                            let e = ids.pop().unwrap();
+                            self.uniop(op.clone(), e, loc)
+                        },
+                        AstKind::BinOp { ref op, .. } => {
+                            let r = ids.pop().unwrap();
+                            let l = ids.pop().unwrap();
+                            self.binop(op.clone(), l, r, loc)
+                        },
+                        AstKind::Tuple(ref es) => {
+                            let es = ids.split_off(ids.len() - es.len());
+                            self.tuple(es, loc)
+                        },
+                    };
+                    ids.push(id);
+                },
+            }
+        }
+        ids.pop().unwrap()
+    }
+
+    /// idを根とする部分木をBoxのASTにする。深い木でも再帰しない
+    /// 複数の親から指される節点は、親ごとに別の部分木になる
+    pub fn to_ast(&self, id: NodeId) -> Ast {
+        enum Visit {
+            Enter(NodeId),
+            Exit(NodeId),
+        }
+
+        let mut stack = vec![Visit::Enter(id)];
+        // 作り終えて、まだ親に渡していない部分木
+        let mut asts: Vec<Ast> = Vec::new();
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(id) => {
+                    stack.push(Visit::Exit(id));
+                    match self[id].value {
+                        NodeKind::Num(_) | NodeKind::Var(_) => (),
+                        NodeKind::UniOp { e, .. } => stack.push(Visit::Enter(e)),
+                        NodeKind::BinOp { l, r, .. } => {
+                            stack.push(Visit::Enter(r));
+                            stack.push(Visit::Enter(l));
+                        },
+                        NodeKind::Tuple(ref es) => stack.extend(es.iter().rev().map(|&e| Visit::Enter(e))),
+                    }
+                },
+                Visit::Exit(id) => {
+                    let node = &self[id];
+                    let loc = node.loc.clone();
+                    let ast = match node.value {
+                        NodeKind::Num(n) => Ast::num(n, loc),
+                        NodeKind::Var(ref name) => Ast::var(name, loc),
+                        NodeKind::UniOp { ref op, .. } => {
+                            let e = asts.pop().unwrap();
+                            Ast::uniop(op.clone(), e, loc)
+                        },
+                        NodeKind::BinOp { ref op, .. } => {
+                            let r = asts.pop().unwrap();
+                            let l = asts.pop().unwrap();
+                            Ast::binop(op.clone(), l, r, loc)
+                        },
+                        NodeKind::Tuple(ref es) => {
+                            let es = asts.split_off(asts.len() - es.len());
+                            Ast::tuple(es, loc)
+                        },
+                    };
+                    asts.push(ast);
+                },
+            }
+        }
+        asts.pop().unwrap()
+    }
+}
+
+impl Index<NodeId> for AstArena {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        &self.nodes[id.index()]
+    }
+}
+
+#[test]
+fn test_arena() {
+    let mut arena = AstArena::new();
+    // 1 + -x
+    let one = arena.num(1, Loc(0, 1));
+    let x = arena.var("x", Loc(5, 6));
+    let neg = arena.uniop(UniOp::minus(Loc(4, 5)), x, Loc(4, 6));
+    let root = arena.binop(BinOp::add(Loc(2, 3)), one, neg, Loc(0, 6));
+
+    let ast = "1 + -x".parse::<Ast>().unwrap();
+    assert_eq!(arena.to_ast(root), ast);
+    assert_eq!(arena[root].value, NodeKind::BinOp { op: BinOp::add(Loc(2, 3)), l: one, r: neg });
+
+    let mut other = AstArena::new();
+    let id = other.alloc_ast(&ast);
+    assert_eq!(other, arena);
+    assert_eq!(id, root);
+
+    // 深い木でも変換でスタックが溢れない
+    let mut deep = AstArena::new();
+    let mut root = deep.num(1, Loc(0, 1));
+    for _ in 0..100_000 {
+        let one = deep.num(1, Loc(0, 1));
+        root = deep.binop(BinOp::add(Loc(0, 1)), root, one, Loc(0, 1));
+    }
+    let ast = deep.to_ast(root);
+    let mut other = AstArena::new();
+    assert_eq!(other.alloc_ast(&ast), root);
+    assert_eq!(other, deep);
+}
@@ -0,0 +1,213 @@
+use crate::utils::Loc;
+use crate::error::{CompileError, FormatError, FormatErrorKind};
+use crate::parser::{Ast, AstKind, UniOpKind, BinOpKind, Visit};
+
+/// スタックマシンの命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instr {
+    /// 定数プールの指定した番号の値を積む
+    Const(u32),
+    /// 名前表の指定した番号の変数の値を積む
+    Load(u32),
+    /// 2つ取り出して足した値を積む
+    Add,
+    /// 2つ取り出して引いた値を積む
+    Sub,
+    /// 2つ取り出して掛けた値を積む
+    Mul,
+    /// 2つ取り出して割った値を積む
+    Div,
+    /// 1つ取り出して符号を反転した値を積む
+    Neg,
+    /// 1つ取り出して階乗を積む
+    Fact,
+    /// 1つ取り出して100で割った値を積む
+    Percent,
+    /// 1つ取り出して論理否定を積む
+    Not,
+    /// 1つ取り出してビット反転した値を積む
+    BitNot,
+    /// 2つ取り出してべき乗を積む
+    Pow,
+    /// 2つ取り出して排他的論理和を積む
+    Xor,
+    /// 2つ取り出して、右辺を左辺に対する百分率として足した値を積む
+    AddPct,
+    /// 2つ取り出して、右辺を左辺に対する百分率として引いた値を積む
+    SubPct,
+    /// 2つ取り出して、右辺を百分率として掛けた値を積む
+    MulPct,
+    /// 2つ取り出して、右辺を百分率として割った値を積む
+    DivPct,
+}
+
+/// コンパイル済みのバイトコード
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Chunk {
+    /// 定数プール
+    pub consts: Vec<i64>,
+    /// 変数の名前表
+    pub names: Vec<String>,
+    /// 命令列
+    pub code: Vec<Instr>,
+    /// 各命令に対応するソース上の位置。codeと同じ長さを持つ
+    pub spans: Vec<Loc>,
+}
+
+impl Chunk {
+    /// VMがパニックせずに実行できるか検査する
+    /// 定数と変数の番号が範囲内にあり、スパンテーブルが命令列と同じ長さで、
+    /// 実行するとスタックに値がちょうど1つ残ることを確かめる。
+    /// エラーの位置情報は問題のある命令の番号の区間 `Loc(pc, pc + 1)` を表す
+    pub fn verify(&self) -> Result<(), FormatError> {
+        let at = |pc: usize, kind| Err(FormatError::new(kind, Loc(pc, pc + 1)));
+
+        if self.spans.len() != self.code.len() {
+            return at(self.code.len().min(self.spans.len()), FormatErrorKind::SpanCountMismatch);
+        }
+        let mut depth: usize = 0;
+        for (pc, instr) in self.code.iter().enumerate() {
+            let (pops, pushes) = match *instr {
+                Instr::Const(idx) if idx as usize >= self.consts.len() => {
+                    return at(pc, FormatErrorKind::ConstOutOfRange(idx))
+                },
+                Instr::Load(idx) if idx as usize >= self.names.len() => {
+                    return at(pc, FormatErrorKind::NameOutOfRange(idx))
+                },
+                Instr::Const(_) | Instr::Load(_) => (0, 1),
+                Instr::Neg | Instr::Fact | Instr::Percent | Instr::Not | Instr::BitNot => (1, 1),
+                Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Pow | Instr::Xor => (2, 1),
+                Instr::AddPct | Instr::SubPct | Instr::MulPct | Instr::DivPct => (2, 1),
+            };
+            depth = match depth.checked_sub(pops) {
+                Some(depth) => depth + pushes,
+                None => return at(pc, FormatErrorKind::StackUnderflow),
+            };
+        }
+        // 値が残らないか余るなら、最後の命令を指す
+        if depth != 1 {
+            return at(self.code.len().saturating_sub(1), FormatErrorKind::UnbalancedStack(depth));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, instr: Instr, loc: Loc) {
+        self.code.push(instr);
+        self.spans.push(loc);
+    }
+
+    /// 定数を定数プールに追加してその番号を返す。同じ値は使い回す
+    fn add_const(&mut self, n: i64) -> u32 {
+        let idx = match self.consts.iter().position(|&c| c == n) {
+            Some(idx) => idx,
+            None => {
+                self.consts.push(n);
+                self.consts.len() - 1
+            },
+        };
+        idx as u32
+    }
+
+    /// 変数名を名前表に追加してその番号を返す。同じ名前は使い回す
+    fn add_name(&mut self, name: &str) -> u32 {
+        let idx = match self.names.iter().position(|n| n == name) {
+            Some(idx) => idx,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            },
+        };
+        idx as u32
+    }
+}
+
+/// バイトコードへのコンパイラを表すデータ型
+/// 命令は数値だけを扱うので、組を含む式は `CompileErrorKind::UnsupportedTuple` のエラーになる
+#[derive(Default)]
+pub struct BytecodeCompiler;
+
+impl BytecodeCompiler {
+    pub fn new() -> Self {
+        BytecodeCompiler
+    }
+
+    /// ASTを後行順にたどって命令を出力する
+    /// 深くネストした式でもネイティブスタックを使い切らないよう、再帰せずに木をたどる
+    pub fn compile(&mut self, expr: &Ast) -> Result<Chunk, CompileError> {
+        let mut chunk = Chunk::default();
+        let mut walk = expr.walk();
+        while let Some(visit) = walk.next() {
+            match visit {
+                Visit::Enter(e) if matches!(e.value, AstKind::Tuple(_)) => {
+                    return Err(CompileError::unsupported_tuple("bytecode", e.loc.clone()))
+                },
+                // -2^63は負号と合わせてi64::MINの定数にする
+                Visit::Enter(e) if e.is_min_literal() => {
+                    walk.skip_children();
+                    let idx = chunk.add_const(i64::MIN);
+                    chunk.push(Instr::Const(idx), e.loc.clone());
+                },
+                Visit::Exit(e) if e.is_min_literal() => (),
+                Visit::Exit(e) => self.compile_node(e, &mut chunk),
+                Visit::Enter(_) | Visit::Between(..) => (),
+            }
+        }
+        Ok(chunk)
+    }
+
+    fn compile_node(&mut self, expr: &Ast, chunk: &mut Chunk) {
+        let loc = expr.loc.clone();
+        let instr = match expr.value {
+            AstKind::Num(n) => match i64::try_from(n) {
+                Ok(n) => Instr::Const(chunk.add_const(n)),
+                // i64に収まらない数値は、その位置でオーバーフローするよう i64::MIN の符号を反転する
+                Err(_) => {
+                    let idx = chunk.add_const(i64::MIN);
+                    chunk.push(Instr::Const(idx), loc.clone());
+                    Instr::Neg
+                },
+            },
+            AstKind::Var(ref name) => Instr::Load(chunk.add_name(name)),
+            AstKind::UniOp { ref op, .. } => match op.value {
+                // 正号は値を変えないので何も出力しない
+                UniOpKind::Plus => return,
+                UniOpKind::Minus => Instr::Neg,
+                UniOpKind::Factorial => Instr::Fact,
+                UniOpKind::Percent => Instr::Percent,
+                UniOpKind::Not => Instr::Not,
+                UniOpKind::BitNot => Instr::BitNot,
+            },
+            AstKind::BinOp { ref op, .. } => match op.value {
+                BinOpKind::Add => Instr::Add,
+                BinOpKind::Sub => Instr::Sub,
+                BinOpKind::Mult => Instr::Mul,
+                BinOpKind::Div => Instr::Div,
+                BinOpKind::Pow => Instr::Pow,
+                BinOpKind::BitXor => Instr::Xor,
+                BinOpKind::AddPercent => Instr::AddPct,
+                BinOpKind::SubPercent => Instr::SubPct,
+                BinOpKind::MultPercent => Instr::MulPct,
+                BinOpKind::DivPercent => Instr::DivPct,
+            },
+            AstKind::Tuple(_) => unreachable!(),
+        };
+        chunk.push(instr, loc);
+    }
+}
+
+#[test]
+fn test_bytecode() {
+    // 1 + 2 * 3 - -1
+    let ast = "1 + 2 * 3 - -1".parse::<Ast>().unwrap();
+    let chunk = BytecodeCompiler::new().compile(&ast).unwrap();
+
+    use self::Instr::*;
+    assert_eq!(chunk.consts, vec![1, 2, 3]);
+    assert_eq!(chunk.code, vec![Const(0), Const(1), Const(2), Mul, Add, Const(0), Neg, Sub]);
+    assert_eq!(chunk.spans[3], Loc(4, 9));
+    assert_eq!(chunk.spans[6], Loc(12, 14));
+
+    // 組はコンパイルできない
+    let ast = "1 + (2, 3)".parse::<Ast>().unwrap();
+    assert_eq!(BytecodeCompiler::new().compile(&ast), Err(CompileError::unsupported_tuple("bytecode", Loc(5, 9))));
+}
@@ -0,0 +1,308 @@
+use crate::utils::Loc;
+use crate::bytecode::{Chunk, Instr};
+use crate::error::{FormatError, FormatErrorKind};
+
+// バイトコードファイルの形式（数値は全てリトルエンディアン）
+//
+//   magic     b"EXBC"
+//   version   u16
+//   consts    u32 個数, i64 * 個数
+//   names     u32 個数, (u32 バイト数, UTF-8 の変数名) * 個数（バージョン2以降）
+//   code      u32 個数, 命令 * 個数（opcode u8, Const・Loadは続けてu32の番号）
+//   spans     u32 個数, (u64 開始, u64 終了) * 個数
+
+/// ファイル先頭のマジックナンバー
+pub const MAGIC: &[u8; 4] = b"EXBC";
+
+/// 現在のファイル形式のバージョン
+/// 1: 最初の形式。Const、Add、Sub、Mul、Div、Neg命令だけを持つ
+/// 2: 変数の名前表と、Load命令およびそれ以降に加えた全ての命令を追加
+pub const VERSION: u16 = 2;
+
+// 命令のオペコード
+const OP_CONST: u8 = 0x01;
+const OP_LOAD: u8 = 0x02;
+const OP_ADD: u8 = 0x10;
+const OP_SUB: u8 = 0x11;
+const OP_MUL: u8 = 0x12;
+const OP_DIV: u8 = 0x13;
+const OP_POW: u8 = 0x14;
+const OP_XOR: u8 = 0x15;
+const OP_ADD_PCT: u8 = 0x16;
+const OP_SUB_PCT: u8 = 0x17;
+const OP_MUL_PCT: u8 = 0x18;
+const OP_DIV_PCT: u8 = 0x19;
+const OP_NEG: u8 = 0x20;
+const OP_FACT: u8 = 0x21;
+const OP_PERCENT: u8 = 0x22;
+const OP_NOT: u8 = 0x23;
+const OP_BITNOT: u8 = 0x24;
+
+/// バイトコードをファイル形式のバイト列にする
+pub fn to_bytes(chunk: &Chunk) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+
+    buf.extend_from_slice(&(chunk.consts.len() as u32).to_le_bytes());
+    for n in &chunk.consts {
+        buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    buf.extend_from_slice(&(chunk.names.len() as u32).to_le_bytes());
+    for name in &chunk.names {
+        buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+    }
+
+    buf.extend_from_slice(&(chunk.code.len() as u32).to_le_bytes());
+    for instr in &chunk.code {
+        match *instr {
+            Instr::Const(idx) => {
+                buf.push(OP_CONST);
+                buf.extend_from_slice(&idx.to_le_bytes());
+            },
+            Instr::Load(idx) => {
+                buf.push(OP_LOAD);
+                buf.extend_from_slice(&idx.to_le_bytes());
+            },
+            Instr::Add => buf.push(OP_ADD),
+            Instr::Sub => buf.push(OP_SUB),
+            Instr::Mul => buf.push(OP_MUL),
+            Instr::Div => buf.push(OP_DIV),
+            Instr::Pow => buf.push(OP_POW),
+            Instr::Xor => buf.push(OP_XOR),
+            Instr::AddPct => buf.push(OP_ADD_PCT),
+            Instr::SubPct => buf.push(OP_SUB_PCT),
+            Instr::MulPct => buf.push(OP_MUL_PCT),
+            Instr::DivPct => buf.push(OP_DIV_PCT),
+            Instr::Neg => buf.push(OP_NEG),
+            Instr::Fact => buf.push(OP_FACT),
+            Instr::Percent => buf.push(OP_PERCENT),
+            Instr::Not => buf.push(OP_NOT),
+            Instr::BitNot => buf.push(OP_BITNOT),
+        }
+    }
+
+    buf.extend_from_slice(&(chunk.spans.len() as u32).to_le_bytes());
+    for loc in &chunk.spans {
+        buf.extend_from_slice(&(loc.0 as u64).to_le_bytes());
+        buf.extend_from_slice(&(loc.1 as u64).to_le_bytes());
+    }
+    buf
+}
+
+/// ファイル形式のバイト列を読み込んでバイトコードにする
+/// 壊れた入力でもパニックせず、問題のあるバイトの位置をエラーで返す
+pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, FormatError> {
+    let mut r = Reader { bytes, pos: 0 };
+
+    let magic = r.take(MAGIC.len())?;
+    if magic != MAGIC {
+        return Err(FormatError::new(FormatErrorKind::BadMagic, Loc(0, MAGIC.len())));
+    }
+    let start = r.pos;
+    let version = r.u16()?;
+    // 古いバージョンも読めるようにする
+    if !(1..=VERSION).contains(&version) {
+        return Err(FormatError::new(FormatErrorKind::UnsupportedVersion(version), Loc(start, r.pos)));
+    }
+
+    let mut chunk = Chunk::default();
+    let len = r.len()?;
+    for _ in 0..len {
+        chunk.consts.push(r.u64()? as i64);
+    }
+
+    // バージョン1には名前表がない
+    if version >= 2 {
+        let len = r.len()?;
+        for _ in 0..len {
+            let start = r.pos;
+            let n = r.len()?;
+            let name = std::str::from_utf8(r.take(n)?)
+                .map_err(|_| FormatError::new(FormatErrorKind::InvalidName, Loc(start, r.pos)))?;
+            chunk.names.push(name.to_string());
+        }
+    }
+
+    let code_start = r.pos;
+    let len = r.len()?;
+    // 各命令のファイル上の位置。検査エラーの位置に使う
+    let mut instr_locs = Vec::with_capacity(len);
+    for _ in 0..len {
+        let start = r.pos;
+        let op = r.u8()?;
+        // 宣言されたバージョンより後に加えた命令は、そのバージョンには存在しないオペコードとする
+        if version < since(op) {
+            return Err(FormatError::new(FormatErrorKind::InvalidOpcode(op), Loc(start, r.pos)));
+        }
+        let instr = match op {
+            OP_CONST => {
+                let idx = r.u32()?;
+                if idx as usize >= chunk.consts.len() {
+                    let kind = FormatErrorKind::ConstOutOfRange(idx);
+                    return Err(FormatError::new(kind, Loc(start, r.pos)));
+                }
+                Instr::Const(idx)
+            },
+            OP_LOAD => {
+                let idx = r.u32()?;
+                if idx as usize >= chunk.names.len() {
+                    let kind = FormatErrorKind::NameOutOfRange(idx);
+                    return Err(FormatError::new(kind, Loc(start, r.pos)));
+                }
+                Instr::Load(idx)
+            },
+            OP_ADD => Instr::Add,
+            OP_SUB => Instr::Sub,
+            OP_MUL => Instr::Mul,
+            OP_DIV => Instr::Div,
+            OP_POW => Instr::Pow,
+            OP_XOR => Instr::Xor,
+            OP_ADD_PCT => Instr::AddPct,
+            OP_SUB_PCT => Instr::SubPct,
+            OP_MUL_PCT => Instr::MulPct,
+            OP_DIV_PCT => Instr::DivPct,
+            OP_NEG => Instr::Neg,
+            OP_FACT => Instr::Fact,
+            OP_PERCENT => Instr::Percent,
+            OP_NOT => Instr::Not,
+            OP_BITNOT => Instr::BitNot,
+            op => return Err(FormatError::new(FormatErrorKind::InvalidOpcode(op), Loc(start, r.pos))),
+        };
+        chunk.code.push(instr);
+        instr_locs.push(Loc(start, r.pos));
+    }
+
+    let start = r.pos;
+    let len = r.len()?;
+    if len != chunk.code.len() {
+        return Err(FormatError::new(FormatErrorKind::SpanCountMismatch, Loc(start, r.pos)));
+    }
+    for _ in 0..len {
+        let start = r.u64()? as usize;
+        let end = r.u64()? as usize;
+        chunk.spans.push(Loc(start, end));
+    }
+
+    if r.pos != bytes.len() {
+        return Err(FormatError::new(FormatErrorKind::TrailingBytes, Loc(r.pos, bytes.len())));
+    }
+    // 命令の番号をファイル上の位置に直す。命令が1つもなければ命令の個数を指す
+    chunk.verify().map_err(|e| {
+        let loc = instr_locs.get(e.loc.0).cloned().unwrap_or(Loc(code_start, code_start + 4));
+        FormatError::new(e.value, loc)
+    })?;
+    Ok(chunk)
+}
+
+/// オペコードが加わったファイル形式のバージョン
+fn since(op: u8) -> u16 {
+    match op {
+        OP_CONST | OP_ADD | OP_SUB | OP_MUL | OP_DIV | OP_NEG => 1,
+        _ => 2,
+    }
+}
+
+/// バイト列を先頭から読む
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        let end = self.pos.checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| FormatError::new(FormatErrorKind::Truncated, Loc(self.pos, self.bytes.len())))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        // takeは要求した長さを返すのでtry_intoは常に成功する
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// 個数を読む。残りのバイト数より多い個数は読む前に弾いて巨大な確保を防ぐ
+    fn len(&mut self) -> Result<usize, FormatError> {
+        let start = self.pos;
+        let len = self.u32()? as usize;
+        if len > self.bytes.len() - self.pos {
+            return Err(FormatError::new(FormatErrorKind::Truncated, Loc(start, self.bytes.len())));
+        }
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_bytecode_file() {
+    use crate::parser::Ast;
+    use crate::bytecode::BytecodeCompiler;
+
+    let ast = "1 + x * 3 - -1".parse::<Ast>().unwrap();
+    let chunk = BytecodeCompiler::new().compile(&ast).unwrap();
+    let bytes = to_bytes(&chunk);
+    assert_eq!(from_bytes(&bytes), Ok(chunk));
+
+    // 途中で切れたファイルは全てエラーになる
+    for len in 0..bytes.len() {
+        assert!(from_bytes(&bytes[..len]).is_err());
+    }
+
+    let mut bad = bytes.clone();
+    bad[4] = 9;
+    assert_eq!(
+        from_bytes(&bad),
+        Err(FormatError::new(FormatErrorKind::UnsupportedVersion(9), Loc(4, 6)))
+    );
+
+    // 最初の命令（Const）を未知のオペコードにする
+    let mut bad = bytes.clone();
+    let code = 4 + 2 + 4 + 8 * 2 + 4 + 4 + 1 + 4;
+    bad[code] = 0xff;
+    assert_eq!(
+        from_bytes(&bad),
+        Err(FormatError::new(FormatErrorKind::InvalidOpcode(0xff), Loc(code, code + 1)))
+    );
+
+    // バージョン1のファイル（名前表なし）も読める
+    let chunk = BytecodeCompiler::new().compile(&"1 - 2".parse::<Ast>().unwrap()).unwrap();
+    let mut v1 = to_bytes(&chunk);
+    v1[4] = 1;
+    let names = 4 + 2 + 4 + 8 * 2;
+    v1.drain(names..names + 4);
+    assert_eq!(from_bytes(&v1), Ok(chunk.clone()));
+
+    // バージョン1にない命令はバージョン1のファイルでは未知のオペコードになる
+    let mut bad = v1.clone();
+    let last = 4 + 2 + 4 + 8 * 2 + 4 + (1 + 4) * 2;
+    bad[last] = OP_XOR;
+    assert_eq!(
+        from_bytes(&bad),
+        Err(FormatError::new(FormatErrorKind::InvalidOpcode(OP_XOR), Loc(last, last + 1)))
+    );
+
+    // 最後の命令（Sub）をNegにすると値が2つ残る。エラーはその命令を指す
+    let mut bad = to_bytes(&chunk);
+    let last = 4 + 2 + 4 + 8 * 2 + 4 + 4 + (1 + 4) * 2;
+    bad[last] = OP_NEG;
+    assert_eq!(
+        from_bytes(&bad),
+        Err(FormatError::new(FormatErrorKind::UnbalancedStack(2), Loc(last, last + 1)))
+    );
+}
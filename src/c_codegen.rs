@@ -0,0 +1,357 @@
+use crate::parser::{Ast, AstKind, UniOpKind, BinOpKind, Visit};
+use crate::error::CompileError;
+
+/// C言語のソースコードへのコンパイラを表すデータ型
+///
+/// 式を `int NAME(int64_t v_x, ..., int64_t *result)` という関数にする。
+/// 引数は式に現れる変数を名前順に並べたもので、戻り値はエラーコード
+/// （`EXPR_OK`, `EXPR_DIVISION_BY_ZERO`, `EXPR_OVERFLOW`, `EXPR_NEGATIVE_FACTORIAL`）。
+/// 演算は1つずつ一時変数に代入し、Interpreterと同じ順序で
+/// ゼロ除算とオーバーフローを検査するので、最初に起きたエラーも同じになる。
+/// 組を含む式は `CompileErrorKind::UnsupportedTuple` のエラーになる
+pub struct CCompiler {
+    // 生成する関数名
+    name: String,
+    // 次に使う一時変数の番号
+    temps: usize,
+}
+
+impl CCompiler {
+    pub fn new(name: &str) -> Self {
+        CCompiler { name: name.to_string(), temps: 0 }
+    }
+
+    pub fn compile(&mut self, expr: &Ast) -> Result<String, CompileError> {
+        if let Some(t) = expr.find_tuple() {
+            return Err(CompileError::unsupported_tuple("C", t.loc.clone()));
+        }
+        self.temps = 0;
+        let mut body = String::new();
+        let result = self.compile_body(expr, &mut body);
+
+        let mut params: Vec<String> = expr.vars().iter().map(|v| format!("int64_t v_{}", v)).collect();
+        params.push("int64_t *result".to_string());
+
+        let mut buf = String::new();
+        buf.push_str(PRELUDE);
+        buf.push_str(&format!("int {}({})\n{{\n", self.name, params.join(", ")));
+        if self.temps > 0 {
+            let temps: Vec<String> = (0..self.temps).map(|i| format!("t{}", i)).collect();
+            buf.push_str(&format!("    int64_t {};\n", temps.join(", ")));
+        }
+        buf.push_str(&body);
+        buf.push_str(&format!("    *result = {};\n    return EXPR_OK;\n}}\n", result));
+        Ok(buf)
+    }
+
+    /// 式を計算する文をbufに出力し、その値を表すC言語の式を返す
+    /// 深い式でもスタックが溢れないよう、再帰せずに木をたどって評価する順に文を出力する
+    fn compile_body(&mut self, expr: &Ast, buf: &mut String) -> String {
+        use self::AstKind::*;
+
+        // 計算済みの部分式の値を表すC言語の式
+        let mut values: Vec<String> = Vec::new();
+        let mut walk = expr.walk();
+        while let Some(visit) = walk.next() {
+            match visit {
+                // 負の数のリテラルは検査せずにそのまま書ける
+                Visit::Enter(e) if is_negative_literal(e) => {
+                    values.push(literal(e.literal_value().unwrap()));
+                    walk.skip_children();
+                },
+                Visit::Enter(e) => match e.value {
+                    Num(n) => values.push(match i64::try_from(n) {
+                        Ok(n) => literal(n),
+                        // i64に収まらない数値は、評価した時点でオーバーフローとする
+                        Err(_) => {
+                            buf.push_str("    return EXPR_OVERFLOW;\n");
+                            literal(0)
+                        },
+                    }),
+                    Var(ref name) => values.push(format!("v_{}", name)),
+                    UniOp { .. } | BinOp { .. } => (),
+                    // compileで先に調べている
+                    Tuple(_) => unreachable!(),
+                },
+                Visit::Between(..) => (),
+                Visit::Exit(e) if is_negative_literal(e) => (),
+                Visit::Exit(e) => match e.value {
+                    UniOp { ref op, .. } => {
+                        let e = values.pop().unwrap();
+                        let v = self.compile_uniop(&op.value, e, buf);
+                        values.push(v);
+                    },
+                    BinOp { ref op, .. } => {
+                        let r = values.pop().unwrap();
+                        let l = values.pop().unwrap();
+                        let v = self.compile_binop(&op.value, l, r, buf);
+                        values.push(v);
+                    },
+                    Num(_) | Var(_) | Tuple(_) => (),
+                },
+            }
+        }
+        values.pop().unwrap()
+    }
+
+    fn compile_uniop(&mut self, op: &UniOpKind, e: String, buf: &mut String) -> String {
+        match op {
+            UniOpKind::Plus => e,
+            UniOpKind::Minus => {
+                let t = self.temp();
+                buf.push_str(&format!("    if ({} == INT64_MIN) return EXPR_OVERFLOW;\n", e));
+                buf.push_str(&format!("    {} = -{};\n", t, e));
+                t
+            },
+            UniOpKind::Factorial => {
+                let t = self.temp();
+                buf.push_str(&format!(
+                    "    {{ int e = expr_factorial({}, &{}); if (e) return e; }}\n", e, t
+                ));
+                t
+            },
+            UniOpKind::Percent => {
+                let t = self.temp();
+                buf.push_str(&format!("    {} = {} / 100;\n", t, e));
+                t
+            },
+            UniOpKind::Not => {
+                let t = self.temp();
+                buf.push_str(&format!("    {} = {} == 0;\n", t, e));
+                t
+            },
+            UniOpKind::BitNot => {
+                let t = self.temp();
+                buf.push_str(&format!("    {} = ~{};\n", t, e));
+                t
+            },
+        }
+    }
+
+    fn compile_binop(&mut self, op: &BinOpKind, l: String, r: String, buf: &mut String) -> String {
+        let t = self.temp();
+        // a ± b% は a ± (a * b)%、a * b% は (a * b)%、a / b% は a * 100 / b と同じ順序で検査する
+        // 途中の値はtに置き、残りの演算の被演算子にする
+        let (l, r, op) = match op {
+            BinOpKind::AddPercent | BinOpKind::SubPercent | BinOpKind::MultPercent => {
+                buf.push_str(&format!(
+                    "    if (__builtin_mul_overflow({}, {}, &{})) return EXPR_OVERFLOW;\n", l, r, t
+                ));
+                buf.push_str(&format!("    {} = {} / 100;\n", t, t));
+                match op.percent_base() {
+                    Some(BinOpKind::Mult) => return t,
+                    base => (l, t.clone(), base.unwrap()),
+                }
+            },
+            BinOpKind::DivPercent => {
+                buf.push_str(&format!(
+                    "    if (__builtin_mul_overflow({}, INT64_C(100), &{})) return EXPR_OVERFLOW;\n", l, t
+                ));
+                (t.clone(), r, BinOpKind::Div)
+            },
+            kind => (l, r, kind.clone()),
+        };
+        let builtin = match op {
+            BinOpKind::Add => "__builtin_add_overflow",
+            BinOpKind::Sub => "__builtin_sub_overflow",
+            BinOpKind::Mult => "__builtin_mul_overflow",
+            BinOpKind::Div => {
+                buf.push_str(&format!("    if ({} == 0) return EXPR_DIVISION_BY_ZERO;\n", r));
+                buf.push_str(&format!(
+                    "    if ({} == INT64_MIN && {} == -1) return EXPR_OVERFLOW;\n", l, r
+                ));
+                buf.push_str(&format!("    {} = {} / {};\n", t, l, r));
+                return t;
+            },
+            BinOpKind::BitXor => {
+                buf.push_str(&format!("    {} = {} ^ {};\n", t, l, r));
+                return t;
+            },
+            BinOpKind::Pow => {
+                buf.push_str(&format!(
+                    "    {{ int e = expr_pow({}, {}, &{}); if (e) return e; }}\n", l, r, t
+                ));
+                return t;
+            },
+            BinOpKind::AddPercent | BinOpKind::SubPercent | BinOpKind::MultPercent | BinOpKind::DivPercent => {
+                unreachable!()
+            },
+        };
+        buf.push_str(&format!(
+            "    if ({}({}, {}, &{})) return EXPR_OVERFLOW;\n", builtin, l, r, t
+        ));
+        t
+    }
+
+    fn temp(&mut self) -> String {
+        let t = format!("t{}", self.temps);
+        self.temps += 1;
+        t
+    }
+}
+
+/// 生成するソースの先頭に置く定義。複数の関数を連結しても衝突しないようにする
+const PRELUDE: &str = "\
+#include <stdint.h>
+
+#ifndef EXPR_OK
+#define EXPR_OK 0
+#define EXPR_DIVISION_BY_ZERO 1
+#define EXPR_OVERFLOW 2
+#define EXPR_NEGATIVE_FACTORIAL 3
+
+static inline int expr_factorial(int64_t n, int64_t *result)
+{
+    int64_t r = 1;
+    if (n < 0) return EXPR_NEGATIVE_FACTORIAL;
+    for (int64_t i = 2; i <= n; i++)
+        if (__builtin_mul_overflow(r, i, &r)) return EXPR_OVERFLOW;
+    *result = r;
+    return EXPR_OK;
+}
+
+static inline int expr_pow(int64_t l, int64_t r, int64_t *result)
+{
+    int64_t n = 1;
+    if (l == 0) {
+        if (r < 0) return EXPR_DIVISION_BY_ZERO;
+        *result = r == 0;
+        return EXPR_OK;
+    }
+    if (l == 1 || l == -1) {
+        *result = l == -1 && r % 2 != 0 ? -1 : 1;
+        return EXPR_OK;
+    }
+    for (; r > 0; r--)
+        if (__builtin_mul_overflow(n, l, &n)) return EXPR_OVERFLOW;
+    /* a negative exponent gives 1 / l^(-r), which truncates to 0 */
+    *result = r < 0 ? 0 : n;
+    return EXPR_OK;
+}
+#endif
+
+";
+
+/// 検査せずにそのまま書ける負の数のリテラルか
+fn is_negative_literal(e: &Ast) -> bool {
+    matches!(e.value, AstKind::UniOp { .. }) && e.literal_value().is_some()
+}
+
+/// 整数リテラル。負の値は演算子の被演算子になっても崩れないよう括弧で囲む
+fn literal(n: i64) -> String {
+    if n == i64::MIN {
+        "INT64_MIN".to_string()
+    } else if n < 0 {
+        format!("(-INT64_C({}))", n.unsigned_abs())
+    } else {
+        format!("INT64_C({})", n)
+    }
+}
+
+#[test]
+fn test_c_codegen() {
+    use std::{fs, process::Command};
+    use crate::interpreter::Interpreter;
+    use crate::error::InterpreterErrorKind;
+    use crate::utils::Loc;
+
+    // (式, ゴールデンファイル, 試す変数の値)
+    let cases: [(&str, &str, &[&[i64]]); 4] = [
+        ("1 + 2 * 3 - -10", include_str!("../golden/c/constant.c"), &[&[]]),
+        (
+            "(x + 1) * y / (x - y)",
+            include_str!("../golden/c/vars.c"),
+            &[&[5, 2], &[3, 3], &[i64::MAX, 1], &[-7, 4]],
+        ),
+        ("x! + y%", include_str!("../golden/c/postfix.c"), &[&[5, 250], &[-1, 0], &[21, -199], &[i64::MAX, 0]]),
+        (
+            "-x ^ y + !~x",
+            include_str!("../golden/c/unary.c"),
+            &[&[3, 3], &[-2, 63], &[2, 63], &[0, -1], &[-1, -3], &[7, -2], &[i64::MAX, 0]],
+        ),
+    ];
+
+    for (input, golden, _) in &cases {
+        let ast = input.parse::<Ast>().unwrap();
+        assert_eq!(&CCompiler::new("f").compile(&ast).unwrap(), golden, "{}", input);
+    }
+    let ast = "-(1, 2)".parse::<Ast>().unwrap();
+    assert_eq!(CCompiler::new("f").compile(&ast), Err(CompileError::unsupported_tuple("C", Loc(2, 6))));
+
+    // Cコンパイラがあれば、ゴールデンファイルを実際に動かしてInterpreterと結果を比べる
+    if Command::new("cc").arg("--version").output().is_err() {
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("parser-c-codegen-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (i, (input, golden, values)) in cases.iter().enumerate() {
+        let ast = input.parse::<Ast>().unwrap();
+        let vars = ast.vars();
+
+        let mut main = String::from("#include <stdio.h>\n#include <inttypes.h>\nint main(void) {\n    int64_t r;\n");
+        let mut expected = String::new();
+        for values in values.iter() {
+            let mut interp = Interpreter::new();
+            let mut args: Vec<String> = Vec::new();
+            for (var, v) in vars.iter().zip(values.iter()) {
+                interp.set_var(var, *v);
+                args.push(format!("INT64_C({})", v));
+            }
+            args.push("&r".to_string());
+            main.push_str(&format!(
+                "    {{ int e = f({}); if (e) printf(\"error %d\\n\", e); else printf(\"%\" PRId64 \"\\n\", r); }}\n",
+                args.join(", ")
+            ));
+            expected.push_str(&match interp.eval(&ast) {
+                Ok(n) => format!("{}\n", n),
+                Err(e) => match e.value {
+                    InterpreterErrorKind::DivisionByZero => "error 1\n".to_string(),
+                    InterpreterErrorKind::Overflow => "error 2\n".to_string(),
+                    InterpreterErrorKind::NegativeFactorial => "error 3\n".to_string(),
+                    InterpreterErrorKind::UnboundVariable(_)
+                    | InterpreterErrorKind::StepLimitExceeded
+                    | InterpreterErrorKind::UnexpectedTuple
+                    | InterpreterErrorKind::InvalidBytecode(_) => unreachable!(),
+                },
+            });
+        }
+        main.push_str("    return 0;\n}\n");
+
+        let src = dir.join(format!("case{}.c", i));
+        let exe = dir.join(format!("case{}", i));
+        fs::write(&src, format!("{}\n{}", golden, main)).unwrap();
+        let status = Command::new("cc").arg("-o").arg(&exe).arg(&src).status().unwrap();
+        assert!(status.success(), "{}", input);
+        let output = Command::new(&exe).output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected, "{}", input);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_c_codegen_literals() {
+    let body = |input: &str| {
+        let c = CCompiler::new("f").compile(&input.parse::<Ast>().unwrap()).unwrap();
+        c[c.find("int f(").unwrap()..].to_string()
+    };
+    assert_eq!(body("-9223372036854775808"), "int f(int64_t *result)\n{\n    *result = INT64_MIN;\n    return EXPR_OK;\n}\n");
+    // i64に収まらない数値は、評価した時点でオーバーフローを返す
+    assert_eq!(
+        body("9223372036854775808"),
+        "int f(int64_t *result)\n{\n    return EXPR_OVERFLOW;\n    *result = INT64_C(0);\n    return EXPR_OK;\n}\n"
+    );
+}
+
+#[test]
+fn test_c_codegen_deep() {
+    use crate::parser::UniOp;
+    use crate::utils::Loc;
+
+    // ~~...~1 のように深くネストした式も再帰せずにコンパイルできる
+    let mut ast = Ast::num(1, Loc(0, 1));
+    for _ in 0..100_000 {
+        ast = Ast::uniop(UniOp::new(UniOpKind::BitNot, Loc(0, 0)), ast, Loc(0, 0));
+    }
+    let c = CCompiler::new("f").compile(&ast).unwrap();
+    assert_eq!(c.matches('~').count(), 100_000);
+}
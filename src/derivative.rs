@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use crate::utils::Loc;
+use crate::parser::{Ast, AstKind, UniOp, UniOpKind, BinOpKind, BinOp, Visit};
+use crate::lexer::is_ident;
+use crate::optimizer::Optimizer;
+use crate::error::{DiffError, DiffErrorKind};
+
+/// 式を変数varで微分し、簡約した式を返す
+/// 和・積・商と定数乗の微分の規則を使う。新しく作った節点には元の節点の位置情報を付ける
+pub fn derive(expr: &Ast, var: &str) -> Result<Ast, DiffError> {
+    if !is_ident(var) {
+        return Err(DiffError::new(DiffErrorKind::InvalidVariable(var.to_string()), expr.loc.clone()));
+    }
+    let d = derive_inner(expr, var)?;
+    Ok(Optimizer::new().optimize(&d))
+}
+
+/// 深い式でもスタックが溢れないよう、再帰せずに子の導関数から順に組み立てる
+fn derive_inner(expr: &Ast, var: &str) -> Result<Ast, DiffError> {
+    use self::AstKind::*;
+
+    // 各節点の部分式がvarを含むか。子から順に求める
+    let mut has_var: HashMap<*const Ast, bool> = HashMap::new();
+    for visit in expr.walk() {
+        if let Visit::Exit(e) = visit {
+            let v = match e.value {
+                Var(ref name) => name == var,
+                _ => e.children().any(|c| has_var[&key(c)]),
+            };
+            has_var.insert(key(e), v);
+        }
+    }
+    // varを含まない式は定数なので導関数は0になる。組は要素ごとに微分する
+    let constant = |e: &Ast| !has_var[&key(e)] && !matches!(e.value, Tuple(_));
+
+    // 微分した子の式
+    let mut ds: Vec<Ast> = Vec::new();
+    let mut walk = expr.walk();
+    while let Some(visit) = walk.next() {
+        let e = match visit {
+            Visit::Enter(e) if constant(e) => {
+                ds.push(Ast::num(0, e.loc.clone()));
+                walk.skip_children();
+                continue;
+            },
+            Visit::Enter(e) => {
+                // 変数を含む式の階乗、論理否定、ビット反転、排他的論理和と、指数に変数を含むべき乗は微分できない
+                let unsupported = match e.value {
+                    UniOp { ref op, .. } => match op.value {
+                        UniOpKind::Factorial => Some("factorial"),
+                        UniOpKind::Not => Some("logical not"),
+                        UniOpKind::BitNot => Some("bitwise complement"),
+                        UniOpKind::Plus | UniOpKind::Minus | UniOpKind::Percent => None,
+                    },
+                    BinOp { ref op, ref r, .. } if op.value == BinOpKind::Pow && has_var[&key(r)] => {
+                        Some("power with a variable exponent")
+                    },
+                    BinOp { ref op, .. } if op.value == BinOpKind::BitXor => Some("xor"),
+                    _ => None,
+                };
+                match unsupported {
+                    Some(name) => return Err(DiffError::new(DiffErrorKind::Unsupported(name.to_string()), e.loc.clone())),
+                    None => continue,
+                }
+            },
+            Visit::Between(..) => continue,
+            Visit::Exit(e) if constant(e) => continue,
+            Visit::Exit(e) => e,
+        };
+
+        let loc = e.loc.clone();
+        let d = match e.value {
+            // x' = 1
+            Var(_) => Ast::num(1, loc),
+            UniOp { ref op, .. } => {
+                let de = ds.pop().unwrap();
+                match op.value {
+                    // (+e)' = e'
+                    UniOpKind::Plus => de,
+                    // (-e)' = -e', (e%)' = e'%
+                    _ => Ast::uniop(op.clone(), de, loc),
+                }
+            },
+            Tuple(ref es) => Ast::tuple(ds.split_off(ds.len() - es.len()), loc),
+            BinOp { ref op, ref l, ref r } => {
+                let dr = ds.pop().unwrap();
+                let dl = ds.pop().unwrap();
+                derive_binop(op, (**l).clone(), dl, (**r).clone(), dr, loc)
+            },
+            Num(_) => unreachable!(),
+        };
+        ds.push(d);
+    }
+    Ok(ds.pop().unwrap())
+}
+
+/// 二項演算の導関数を、被演算子とその導関数から作る
+fn derive_binop(op: &BinOp, l: Ast, dl: Ast, r: Ast, dr: Ast, loc: Loc) -> Ast {
+    let binop = |kind: BinOpKind, l: Ast, r: Ast| {
+        Ast::binop(BinOp::new(kind, op.loc.clone()), l, r, loc.clone())
+    };
+    let percent = |e: Ast| Ast::uniop(UniOp::percent(op.loc.clone()), e, loc.clone());
+    // (l * r)' = l' * r + l * r'
+    let product = |l: Ast, dl: Ast, r: Ast, dr: Ast| {
+        binop(BinOpKind::Add, binop(BinOpKind::Mult, dl, r), binop(BinOpKind::Mult, l, dr))
+    };
+    // (l / r)' = (l' * r - l * r') / (r * r)
+    let quotient = |l: Ast, dl: Ast, r: Ast, dr: Ast| {
+        binop(
+            BinOpKind::Div,
+            binop(BinOpKind::Sub, binop(BinOpKind::Mult, dl, r.clone()), binop(BinOpKind::Mult, l, dr)),
+            binop(BinOpKind::Mult, r.clone(), r),
+        )
+    };
+
+    match op.value {
+        // (l + r)' = l' + r', (l - r)' = l' - r'
+        BinOpKind::Add | BinOpKind::Sub => binop(op.value.clone(), dl, dr),
+        BinOpKind::Mult => product(l, dl, r, dr),
+        BinOpKind::Div => quotient(l, dl, r, dr),
+        // (l ^ c)' = c * l ^ (c - 1) * l'
+        BinOpKind::Pow => binop(
+            BinOpKind::Mult,
+            binop(
+                BinOpKind::Mult,
+                r.clone(),
+                binop(BinOpKind::Pow, l, binop(BinOpKind::Sub, r, Ast::num(1, op.loc.clone()))),
+            ),
+            dl,
+        ),
+        // 百分率つきの四則演算は、基本の演算で書いた式の導関数を直接作る。
+        // 導関数を複製すると入れ子の深さに対して指数的に大きくなるので、l'は1回だけ使う
+        // (l ± r%)' = (l ± (l * r)%)' = (l' * (100 ± r) ± l * r')%
+        BinOpKind::AddPercent | BinOpKind::SubPercent => {
+            let kind = op.value.percent_base().unwrap();
+            let scale = binop(kind.clone(), Ast::num(100, op.loc.clone()), r);
+            percent(binop(kind, binop(BinOpKind::Mult, dl, scale), binop(BinOpKind::Mult, l, dr)))
+        },
+        // (l * r%)' = ((l * r)%)' = (l' * r + l * r')%
+        BinOpKind::MultPercent => percent(product(l, dl, r, dr)),
+        // (l / r%)' = (l * 100 / r)' = (l' * 100 * r - l * 100 * r') / (r * r)
+        BinOpKind::DivPercent => {
+            let hundred = || Ast::num(100, op.loc.clone());
+            quotient(binop(BinOpKind::Mult, l, hundred()), binop(BinOpKind::Mult, dl, hundred()), r, dr)
+        },
+        // 排他的論理和は変数を含めば微分できず、含まなければ定数として扱っている
+        BinOpKind::BitXor => unreachable!(),
+    }
+}
+
+fn key(e: &Ast) -> *const Ast {
+    e
+}
+
+#[test]
+fn test_derive() {
+    use crate::printer::InfixPrinter;
+
+    let mut printer = InfixPrinter::new();
+    let mut derive_str = |input: &str, var: &str| {
+        let ast = input.parse::<Ast>().unwrap();
+        derive(&ast, var).map(|d| printer.print(&d).unwrap())
+    };
+
+    assert_eq!(derive_str("3 * x + 2", "x"), Ok("3".to_string()));
+    assert_eq!(derive_str("x * x - y", "x"), Ok("x + x".to_string()));
+    assert_eq!(derive_str("x * y", "y"), Ok("x".to_string()));
+    assert_eq!(derive_str("-(x * 5)", "x"), Ok("-5".to_string()));
+    assert_eq!(derive_str("1 / x", "x"), Ok("-1 / (x * x)".to_string()));
+    assert_eq!(derive_str("42", "x"), Ok("0".to_string()));
+    assert_eq!(derive_str("x ^ 3 + ~2", "x"), Ok("3 * x ^ 2".to_string()));
+    assert_eq!(
+        derive_str("2 ^ x", "x"),
+        Err(DiffError::new(DiffErrorKind::Unsupported("power with a variable exponent".to_string()), Loc(0, 5)))
+    );
+
+    assert_eq!(
+        derive_str("x + 1", "1x"),
+        Err(DiffError::new(DiffErrorKind::InvalidVariable("1x".to_string()), Loc(0, 5)))
+    );
+    // 微分結果の演算子は元の演算子の位置を指す
+    let ast = "x * x".parse::<Ast>().unwrap();
+    assert_eq!(
+        derive(&ast, "x").unwrap(),
+        Ast::binop(BinOp::add(Loc(2, 3)), Ast::var("x", Loc(4, 5)), Ast::var("x", Loc(0, 1)), Loc(0, 5))
+    );
+}
+
+#[test]
+fn test_derive_percent_of() {
+    use crate::interpreter::Interpreter;
+
+    // 百分率つきの四則演算の導関数は、展開した式の導関数と同じ値になる
+    let mut interp = Interpreter::new();
+    interp.set_var("x", 300);
+    for (input, expanded) in [
+        ("x * x + 10%", "x * x + ((x * x * 10)%)"),
+        ("x * x - x%", "x * x - ((x * x * x)%)"),
+        ("x * x * 40%", "(x * x * 40)%"),
+        ("x * x / 30%", "x * x * 100 / 30"),
+        ("1000 / x%", "1000 * 100 / x"),
+    ] {
+        let d = derive(&input.parse().unwrap(), "x").unwrap();
+        let expected = derive(&expanded.parse().unwrap(), "x").unwrap();
+        assert_eq!(interp.eval(&d), interp.eval(&expected), "{}", input);
+    }
+
+    // 左辺に入れ子にしても導関数は指数的に大きくならない
+    let input = format!("x{}", " + 10% - x%".repeat(20));
+    let d = derive(&input.parse().unwrap(), "x").unwrap();
+    assert!(d.walk().count() < 100_000);
+}
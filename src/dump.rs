@@ -0,0 +1,198 @@
+use crate::parser::{Ast, AstKind, UniOpKind, BinOpKind, Visit};
+use crate::lexer::Token;
+
+/// トークン列を種類・内容・位置情報の表にする
+pub fn tokens_table(tokens: &[Token]) -> String {
+    let rows: Vec<(&str, String, String)> = tokens.iter()
+        .map(|tok| (tok.value.name(), tok.value.to_string(), tok.loc.to_string()))
+        .collect();
+    let kind_width = rows.iter().map(|r| r.0.len()).chain(Some("KIND".len())).max().unwrap();
+    let text_width = rows.iter().map(|r| r.1.len()).chain(Some("TEXT".len())).max().unwrap();
+
+    let mut buf = format!("{:kw$}  {:tw$}  LOC\n", "KIND", "TEXT", kw = kind_width, tw = text_width);
+    for (kind, text, loc) in rows {
+        buf.push_str(&format!("{:kw$}  {:tw$}  {}\n", kind, text, loc, kw = kind_width, tw = text_width));
+    }
+    buf
+}
+
+/// トークン列をJSON Lines形式にする。1行に1トークンのオブジェクトを出力する
+pub fn tokens_jsonl(tokens: &[Token]) -> String {
+    let mut buf = String::new();
+    for tok in tokens {
+        buf.push_str(&format!(
+            "{{\"kind\":\"{}\",\"text\":\"{}\",\"start\":{},\"end\":{}}}\n",
+            tok.value.name(),
+            json_escape(&tok.value.to_string()),
+            tok.loc.0,
+            tok.loc.1,
+        ));
+    }
+    buf
+}
+
+fn json_escape(s: &str) -> String {
+    let mut buf = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            c if (c as u32) < 0x20 => buf.push_str(&format!("\\u{:04x}", c as u32)),
+            c => buf.push(c),
+        }
+    }
+    buf
+}
+
+/// ASTをGraphvizのDOT形式にする。各節点には演算子と位置情報を表示する
+/// 節点は行きがけ順に番号を付け、辺は子の部分木を全て出力してから出力する
+pub fn to_dot(expr: &Ast) -> String {
+    let mut buf = String::from("digraph ast {\n    node [shape=box];\n");
+    // 入ったまま出ていない節点の番号
+    let mut ids: Vec<usize> = Vec::new();
+    let mut next = 0;
+    for visit in expr.walk() {
+        match visit {
+            Visit::Enter(e) => {
+                buf.push_str(&format!("    n{} [label=\"{}\\n{}\"];\n", next, label(e), e.loc));
+                ids.push(next);
+                next += 1;
+            },
+            Visit::Between(..) => (),
+            Visit::Exit(_) => {
+                let id = ids.pop().unwrap();
+                if let Some(parent) = ids.last() {
+                    buf.push_str(&format!("    n{} -> n{};\n", parent, id));
+                }
+            },
+        }
+    }
+    buf.push_str("}\n");
+    buf
+}
+
+/// ASTをS式にする。単項のマイナスは二項の`-`と区別するため`neg`と書く
+pub fn to_sexp(expr: &Ast) -> String {
+    let mut buf = String::new();
+    for visit in expr.walk() {
+        match visit {
+            Visit::Enter(e) if e.children().next().is_none() => buf.push_str(&label(e)),
+            Visit::Enter(e) => {
+                buf.push('(');
+                buf.push_str(&label(e));
+                buf.push(' ');
+            },
+            Visit::Between(..) => buf.push(' '),
+            Visit::Exit(e) if e.children().next().is_none() => (),
+            Visit::Exit(_) => buf.push(')'),
+        }
+    }
+    buf
+}
+
+/// ASTを字下げした木の形にする。各節点の後ろに位置情報を表示する
+pub fn to_tree(expr: &Ast) -> String {
+    // 子を逆順に積む。要素は（節点, 字下げ, 最後の子か）
+    fn push_children<'a>(e: &'a Ast, prefix: &str, stack: &mut Vec<(&'a Ast, String, bool)>) {
+        let n = e.children().count();
+        for (i, child) in (0..n).rev().zip(e.children().rev()) {
+            stack.push((child, prefix.to_string(), i + 1 == n));
+        }
+    }
+
+    let mut buf = format!("{} {}\n", label(expr), expr.loc);
+    let mut stack = Vec::new();
+    push_children(expr, "", &mut stack);
+    while let Some((e, prefix, last)) = stack.pop() {
+        let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+        buf.push_str(&format!("{}{}{} {}\n", prefix, branch, label(e), e.loc));
+        push_children(e, &format!("{}{}", prefix, indent), &mut stack);
+    }
+    buf
+}
+
+/// 節点の表示名
+fn label(expr: &Ast) -> String {
+    match expr.value {
+        AstKind::Num(n) => n.to_string(),
+        AstKind::Var(ref name) => name.clone(),
+        AstKind::UniOp { ref op, .. } => match op.value {
+            UniOpKind::Plus => "+".to_string(),
+            UniOpKind::Minus => "neg".to_string(),
+            UniOpKind::Factorial => "!".to_string(),
+            UniOpKind::Percent => "%".to_string(),
+            UniOpKind::Not => "not".to_string(),
+            UniOpKind::BitNot => "~".to_string(),
+        },
+        AstKind::BinOp { ref op, .. } => match op.value {
+            BinOpKind::Add => "+".to_string(),
+            BinOpKind::Sub => "-".to_string(),
+            BinOpKind::Mult => "*".to_string(),
+            BinOpKind::Div => "/".to_string(),
+            BinOpKind::Pow => "^".to_string(),
+            BinOpKind::BitXor => "xor".to_string(),
+            BinOpKind::AddPercent => "+%".to_string(),
+            BinOpKind::SubPercent => "-%".to_string(),
+            BinOpKind::MultPercent => "*%".to_string(),
+            BinOpKind::DivPercent => "/%".to_string(),
+        },
+        AstKind::Tuple(_) => "tuple".to_string(),
+    }
+}
+
+#[test]
+fn test_dump_tokens() {
+    use crate::lexer::lex;
+
+    let tokens = lex("12 * x").unwrap();
+    assert_eq!(
+        tokens_table(&tokens),
+        "\
+KIND      TEXT  LOC
+Number    12    0-2
+Asterisk  *     3-4
+Ident     x     5-6
+"
+    );
+    assert_eq!(
+        tokens_jsonl(&tokens[..2]),
+        "\
+{\"kind\":\"Number\",\"text\":\"12\",\"start\":0,\"end\":2}
+{\"kind\":\"Asterisk\",\"text\":\"*\",\"start\":3,\"end\":4}
+"
+    );
+    assert_eq!(json_escape("a\"b\\\n"), "a\\\"b\\\\\\u000a");
+}
+
+#[test]
+fn test_dump() {
+    let ast = "1 + 2 * 3 - -10".parse::<Ast>().unwrap();
+
+    assert_eq!(to_sexp(&ast), "(- (+ 1 (* 2 3)) (neg 10))");
+    assert_eq!(
+        to_tree(&ast),
+        "\
+- 0-15
+├── + 0-9
+│   ├── 1 0-1
+│   └── * 4-9
+│       ├── 2 4-5
+│       └── 3 8-9
+└── neg 12-15
+    └── 10 13-15
+"
+    );
+
+    let ast = "-x".parse::<Ast>().unwrap();
+    assert_eq!(
+        to_dot(&ast),
+        "\
+digraph ast {
+    node [shape=box];
+    n0 [label=\"neg\\n0-2\"];
+    n1 [label=\"x\\n1-2\"];
+    n0 -> n1;
+}
+"
+    );
+}
@@ -2,12 +2,19 @@ use std::{fmt, error::Error as StdError};
 
 use crate::utils::{Annot, Loc};
 use crate::lexer::Token;
+use crate::span::{floor_char_boundary, Diagnostic, Span};
 
 // 字句解析エラー
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum LexErrorKind {
     InvalidChar(char),
     Eof,
+    /// ブロックコメントが閉じられていない
+    UnclosedComment,
+    /// 数値リテラルがu64に収まらない
+    NumberTooLarge,
+    /// 小数点を含む数値リテラル。評価器は整数だけを扱うので、どの方言でも受け付けない
+    FloatLiteral,
 }
 
 pub type LexError = Annot<LexErrorKind>;
@@ -20,6 +27,18 @@ impl LexError {
     pub fn eof(loc: Loc) -> Self {
         Self::new(LexErrorKind::Eof, loc)
     }
+
+    pub fn unclosed_comment(loc: Loc) -> Self {
+        Self::new(LexErrorKind::UnclosedComment, loc)
+    }
+
+    pub fn number_too_large(loc: Loc) -> Self {
+        Self::new(LexErrorKind::NumberTooLarge, loc)
+    }
+
+    pub fn float_literal(loc: Loc) -> Self {
+        Self::new(LexErrorKind::FloatLiteral, loc)
+    }
 }
 
 impl fmt::Display for LexError {
@@ -30,6 +49,9 @@ impl fmt::Display for LexError {
         match self.value {
             InvalidChar(c) => write!(f, "{}: invalid char '{}'", loc, c),
             Eof => write!(f, "End of file"),
+            UnclosedComment => write!(f, "{}: block comment is not closed", loc),
+            NumberTooLarge => write!(f, "{}: number is too large", loc),
+            FloatLiteral => write!(f, "{}: floating-point literals are not supported", loc),
         }
     }
 }
@@ -51,6 +73,14 @@ pub enum ParseError {
     RedundantExpression(Token),
     /// パース途中で入力が終わった
     Eof,
+    /// 括弧や演算子の入れ子が深すぎる。上限を超えた開き括弧か演算子を指す
+    TooDeep(Loc),
+    /// 入力が長すぎる
+    InputTooLong { len: usize, max: usize },
+    /// トークンが多すぎる。上限を超えたトークンを持つ
+    TooManyTokens(Token),
+    /// ASTの節点が多すぎる。上限を超えた節点の位置を指す
+    TooManyNodes(Loc),
 }
 
 impl fmt::Display for ParseError {
@@ -64,6 +94,10 @@ impl fmt::Display for ParseError {
             UnclosedOpenParen(tok) => write!(f, "{}: '{}' is not closed", tok.loc, tok.value),
             RedundantExpression(tok) => write!(f, "{}: expression after '{}' is redundant", tok.loc, tok.value),
             Eof => write!(f, "End of file"),
+            TooDeep(loc) => write!(f, "{}: expression is nested too deeply", loc),
+            InputTooLong { len, max } => write!(f, "input is too long ({} bytes, limit is {})", len, max),
+            TooManyTokens(tok) => write!(f, "{}: too many tokens", tok.loc),
+            TooManyNodes(loc) => write!(f, "{}: expression has too many nodes", loc),
         }
     }
 }
@@ -73,25 +107,34 @@ impl StdError for ParseError {}
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum InterpreterErrorKind {
     DivisionByZero,
+    /// 値が設定されていない変数
+    UnboundVariable(String),
+    /// 計算結果がi64に収まらない
+    Overflow,
+    /// 評価の手数の上限を超えた
+    StepLimitExceeded,
+    /// 負の数の階乗
+    NegativeFactorial,
+    /// 数値が必要なところに組が現れた
+    UnexpectedTuple,
+    /// VMが実行できないバイトコード
+    InvalidBytecode(FormatErrorKind),
 }
 
 pub type InterpreterError = Annot<InterpreterErrorKind>;
 
-impl InterpreterError {
-    pub fn show_diagnostic(&self, input: &str) {
-        // エラー情報を簡単に表示し
-        eprintln!("{}", self);
-        // エラー位置を指示する
-        print_annot(input, self.loc.clone())
-    }
-}
-
 impl fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::InterpreterErrorKind::*;
 
         match self.value {
             DivisionByZero => write!(f, "division by zero"),
+            UnboundVariable(ref name) => write!(f, "variable '{}' is not defined", name),
+            Overflow => write!(f, "integer overflow"),
+            StepLimitExceeded => write!(f, "evaluation step limit exceeded"),
+            NegativeFactorial => write!(f, "factorial of a negative number"),
+            UnexpectedTuple => write!(f, "expected a number, found a tuple"),
+            InvalidBytecode(ref kind) => write!(f, "invalid bytecode: {}", kind),
         }
     }
 }
@@ -102,44 +145,268 @@ impl StdError for InterpreterError {
 
         match self.value {
             DivisionByZero => "the right hand expression of the division evaluates to zero",
+            UnboundVariable(_) => "the variable has no value",
+            Overflow => "the result does not fit in a 64-bit signed integer",
+            StepLimitExceeded => "the evaluation took more steps than allowed",
+            NegativeFactorial => "the operand of the factorial is negative",
+            UnexpectedTuple => "a tuple cannot be an operand of an operator",
+            InvalidBytecode(_) => "the bytecode does not pass verification",
+        }
+    }
+}
+
+// 最適化の警告
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    /// ゼロ除算になるので畳み込まなかった
+    DivisionByZero,
+    /// オーバーフローするので畳み込まなかった
+    Overflow,
+    /// 負の数の階乗になるので畳み込まなかった
+    NegativeFactorial,
+}
+
+pub type Warning = Annot<WarningKind>;
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::WarningKind::*;
+
+        let loc = &self.loc;
+        match self.value {
+            DivisionByZero => write!(f, "{}: warning: this expression divides by zero at runtime", loc),
+            Overflow => write!(f, "{}: warning: this expression overflows at runtime", loc),
+            NegativeFactorial => write!(f, "{}: warning: this expression takes the factorial of a negative number at runtime", loc),
+        }
+    }
+}
+
+impl StdError for Warning {}
+
+// 微分のエラー
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DiffErrorKind {
+    /// 微分する変数の名前が識別子ではない
+    InvalidVariable(String),
+    /// 微分の規則がない構文
+    Unsupported(String),
+}
+
+pub type DiffError = Annot<DiffErrorKind>;
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DiffErrorKind::*;
+
+        let loc = &self.loc;
+        match self.value {
+            InvalidVariable(ref name) => write!(f, "{}: '{}' is not a variable name", loc, name),
+            Unsupported(ref what) => write!(f, "{}: cannot differentiate {}", loc, what),
         }
     }
 }
 
-/// 字句解析エラーと構文解析エラーを統合するエラー型
+impl StdError for DiffError {}
+
+// バイトコードやC言語などへのコンパイルのエラー
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CompileErrorKind {
+    /// コンパイル先が組を扱えない。コンパイル先の名前を持つ
+    UnsupportedTuple(&'static str),
+    /// 中置記法の方言に演算の綴りがない。演算の名前を持つ
+    UnsupportedOperator(&'static str),
+}
+
+pub type CompileError = Annot<CompileErrorKind>;
+
+impl CompileError {
+    pub fn unsupported_tuple(target: &'static str, loc: Loc) -> Self {
+        Self::new(CompileErrorKind::UnsupportedTuple(target), loc)
+    }
+
+    pub fn unsupported_operator(name: &'static str, loc: Loc) -> Self {
+        Self::new(CompileErrorKind::UnsupportedOperator(name), loc)
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CompileErrorKind::*;
+
+        let loc = &self.loc;
+        match self.value {
+            UnsupportedTuple(target) => write!(f, "{}: a tuple cannot be compiled to {}", loc, target),
+            UnsupportedOperator(name) => write!(f, "{}: {} cannot be written in this dialect", loc, name),
+        }
+    }
+}
+
+impl StdError for CompileError {}
+
+// 逆ポーランド記法・ポーランド記法の読み込みと評価のエラー
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RpnErrorKind {
+    /// 数値でも演算子でもない語
+    InvalidWord(String),
+    /// 演算子に対して被演算子が足りない
+    StackUnderflow,
+    /// 評価が終わったのにスタックに値が残っている
+    RedundantOperand,
+    /// 値が1つもないまま入力が終わった
+    Eof,
+    DivisionByZero,
+    Overflow,
+    NegativeFactorial,
+    /// 値を設定していない変数
+    UnboundVariable(String),
+    /// 演算子の被演算子が組
+    UnexpectedTuple,
+    /// 読み込み中のスタックが深さの上限を超えた
+    TooDeep,
+    /// 語の数が節点数の上限を超えた
+    TooManyNodes,
+}
+
+pub type RpnError = Annot<RpnErrorKind>;
+
+impl fmt::Display for RpnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RpnErrorKind::*;
+
+        let loc = &self.loc;
+        match self.value {
+            InvalidWord(ref w) => write!(f, "{}: '{}' is not a number or an operator", loc, w),
+            StackUnderflow => write!(f, "{}: stack underflow", loc),
+            RedundantOperand => write!(f, "{}: operand is left on the stack", loc),
+            Eof => write!(f, "End of file"),
+            DivisionByZero => write!(f, "{}: division by zero", loc),
+            Overflow => write!(f, "{}: integer overflow", loc),
+            NegativeFactorial => write!(f, "{}: factorial of a negative number", loc),
+            UnboundVariable(ref name) => write!(f, "{}: variable '{}' is not defined", loc, name),
+            UnexpectedTuple => write!(f, "{}: expected a number, found a tuple", loc),
+            TooDeep => write!(f, "{}: expression is nested too deeply", loc),
+            TooManyNodes => write!(f, "{}: expression has too many nodes", loc),
+        }
+    }
+}
+
+impl StdError for RpnError {}
+
+// バイトコードファイルの読み込みエラー
+// 位置情報はファイル先頭からのバイト位置を表す
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FormatErrorKind {
+    /// 先頭のマジックナンバーが一致しない
+    BadMagic,
+    /// 対応していないバージョン
+    UnsupportedVersion(u16),
+    /// 途中でファイルが終わっている
+    Truncated,
+    /// 未知のオペコード
+    InvalidOpcode(u8),
+    /// 定数プールの範囲外を指している
+    ConstOutOfRange(u32),
+    /// 名前表の範囲外を指している
+    NameOutOfRange(u32),
+    /// 変数名がUTF-8として正しくない
+    InvalidName,
+    /// スパンテーブルと命令列の長さが一致しない
+    SpanCountMismatch,
+    /// データの後ろに余分なバイトがある
+    TrailingBytes,
+    /// 実行するとスタックが足りなくなる
+    StackUnderflow,
+    /// 実行後のスタックに値がちょうど1つ残らない
+    UnbalancedStack(usize),
+}
+
+pub type FormatError = Annot<FormatErrorKind>;
+
+impl fmt::Display for FormatErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FormatErrorKind::*;
+
+        match *self {
+            BadMagic => write!(f, "not a bytecode file"),
+            UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            Truncated => write!(f, "unexpected end of file"),
+            InvalidOpcode(op) => write!(f, "invalid opcode {:#04x}", op),
+            ConstOutOfRange(idx) => write!(f, "constant #{} is out of range", idx),
+            NameOutOfRange(idx) => write!(f, "name #{} is out of range", idx),
+            InvalidName => write!(f, "name is not valid UTF-8"),
+            SpanCountMismatch => write!(f, "span table does not match the code"),
+            TrailingBytes => write!(f, "trailing bytes after the span table"),
+            StackUnderflow => write!(f, "code underflows the stack"),
+            UnbalancedStack(n) => write!(f, "code leaves {} values on the stack", n),
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+impl StdError for FormatError {}
+
+/// 位置情報を持つエラーと警告の診断メッセージ
+impl<K: Clone> Annot<K>
+where
+    Annot<K>: StdError,
+{
+    /// 入力を借用して、入力を渡し直さなくても表示できる診断メッセージにする
+    pub fn diagnostic<'src>(&self, input: &'src str) -> Diagnostic<'src, Self> {
+        Diagnostic { error: self.clone(), span: Span::new(input, self.loc.clone()) }
+    }
+
+    /// 診断メッセージを表示する
+    pub fn show_diagnostic(&self, input: &str) {
+        self.diagnostic(input).show();
+    }
+}
+
+/// 字句解析エラーと構文解析エラーなど、式の読み込みのエラーを統合するエラー型
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Error {
     Lexer(LexError),
     Parser(ParseError),
+    Rpn(RpnError),
+    Compile(CompileError),
 }
 
 impl Error {
     /// 診断メッセージを表示する
     pub fn show_diagnostic(&self, input: &str) {
+        self.diagnostic(input).show();
+    }
+
+    /// 入力を借用して、入力を渡し直さなくても表示できる診断メッセージにする
+    pub fn diagnostic<'src>(&self, input: &'src str) -> Diagnostic<'src> {
         use self::Error::*;
         use self::ParseError as P;
 
-        // エラー情報とその位置情報を取り出す。エラーの種類によって位置情報を調整する
-        let (e, loc): (&dyn StdError, Loc) = match self {
-            Lexer(e) => (e, e.loc.clone()),
-            Parser(e) => {
-                let loc = match e {
-                    P::UnexpectedToken(Token {loc, ..})
-                    | P::NotExpression(Token {loc, ..})
-                    | P::NotOperator(Token {loc, ..})
-                    | P::UnclosedOpenParen(Token {loc, ..}) => loc.clone(),
-                    // redundant expressionはトークン以降行末までが余りなのでlocの終了位置を調整する
-                    P::RedundantExpression(Token {loc, ..}) => Loc(loc.0, input.len()),
-                    // EoFはloc情報を持っていないのでその場で作る
-                    P::Eof => Loc(input.len(), input.len() + 1),
-                };
-                (e, loc)
+        // エラーの種類によって位置情報を調整する
+        let loc = match self {
+            Lexer(e) => e.loc.clone(),
+            Parser(e) => match e {
+                P::UnexpectedToken(Token {loc, ..})
+                | P::NotExpression(Token {loc, ..})
+                | P::NotOperator(Token {loc, ..})
+                | P::UnclosedOpenParen(Token {loc, ..}) => loc.clone(),
+                // redundant expressionはトークン以降行末までが余りなのでlocの終了位置を調整する
+                P::RedundantExpression(Token {loc, ..}) => Loc(loc.0, input.len()),
+                // EoFはloc情報を持っていないのでその場で作る
+                P::Eof => Loc(input.len(), input.len() + 1),
+                P::TooDeep(loc) | P::TooManyNodes(loc) => loc.clone(),
+                P::TooManyTokens(Token {loc, ..}) => loc.clone(),
+                // 上限を超えた部分を指す。上限が文字の途中ならその文字から指す
+                P::InputTooLong { len, max } => Loc(floor_char_boundary(input, *max), *len),
             },
+            Rpn(e) => e.loc.clone(),
+            Compile(e) => e.loc.clone(),
         };
-        // エラー情報を簡単に表示し
-        eprintln!("{}", e);
-        // エラー位置を指示する
-        print_annot(input, loc);
+        Diagnostic { error: self.clone(), span: Span::new(input, loc) }
     }
 }
 
@@ -155,6 +422,18 @@ impl From<ParseError> for Error {
     }
 }
 
+impl From<RpnError> for Error {
+    fn from(e: RpnError) -> Self {
+        Error::Rpn(e)
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(e: CompileError) -> Self {
+        Error::Compile(e)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "parser error")
@@ -168,18 +447,12 @@ impl StdError for Error {
         match self {
             Lexer(lex) => Some(lex),
             Parser(parse) => Some(parse),
+            Rpn(rpn) => Some(rpn),
+            Compile(compile) => Some(compile),
         }
     }
 }
 
-/// inputに対してlocの位置を強調表示する
-fn print_annot(input: &str, loc: Loc) {
-    // 入力に対して
-    eprintln!("{}", input);
-    // 位置情報を分かりやすく示す
-    eprintln!("{}{}", " ".repeat(loc.0), "^".repeat(loc.1 - loc.0));
-}
-
 pub fn show_trace<E: StdError>(e: E) {
     // エラーがあった場合そのエラーとsourceを全部出力する
     eprintln!("{}", e);
@@ -1,59 +1,231 @@
+use std::{collections::HashMap, fmt};
+
 use crate::parser::{Ast, AstKind, UniOp, UniOpKind, BinOp, BinOpKind};
 use crate::error::{InterpreterError, InterpreterErrorKind};
+use crate::limits::EvalLimits;
+
+/// 式の値。組の値は要素の値を並べたもの
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    Int(i64),
+    Tuple(Vec<Value>),
+}
+
+/// 組は `(1, 2)` のように括弧で囲んでカンマで区切る
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => n.fmt(f),
+            Value::Tuple(vs) => {
+                write!(f, "(")?;
+                for (i, v) in vs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    v.fmt(f)?;
+                }
+                write!(f, ")")
+            },
+        }
+    }
+}
 
 /// 評価器を表すデータ型
-pub struct Interpreter;
+#[derive(Default)]
+pub struct Interpreter {
+    // 変数の値
+    vars: HashMap<String, i64>,
+    // 資源の上限
+    limits: EvalLimits,
+}
 
 impl Interpreter {
     pub fn new() -> Self {
-        Interpreter
+        Self::default()
+    }
+
+    /// 評価の資源の上限を指定して作る
+    pub fn with_limits(limits: EvalLimits) -> Self {
+        Interpreter { limits, ..Self::default() }
+    }
+
+    /// 変数に値を設定する
+    pub fn set_var(&mut self, name: &str, value: i64) {
+        self.vars.insert(name.to_string(), value);
     }
 
+    /// 数値になる式を評価する。値が組ならエラーにする
     pub fn eval(&mut self, expr: &Ast) -> Result<i64, InterpreterError> {
+        match self.eval_value(expr)? {
+            Value::Int(n) => Ok(n),
+            Value::Tuple(_) => Err(InterpreterError::new(InterpreterErrorKind::UnexpectedTuple, expr.loc.clone())),
+        }
+    }
+
+    /// 組を含む式を評価する。演算子の被演算子が組ならエラーにする
+    /// 深い式でもスタックが溢れないよう、再帰せずに明示的なスタックで評価する
+    pub fn eval_value(&mut self, expr: &Ast) -> Result<Value, InterpreterError> {
         use self::AstKind::*;
 
-        match expr.value {
-            Num(n) => Ok(n as i64),
-            UniOp { ref op, ref e } => {
-                let e = self.eval(e)?;
-                Ok(self.eval_uniop(op, e))
-            },
-            BinOp { ref op, ref l, ref r } => {
-                let l = self.eval(l)?;
-                let r = self.eval(r)?;
-                self.eval_binop(op, l, r)
-                    .map_err(|e| InterpreterError::new(e, expr.loc.clone()))
-            },
+        // Enterで子を積み、Exitで子の値から自分の値を求める
+        enum Visit<'a> {
+            Enter(&'a Ast),
+            Exit(&'a Ast),
+            /// 負号の被演算子の2^63。負号と合わせてi64::MINになる
+            MinLiteral(&'a Ast),
+        }
+
+        let mut stack = vec![Visit::Enter(expr)];
+        let mut values: Vec<Value> = Vec::new();
+        // 節点を1つ訪れるごとに1手と数える
+        let mut steps = 0;
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(e) | Visit::MinLiteral(e) if steps == self.limits.max_steps => {
+                    return Err(InterpreterError::new(InterpreterErrorKind::StepLimitExceeded, e.loc.clone()));
+                },
+                Visit::MinLiteral(_) => {
+                    steps += 1;
+                    values.push(Value::Int(i64::MIN));
+                },
+                Visit::Enter(e) => {
+                    steps += 1;
+                    match e.value {
+                        // i64に収まらない数値はオーバーフローとする
+                        Num(n) => match i64::try_from(n) {
+                            Ok(n) => values.push(Value::Int(n)),
+                            Err(_) => return Err(InterpreterError::new(InterpreterErrorKind::Overflow, e.loc.clone())),
+                        },
+                        Var(ref name) => {
+                            let n = self.vars.get(name).copied().ok_or_else(|| {
+                                InterpreterError::new(InterpreterErrorKind::UnboundVariable(name.clone()), e.loc.clone())
+                            })?;
+                            values.push(Value::Int(n));
+                        },
+                        // -2^63は負号と数値をまとめてi64::MINにする。数値の節点も1手と数える
+                        UniOp { e: ref operand, .. } if e.is_min_literal() => stack.push(Visit::MinLiteral(operand)),
+                        UniOp { e: ref operand, .. } => {
+                            stack.push(Visit::Exit(e));
+                            stack.push(Visit::Enter(operand));
+                        },
+                        // 左辺を先に評価するため右辺を先に積む
+                        BinOp { ref l, ref r, .. } => {
+                            stack.push(Visit::Exit(e));
+                            stack.push(Visit::Enter(r));
+                            stack.push(Visit::Enter(l));
+                        },
+                        Tuple(_) => {
+                            stack.push(Visit::Exit(e));
+                            stack.extend(e.children().rev().map(Visit::Enter));
+                        },
+                    }
+                },
+                Visit::Exit(e) => {
+                    // 演算子の被演算子は数値でなければならない
+                    let mut int = || match values.pop().unwrap() {
+                        Value::Int(n) => Ok(n),
+                        Value::Tuple(_) => Err(InterpreterErrorKind::UnexpectedTuple),
+                    };
+                    let n = match e.value {
+                        UniOp { ref op, .. } => int().and_then(|n| self.eval_uniop(op, n)),
+                        BinOp { ref op, .. } => {
+                            let r = int();
+                            let l = int();
+                            l.and_then(|l| r.and_then(|r| self.eval_binop(op, l, r)))
+                        },
+                        Tuple(ref es) => {
+                            let vs = values.split_off(values.len() - es.len());
+                            values.push(Value::Tuple(vs));
+                            continue;
+                        },
+                        Num(_) | Var(_) => unreachable!(),
+                    };
+                    values.push(Value::Int(n.map_err(|err| InterpreterError::new(err, e.loc.clone()))?));
+                },
+            }
         }
+        Ok(values.pop().unwrap())
     }
 
-    fn eval_uniop(&mut self, op: &UniOp, n: i64) -> i64 {
+    pub(crate) fn eval_uniop(&mut self, op: &UniOp, n: i64) -> Result<i64, InterpreterErrorKind> {
         use self::UniOpKind::*;
 
         match op.value {
-            Plus => n,
-            Minus => -n,
+            Plus => Ok(n),
+            Minus => n.checked_neg().ok_or(InterpreterErrorKind::Overflow),
+            Factorial => factorial(n),
+            Percent => Ok(n / 100),
+            Not => Ok((n == 0) as i64),
+            BitNot => Ok(!n),
         }
     }
 
-    fn eval_binop(&mut self, op: &BinOp, l: i64, r: i64) -> Result<i64, InterpreterErrorKind> {
+    pub(crate) fn eval_binop(&mut self, op: &BinOp, l: i64, r: i64) -> Result<i64, InterpreterErrorKind> {
         use self::BinOpKind::*;
 
-        match op.value {
-            Add => Ok(l + r),
-            Sub => Ok(l - r),
-            Mult => Ok(l * r),
+        // 結果がi64に収まらなければオーバーフローとする
+        let n = match op.value {
+            Add => l.checked_add(r),
+            Sub => l.checked_sub(r),
+            Mult => l.checked_mul(r),
             Div => {
                 if r == 0 {
-                    Err(InterpreterErrorKind::DivisionByZero)
-                } else {
-                    Ok(l / r)
+                    return Err(InterpreterErrorKind::DivisionByZero);
                 }
+                l.checked_div(r)
             },
-        }
+            Pow => return pow(l, r),
+            BitXor => Some(l ^ r),
+            AddPercent | SubPercent | MultPercent | DivPercent => return percent_of(&op.value, l, r),
+        };
+        n.ok_or(InterpreterErrorKind::Overflow)
     }
 }
 
+/// 右辺を左辺に対する百分率とする四則演算
+/// a ± b% は a ± (a * b)%、a * b% は (a * b)%、a / b% は a * 100 / b で、`%` は100で割って0の方向に丸める。
+/// 展開した式を左から計算するのと同じ順序でオーバーフローとゼロ除算を検査する
+pub(crate) fn percent_of(op: &BinOpKind, l: i64, r: i64) -> Result<i64, InterpreterErrorKind> {
+    use self::BinOpKind::*;
+
+    let of = || l.checked_mul(r).map(|n| n / 100).ok_or(InterpreterErrorKind::Overflow);
+    let n = match op {
+        AddPercent => l.checked_add(of()?),
+        SubPercent => l.checked_sub(of()?),
+        MultPercent => return of(),
+        DivPercent => {
+            let scaled = l.checked_mul(100).ok_or(InterpreterErrorKind::Overflow)?;
+            if r == 0 {
+                return Err(InterpreterErrorKind::DivisionByZero);
+            }
+            scaled.checked_div(r)
+        },
+        Add | Sub | Mult | Div | Pow | BitXor => unreachable!(),
+    };
+    n.ok_or(InterpreterErrorKind::Overflow)
+}
+
+/// lのr乗。i64に収まらなければオーバーフローとする
+/// 指数が負なら1 / l^(-r)を0の方向に丸めた値になるので、0の負の数乗はゼロ除算とする
+pub(crate) fn pow(l: i64, r: i64) -> Result<i64, InterpreterErrorKind> {
+    match l {
+        0 if r < 0 => Err(InterpreterErrorKind::DivisionByZero),
+        0 => Ok((r == 0) as i64),
+        1 => Ok(1),
+        -1 => Ok(if r % 2 == 0 { 1 } else { -1 }),
+        _ if r < 0 => Ok(0),
+        _ => u32::try_from(r).ok().and_then(|r| l.checked_pow(r)).ok_or(InterpreterErrorKind::Overflow),
+    }
+}
+
+/// nの階乗。負の数の階乗はエラーで、i64に収まらなければオーバーフローとする
+pub(crate) fn factorial(n: i64) -> Result<i64, InterpreterErrorKind> {
+    if n < 0 {
+        return Err(InterpreterErrorKind::NegativeFactorial);
+    }
+    (2..=n).try_fold(1i64, |acc, i| acc.checked_mul(i)).ok_or(InterpreterErrorKind::Overflow)
+}
+
 #[test]
 fn test_interpreter() {
     use crate::utils::Loc;
@@ -83,5 +255,120 @@ fn test_interpreter() {
 
     let ans = interp.eval(&ast).unwrap();
 
-    assert_eq!(ans, 17)
+    assert_eq!(ans, 17);
+
+    let ast = Ast::binop(BinOp::mult(Loc(1, 2)), Ast::var("x", Loc(0, 1)), Ast::var("y", Loc(2, 3)), Loc(0, 3));
+    interp.set_var("x", 6);
+    assert_eq!(
+        interp.eval(&ast),
+        Err(InterpreterError::new(InterpreterErrorKind::UnboundVariable("y".to_string()), Loc(2, 3)))
+    );
+    interp.set_var("y", 7);
+    assert_eq!(interp.eval(&ast), Ok(42));
+
+    interp.set_var("x", i64::MAX);
+    assert_eq!(
+        interp.eval(&ast),
+        Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 3)))
+    )
+}
+
+#[test]
+fn test_interpreter_deep() {
+    use crate::utils::Loc;
+
+    // 1 + 1 + ... + 1 のように左に深い式でもスタックが溢れない
+    let mut ast = Ast::num(1, Loc(0, 1));
+    for _ in 0..100_000 {
+        ast = Ast::binop(BinOp::add(Loc(0, 0)), ast, Ast::num(1, Loc(0, 0)), Loc(0, 0));
+    }
+    assert_eq!(Interpreter::new().eval(&ast), Ok(100_001));
+}
+
+#[test]
+fn test_interpreter_limits() {
+    use crate::utils::Loc;
+
+    let ast = "1 + 2 * 3".parse::<Ast>().unwrap();
+    let mut interp = Interpreter::with_limits(EvalLimits { max_steps: 5, ..EvalLimits::default() });
+    assert_eq!(interp.eval(&ast), Ok(7));
+    // +, 1, * の次に2を訪れようとして止まる
+    let mut interp = Interpreter::with_limits(EvalLimits { max_steps: 3, ..EvalLimits::default() });
+    assert_eq!(
+        interp.eval(&ast),
+        Err(InterpreterError::new(InterpreterErrorKind::StepLimitExceeded, Loc(4, 5))),
+    );
+}
+
+#[test]
+fn test_interpreter_postfix() {
+    use crate::utils::Loc;
+
+    let eval = |s: &str| Interpreter::new().eval(&s.parse().unwrap());
+    assert_eq!(eval("5!"), Ok(120));
+    assert_eq!(eval("-3!"), Ok(-6));
+    assert_eq!(eval("20!"), Ok(2_432_902_008_176_640_000));
+    assert_eq!(eval("21!"), Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 3))));
+    assert_eq!(eval("(-3)!"), Err(InterpreterError::new(InterpreterErrorKind::NegativeFactorial, Loc(1, 5))));
+    // 右辺の百分率は左辺に対する割合になる
+    assert_eq!(eval("250%"), Ok(2));
+    assert_eq!(eval("200 + 15%"), Ok(230));
+    assert_eq!(eval("200 - 15%"), Ok(170));
+    assert_eq!(eval("200 * 15%"), Ok(30));
+    assert_eq!(eval("30 / 15%"), Ok(200));
+    assert_eq!(eval("200 + (15%)"), Ok(200));
+    assert_eq!(eval("(200 + 15%) * 2"), Ok(460));
+    assert_eq!(eval("10 / 0%"), Err(InterpreterError::new(InterpreterErrorKind::DivisionByZero, Loc(0, 7))));
+}
+
+#[test]
+fn test_interpreter_unary() {
+    use crate::utils::Loc;
+
+    let eval = |s: &str| Interpreter::new().eval(&s.parse().unwrap());
+    // 前置演算子は重ねられる
+    assert_eq!(eval("--5"), Ok(5));
+    assert_eq!(eval("-+5"), Ok(-5));
+    assert_eq!(eval("!!7 + !0"), Ok(2));
+    assert_eq!(eval("~~3 - ~0"), Ok(4));
+    // ^ は前置演算子より強く結合する右結合の演算子
+    assert_eq!(eval("-2^2"), Ok(-4));
+    assert_eq!(eval("(-2)^2"), Ok(4));
+    assert_eq!(eval("2^3^2"), Ok(512));
+    assert_eq!(eval("2^-1"), Ok(0));
+    assert_eq!(eval("(-1)^-3"), Ok(-1));
+    assert_eq!(eval("2^63"), Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 4))));
+    assert_eq!(eval("0^-1"), Err(InterpreterError::new(InterpreterErrorKind::DivisionByZero, Loc(0, 4))));
+}
+
+#[test]
+fn test_interpreter_tuple() {
+    use crate::utils::Loc;
+
+    let eval = |s: &str| Interpreter::new().eval_value(&s.parse().unwrap());
+    let v = eval("1+2, 3*4").unwrap();
+    assert_eq!(v, Value::Tuple(vec![Value::Int(3), Value::Int(12)]));
+    assert_eq!(v.to_string(), "(3, 12)");
+    assert_eq!(eval("1, (2, 3)").unwrap().to_string(), "(1, (2, 3))");
+    // 組は演算子の被演算子にならない
+    assert_eq!(
+        eval("(1, 2) + 3"),
+        Err(InterpreterError::new(InterpreterErrorKind::UnexpectedTuple, Loc(1, 10)))
+    );
+    assert_eq!(
+        Interpreter::new().eval(&"1, 2".parse().unwrap()),
+        Err(InterpreterError::new(InterpreterErrorKind::UnexpectedTuple, Loc(0, 4)))
+    );
+}
+
+#[test]
+fn test_interpreter_literals() {
+    use crate::utils::Loc;
+
+    let eval = |input: &str| Interpreter::new().eval(&input.parse::<Ast>().unwrap());
+    // 負号を付けた2^63だけがi64::MINになる
+    assert_eq!(eval("-9223372036854775808"), Ok(i64::MIN));
+    assert_eq!(eval("9223372036854775808"), Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 19))));
+    assert_eq!(eval("--9223372036854775808"), Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 21))));
+    assert_eq!(eval("18446744073709551615 * 0"), Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 20))));
 }
@@ -0,0 +1,364 @@
+use std::{collections::{HashMap, HashSet}, fmt};
+
+use crate::utils::Loc;
+use crate::parser::{Ast, UniOp, UniOpKind, BinOp, BinOpKind};
+use crate::interpreter::Interpreter;
+use crate::arena::{AstArena, NodeKind};
+use crate::error::{CompileError, InterpreterError, InterpreterErrorKind};
+
+/// 命令の被演算子
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// 定数
+    Const(i64),
+    /// 変数
+    Var(String),
+    /// 一時変数。番号は1から始まる
+    Temp(usize),
+}
+
+/// 命令の右辺
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Rhs {
+    /// 単項演算
+    UniOp(UniOpKind, Operand),
+    /// 二項演算
+    BinOp(BinOpKind, Operand, Operand),
+}
+
+/// 三番地コードの命令 `tN = 右辺`
+/// 一時変数には一度だけ代入する（SSA形式）
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Instr {
+    pub dest: usize,
+    pub rhs: Rhs,
+    /// 実行時エラーを報告するためのソース上の位置
+    pub loc: Loc,
+}
+
+/// 命令列と、最終的な値を表す被演算子
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Program {
+    pub instrs: Vec<Instr>,
+    pub result: Operand,
+    /// 式全体の位置
+    pub loc: Loc,
+}
+
+impl Program {
+    /// ASTを三番地コードにする。組を含む式は `CompileErrorKind::UnsupportedTuple` のエラーになる
+    /// ASTをアリーナに移してから前から順に命令にするので、深い式でも再帰しない
+    pub fn from_ast(expr: &Ast) -> Result<Self, CompileError> {
+        let mut arena = AstArena::new();
+        let root = arena.alloc_ast(expr);
+        let mut instrs = Vec::new();
+        let result = lower(&arena, &mut instrs)?.swap_remove(root.index());
+        Ok(Program { instrs, result, loc: expr.loc.clone() })
+    }
+
+    /// 変数に値を与えて実行する。Interpreterと同じ意味で計算する
+    /// 未定義の変数のエラーは、その変数を使う命令の位置を指す
+    pub fn eval(&self, vars: &HashMap<String, i64>) -> Result<i64, InterpreterError> {
+        let mut interp = Interpreter::new();
+        let mut temps = HashMap::new();
+
+        let value = |operand: &Operand, temps: &HashMap<usize, i64>, loc: &Loc| match operand {
+            Operand::Const(n) => Ok(*n),
+            Operand::Var(name) => vars.get(name).copied().ok_or_else(|| {
+                InterpreterError::new(InterpreterErrorKind::UnboundVariable(name.clone()), loc.clone())
+            }),
+            Operand::Temp(t) => Ok(temps[t]),
+        };
+
+        for instr in &self.instrs {
+            let loc = &instr.loc;
+            let n = match instr.rhs {
+                Rhs::UniOp(ref op, ref e) => {
+                    let e = value(e, &temps, loc)?;
+                    interp.eval_uniop(&UniOp::new(op.clone(), loc.clone()), e)
+                },
+                Rhs::BinOp(ref op, ref l, ref r) => {
+                    let l = value(l, &temps, loc)?;
+                    let r = value(r, &temps, loc)?;
+                    interp.eval_binop(&BinOp::new(op.clone(), loc.clone()), l, r)
+                },
+            };
+            let n = n.map_err(|e| InterpreterError::new(e, loc.clone()))?;
+            temps.insert(instr.dest, n);
+        }
+        value(&self.result, &temps, &self.loc)
+    }
+
+    /// 共通部分式除去
+    /// 同じ右辺を持つ命令は最初の結果を使い回す。後の命令は前の命令と同じ結果になるので、
+    /// 取り除いても実行時エラーが隠れることはない
+    pub fn eliminate_common_subexpressions(&mut self) {
+        let mut seen: HashMap<Rhs, usize> = HashMap::new();
+        let mut renames: HashMap<usize, usize> = HashMap::new();
+        let mut instrs = Vec::new();
+
+        for mut instr in self.instrs.drain(..) {
+            rename_rhs(&mut instr.rhs, &renames);
+            match seen.get(&instr.rhs) {
+                Some(&t) => {
+                    renames.insert(instr.dest, t);
+                },
+                None => {
+                    seen.insert(instr.rhs.clone(), instr.dest);
+                    instrs.push(instr);
+                },
+            }
+        }
+        rename_operand(&mut self.result, &renames);
+        self.instrs = instrs;
+    }
+
+    /// 不要命令除去
+    /// 結果が使われない命令のうち、実行時エラーになりえないものを取り除く
+    pub fn eliminate_dead_code(&mut self) {
+        let mut used: Vec<usize> = Vec::new();
+        if let Operand::Temp(t) = self.result {
+            used.push(t);
+        }
+
+        // 後ろから見ていけば、ある命令の結果を使う命令は全て処理済みになっている
+        let mut live = Vec::new();
+        for instr in self.instrs.drain(..).rev() {
+            if used.contains(&instr.dest) || may_fail(&instr.rhs) {
+                for operand in operands(&instr.rhs) {
+                    if let Operand::Temp(t) = operand {
+                        used.push(*t);
+                    }
+                }
+                live.push(instr);
+            }
+        }
+        live.reverse();
+        self.instrs = live;
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Const(n) => n.fmt(f),
+            Operand::Var(name) => name.fmt(f),
+            Operand::Temp(t) => write!(f, "t{}", t),
+        }
+    }
+}
+
+impl fmt::Display for Rhs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BinOpKind::*;
+
+        match self {
+            Rhs::UniOp(op, e) => {
+                let op = match op {
+                    UniOpKind::Plus => "plus",
+                    UniOpKind::Minus => "neg",
+                    UniOpKind::Factorial => "fact",
+                    UniOpKind::Percent => "pct",
+                    UniOpKind::Not => "not",
+                    UniOpKind::BitNot => "compl",
+                };
+                write!(f, "{} {}", op, e)
+            },
+            Rhs::BinOp(op, l, r) => {
+                let op = match op {
+                    Add => "+",
+                    Sub => "-",
+                    Mult => "*",
+                    Div => "/",
+                    Pow => "^",
+                    BitXor => "xor",
+                    AddPercent => "+%",
+                    SubPercent => "-%",
+                    MultPercent => "*%",
+                    DivPercent => "/%",
+                };
+                write!(f, "{} {} {}", l, op, r)
+            },
+        }
+    }
+}
+
+/// 1行に1命令のテキスト形式で出力する
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instr in &self.instrs {
+            writeln!(f, "t{} = {}", instr.dest, instr.rhs)?;
+        }
+        write!(f, "return {}", self.result)
+    }
+}
+
+/// アリーナの全ての節点を計算する命令をinstrsに追加し、節点ごとの値を表す被演算子を返す
+/// 子は親より前に確保されているので、前から順に見れば被演算子は計算済みになっている
+fn lower(arena: &AstArena, instrs: &mut Vec<Instr>) -> Result<Vec<Operand>, CompileError> {
+    let nodes = arena.nodes();
+    // 負号が付いた2^63は、負号と合わせてi64::MINにする
+    let is_min_literal = |id: usize| match nodes[id].value {
+        NodeKind::UniOp { ref op, e } => op.value == UniOpKind::Minus && nodes[e.index()].value == NodeKind::Num(1 << 63),
+        _ => false,
+    };
+    let min_operands: HashSet<usize> = (0..nodes.len())
+        .filter_map(|id| match nodes[id].value {
+            NodeKind::UniOp { e, .. } if is_min_literal(id) => Some(e.index()),
+            _ => None,
+        })
+        .collect();
+
+    let mut values: Vec<Operand> = Vec::with_capacity(arena.len());
+    for (id, node) in nodes.iter().enumerate() {
+        let rhs = match node.value {
+            NodeKind::Num(_) if min_operands.contains(&id) => {
+                values.push(Operand::Const(i64::MIN));
+                continue;
+            },
+            NodeKind::Num(n) => match i64::try_from(n) {
+                Ok(n) => {
+                    values.push(Operand::Const(n));
+                    continue;
+                },
+                // i64に収まらない数値は、その位置でオーバーフローする命令にする
+                Err(_) => Rhs::UniOp(UniOpKind::Minus, Operand::Const(i64::MIN)),
+            },
+            NodeKind::Var(ref name) => {
+                values.push(Operand::Var(name.clone()));
+                continue;
+            },
+            NodeKind::UniOp { ref op, e } => {
+                if is_min_literal(id) {
+                    values.push(Operand::Const(i64::MIN));
+                    continue;
+                }
+                let e = values[e.index()].clone();
+                if op.value == UniOpKind::Plus {
+                    values.push(e);
+                    continue;
+                }
+                Rhs::UniOp(op.value.clone(), e)
+            },
+            NodeKind::BinOp { ref op, l, r } => {
+                Rhs::BinOp(op.value.clone(), values[l.index()].clone(), values[r.index()].clone())
+            },
+            NodeKind::Tuple(_) => return Err(CompileError::unsupported_tuple("three-address code", node.loc.clone())),
+        };
+        let dest = instrs.len() + 1;
+        instrs.push(Instr { dest, rhs, loc: node.loc.clone() });
+        values.push(Operand::Temp(dest));
+    }
+    Ok(values)
+}
+
+fn operands(rhs: &Rhs) -> Vec<&Operand> {
+    match rhs {
+        Rhs::UniOp(_, e) => vec![e],
+        Rhs::BinOp(_, l, r) => vec![l, r],
+    }
+}
+
+fn rename_operand(operand: &mut Operand, renames: &HashMap<usize, usize>) {
+    if let Operand::Temp(t) = operand {
+        if let Some(&to) = renames.get(t) {
+            *t = to;
+        }
+    }
+}
+
+fn rename_rhs(rhs: &mut Rhs, renames: &HashMap<usize, usize>) {
+    match rhs {
+        Rhs::UniOp(_, e) => rename_operand(e, renames),
+        Rhs::BinOp(_, l, r) => {
+            rename_operand(l, renames);
+            rename_operand(r, renames);
+        },
+    }
+}
+
+/// 実行時エラーになりうる命令か。被演算子が定数で、計算が成功するものだけが安全
+fn may_fail(rhs: &Rhs) -> bool {
+    let mut interp = Interpreter::new();
+    let loc = Loc(0, 0);
+    match rhs {
+        Rhs::UniOp(op, Operand::Const(n)) => interp.eval_uniop(&UniOp::new(op.clone(), loc), *n).is_err(),
+        Rhs::BinOp(op, Operand::Const(l), Operand::Const(r)) => {
+            interp.eval_binop(&BinOp::new(op.clone(), loc), *l, *r).is_err()
+        },
+        _ => true,
+    }
+}
+
+#[test]
+fn test_ir() {
+    let ast = "1 + 2 * 3 - -10".parse::<Ast>().unwrap();
+    let program = Program::from_ast(&ast).unwrap();
+    assert_eq!(
+        program.to_string(),
+        "t1 = 2 * 3\nt2 = 1 + t1\nt3 = neg 10\nt4 = t2 - t3\nreturn t4"
+    );
+    assert_eq!(program.eval(&HashMap::new()), Ok(17));
+
+    let ast = "(x * y + 1) / (x * y + 1) - 4 * 5".parse::<Ast>().unwrap();
+    let mut program = Program::from_ast(&ast).unwrap();
+    program.eliminate_common_subexpressions();
+    assert_eq!(
+        program.to_string(),
+        "t1 = x * y\nt2 = t1 + 1\nt5 = t2 / t2\nt6 = 4 * 5\nt7 = t5 - t6\nreturn t7"
+    );
+
+    let vars: HashMap<String, i64> = [("x".to_string(), 3), ("y".to_string(), 4)].into_iter().collect();
+    let mut interp = Interpreter::new();
+    interp.set_var("x", 3);
+    interp.set_var("y", 4);
+    assert_eq!(program.eval(&vars), interp.eval(&ast));
+
+    let ast = "x * (1, 2)".parse::<Ast>().unwrap();
+    assert_eq!(Program::from_ast(&ast), Err(CompileError::unsupported_tuple("three-address code", Loc(5, 9))));
+
+    // 使われない命令のうち、失敗しないものだけが消える
+    let mut program = Program {
+        instrs: vec![
+            Instr { dest: 1, rhs: Rhs::BinOp(BinOpKind::Mult, Operand::Const(4), Operand::Const(5)), loc: Loc(0, 1) },
+            Instr { dest: 2, rhs: Rhs::BinOp(BinOpKind::Div, Operand::Const(1), Operand::Const(0)), loc: Loc(0, 1) },
+            Instr { dest: 3, rhs: Rhs::UniOp(UniOpKind::Minus, Operand::Var("x".to_string())), loc: Loc(0, 1) },
+        ],
+        result: Operand::Temp(3),
+        loc: Loc(0, 1),
+    };
+    program.eliminate_dead_code();
+    assert_eq!(program.to_string(), "t2 = 1 / 0\nt3 = neg x\nreturn t3");
+}
+
+#[test]
+fn test_ir_literals() {
+    let program = |input: &str| Program::from_ast(&input.parse::<Ast>().unwrap()).unwrap();
+
+    // 負号を付けた2^63は定数のi64::MINになる
+    let min = program("-9223372036854775808");
+    assert_eq!(min.to_string(), "return -9223372036854775808");
+    assert_eq!(min.eval(&HashMap::new()), Ok(i64::MIN));
+
+    // i64に収まらない数値は、そのリテラルの位置でオーバーフローする
+    let mut overflow = program("18446744073709551615 * 0");
+    overflow.eliminate_dead_code();
+    assert_eq!(overflow.to_string(), "t1 = neg -9223372036854775808\nt2 = t1 * 0\nreturn t2");
+    assert_eq!(overflow.eval(&HashMap::new()), Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 20))));
+    assert_eq!(
+        program("--9223372036854775808").eval(&HashMap::new()),
+        Err(InterpreterError::new(InterpreterErrorKind::Overflow, Loc(0, 21)))
+    );
+}
+
+#[test]
+fn test_ir_deep() {
+    // ~~...~1 のように深くネストした式も再帰せずに三番地コードにできる
+    let mut ast = Ast::num(1, Loc(0, 1));
+    for _ in 0..100_000 {
+        ast = Ast::uniop(UniOp::new(UniOpKind::BitNot, Loc(0, 0)), ast, Loc(0, 0));
+    }
+    let program = Program::from_ast(&ast).unwrap();
+    assert_eq!(program.instrs.len(), 100_000);
+    assert_eq!(program.eval(&HashMap::new()), Ok(1));
+}
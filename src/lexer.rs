@@ -2,11 +2,16 @@ use std::fmt;
 
 use crate::utils::{Annot, Loc};
 use crate::error::LexError;
+use crate::span::{self, Span};
 
+/// トークンの種類
+/// 識別子の持ち方Sは、入力を借用するなら `&str`、所有するなら `String` にする
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum TokenKind {
+pub enum TokenKind<S = String> {
     // 数字
     Number(u64),
+    // 識別子
+    Ident(S),
     // +
     Plus,
     // -
@@ -19,6 +24,14 @@ pub enum TokenKind {
     LParen,
     // )
     RParen,
+    // ,
+    Comma,
+    // 演算子表で演算子にできる記号 ! % ^ & | < > = ~ @
+    Symbol(char),
+    // # ... / // ... / /* ... */（ロスレスモードでのみ出力される）
+    Comment,
+    // 空白（ロスレスモードでのみ出力される）
+    Whitespace,
 }
 
 // TokenKindにアノテーションを付与したものをTokenとする
@@ -30,6 +43,10 @@ impl Token {
         Self::new(TokenKind::Number(n), loc)
     }
 
+    pub fn ident(name: &str, loc: Loc) -> Self {
+        Self::new(TokenKind::Ident(name.to_string()), loc)
+    }
+
     pub fn plus(loc: Loc) -> Self {
         Self::new(TokenKind::Plus, loc)
     }
@@ -53,58 +70,190 @@ impl Token {
     pub fn rparen(loc: Loc) -> Self {
         Self::new(TokenKind::RParen, loc)
     }
+
+    pub fn comma(loc: Loc) -> Self {
+        Self::new(TokenKind::Comma, loc)
+    }
+
+    pub fn symbol(c: char, loc: Loc) -> Self {
+        Self::new(TokenKind::Symbol(c), loc)
+    }
+
+    pub fn comment(loc: Loc) -> Self {
+        Self::new(TokenKind::Comment, loc)
+    }
+
+    pub fn whitespace(loc: Loc) -> Self {
+        Self::new(TokenKind::Whitespace, loc)
+    }
+
+    /// コメントや空白など、構文上意味を持たないトークンか
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.value, TokenKind::Comment | TokenKind::Whitespace)
+    }
+}
+
+impl<S> TokenKind<S> {
+    /// 識別子の持ち方を変える
+    pub fn map_ident<T>(self, f: impl FnOnce(S) -> T) -> TokenKind<T> {
+        use self::TokenKind::*;
+        match self {
+            Number(n) => Number(n),
+            Ident(name) => Ident(f(name)),
+            Plus => Plus,
+            Minus => Minus,
+            Asterisk => Asterisk,
+            Slash => Slash,
+            LParen => LParen,
+            RParen => RParen,
+            Comma => Comma,
+            Symbol(c) => Symbol(c),
+            Comment => Comment,
+            Whitespace => Whitespace,
+        }
+    }
+
+    /// トークンの種類の名前
+    pub fn name(&self) -> &'static str {
+        use self::TokenKind::*;
+        match self {
+            Number(_) => "Number",
+            Ident(_) => "Ident",
+            Plus => "Plus",
+            Minus => "Minus",
+            Asterisk => "Asterisk",
+            Slash => "Slash",
+            LParen => "LParen",
+            RParen => "RParen",
+            Comma => "Comma",
+            Symbol(_) => "Symbol",
+            Comment => "Comment",
+            Whitespace => "Whitespace",
+        }
+    }
 }
 
-impl fmt::Display for TokenKind {
+impl<S: fmt::Display> fmt::Display for TokenKind<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::TokenKind::*;
         match self {
             Number(n) => n.fmt(f),
+            Ident(name) => name.fmt(f),
             Plus => write!(f, "+"),
             Minus => write!(f, "-"),
             Asterisk => write!(f, "*"),
             Slash => write!(f, "/"),
             LParen => write!(f, "("),
             RParen => write!(f, ")"),
+            Comma => write!(f, ","),
+            Symbol(c) => c.fmt(f),
+            Comment => write!(f, "comment"),
+            Whitespace => write!(f, "whitespace"),
         }
     }
 }
 
 /// 字句解析器
+/// コメントと空白は読み飛ばす
 pub fn lex(input: &str) -> Result<Vec<Token>, LexError> {
-    // 解析結果を保存するベクタ
-    let mut tokens = Vec::new();
+    Lexer::new(input).map(|r| r.map(span::Token::into_owned)).collect()
+}
+
+/// ロスレスな字句解析器
+/// コメントと空白もトークンとして残すので、トークンを連結すると入力に戻せる
+pub fn lex_lossless(input: &str) -> Result<Vec<Token>, LexError> {
+    Lexer::lossless(input).map(|r| r.map(span::Token::into_owned)).collect()
+}
+
+/// 入力を少しずつ読んで、入力を借用したトークンを1つずつ返す字句解析器
+/// エラーを返した後は何も返さない
+pub struct Lexer<'a> {
     // 入力
-    let input = input.as_bytes();
+    src: &'a str,
     // 位置情報
-    let mut pos = 0;
+    pos: usize,
+    // コメントや空白もトークンとして返すか
+    keep_trivia: bool,
+    // エラーを返したか
+    failed: bool,
+}
 
-    // サブレキサを読んだ後posを更新するマクロ
-    macro_rules! lex_a_token {
-        ($lexer:expr) => {{
-            let (tok, p) = $lexer?;
-            tokens.push(tok);
-            pos = p;
-        }}
+impl<'a> Lexer<'a> {
+    /// コメントと空白を読み飛ばす字句解析器を作る
+    pub fn new(input: &'a str) -> Self {
+        Lexer { src: input, pos: 0, keep_trivia: false, failed: false }
     }
 
-    while pos < input.len() {
+    /// コメントと空白もトークンとして返す字句解析器を作る
+    pub fn lossless(input: &'a str) -> Self {
+        Lexer { keep_trivia: true, ..Self::new(input) }
+    }
+
+    pub(crate) fn source(&self) -> &'a str {
+        self.src
+    }
+
+    /// posの位置から1トークンを読む
+    fn lex_a_token(&self) -> Result<(TokenKind<&'a str>, usize), LexError> {
+        let (input, pos) = (self.src.as_bytes(), self.pos);
         match input[pos] {
-            b'0'..=b'9' => lex_a_token!(lex_number(input, pos)),
-            b'+' => lex_a_token!(lex_plus(input, pos)),
-            b'-' => lex_a_token!(lex_minus(input, pos)),
-            b'*' => lex_a_token!(lex_asterisk(input, pos)),
-            b'/' => lex_a_token!(lex_slash(input, pos)),
-            b'(' => lex_a_token!(lex_lparen(input, pos)),
-            b')' => lex_a_token!(lex_rparen(input, pos)),
-            b' ' | b'\n' | b'\t' => {
-                let ((), p) = skip_spaces(input, pos)?;
-                pos = p;
+            b'0'..=b'9' => lex_number(input, pos),
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => lex_ident(input, pos),
+            b'+' => lex_plus(input, pos),
+            b'-' => lex_minus(input, pos),
+            b'*' => lex_asterisk(input, pos),
+            b'#' => lex_line_comment(input, pos),
+            b'/' => match input.get(pos + 1) {
+                Some(b'/') => lex_line_comment(input, pos),
+                Some(b'*') => lex_block_comment(input, pos),
+                _ => lex_slash(input, pos),
+            },
+            b'(' => lex_lparen(input, pos),
+            b')' => lex_rparen(input, pos),
+            b',' => lex_comma(input, pos),
+            b'!' | b'%' | b'^' | b'&' | b'|' | b'<' | b'>' | b'=' | b'~' | b'@' => {
+                Ok((TokenKind::Symbol(input[pos] as char), pos + 1))
+            },
+            b' ' | b'\n' | b'\t' => lex_spaces(input, pos),
+            // トークンはどれもASCIIなのでposは文字の境界にある。エラーは文字全体を指す
+            _ => {
+                let c = self.src[pos..].chars().next().unwrap();
+                Err(LexError::invalid_char(c, Loc(pos, pos + c.len_utf8())))
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<span::Token<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed && self.pos < self.src.len() {
+            let start = self.pos;
+            match self.lex_a_token() {
+                Ok((kind, pos)) => {
+                    self.pos = pos;
+                    // コメントや空白はロスレスモードのときだけ返す
+                    let trivia = matches!(kind, TokenKind::Comment | TokenKind::Whitespace);
+                    if self.keep_trivia || !trivia {
+                        return Some(Ok(span::Token { kind, span: Span::new(self.src, Loc(start, pos)) }));
+                    }
+                },
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                },
             }
-            b => return Err(LexError::invalid_char(b as char, Loc(pos, pos + 1))),
         }
+        None
     }
-    Ok(tokens)
+}
+
+/// 文字列全体が識別子として読めるか
+pub fn is_ident(s: &str) -> bool {
+    let mut bytes = s.bytes();
+    bytes.next().is_some_and(|b| b.is_ascii_alphabetic() || b == b'_')
+        && bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_')
 }
 
 /// posの位置のバイトが期待したものなら、1バイト消費してposを1つ進める
@@ -122,62 +271,115 @@ fn consume_byte(input: &[u8], pos: usize, b: u8) -> Result<(u8, usize), LexError
     Ok((b, pos + 1))
 }
 
-fn lex_plus(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+fn lex_plus(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     // 以下のようにResult::mapで簡潔に書ける
     consume_byte(input, start, b'+').map(|(_, end)|
-        (Token::plus(Loc(start, end)), end)
+        (TokenKind::Plus, end)
     )
 }
 
-fn lex_minus(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+fn lex_minus(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     consume_byte(input, start, b'-').map(|(_, end)|
-        (Token::minus(Loc(start, end)), end)
+        (TokenKind::Minus, end)
     )
 }
 
-fn lex_asterisk(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+fn lex_asterisk(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     consume_byte(input, start, b'*').map(|(_, end)|
-        (Token::asterisk(Loc(start, end)), end)
+        (TokenKind::Asterisk, end)
     )
 }
 
-fn lex_slash(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+fn lex_slash(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     consume_byte(input, start, b'/').map(|(_, end)|
-        (Token::slash(Loc(start, end)), end)
+        (TokenKind::Slash, end)
     )
 }
 
-fn lex_lparen(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+fn lex_lparen(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     consume_byte(input, start, b'(').map(|(_, end)|
-        (Token::lparen(Loc(start, end)), end)
+        (TokenKind::LParen, end)
     )
 }
 
-fn lex_rparen(input: &[u8], start: usize) -> Result<(Token, usize), LexError> {
+fn lex_rparen(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     consume_byte(input, start, b')').map(|(_, end)|
-        (Token::rparen(Loc(start, end)), end)
+        (TokenKind::RParen, end)
     )
 }
 
-fn lex_number(input: &[u8], pos: usize) -> Result<(Token, usize), LexError> {
+fn lex_comma(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
+    consume_byte(input, start, b',').map(|(_, end)|
+        (TokenKind::Comma, end)
+    )
+}
+
+fn lex_number(input: &[u8], pos: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     use std::str::from_utf8;
 
     // 入力に数字が続く限り位置を進める
     let start = pos;
     let pos = recognize_many(input, pos, |b| b"1234567890".contains(&b));
 
-    // 数字の列を数値に変換
+    // `1.5` のような小数は、`.` が不正な文字だというエラーにせず小数全体を指すエラーにする
+    if input.get(pos) == Some(&b'.') && input.get(pos + 1).is_some_and(u8::is_ascii_digit) {
+        let end = recognize_many(input, pos + 1, |b| b.is_ascii_digit());
+        return Err(LexError::float_literal(Loc(start, end)));
+    }
+
+    // 数字の列を数値に変換。数字だけなので、失敗するのはu64に収まらないときだけ
     let n = from_utf8(&input[start..pos])
         .unwrap() // start..posの範囲でfrom_utf8は常に成功するためunwrap
         .parse()
-        .unwrap(); // 同じ理由
-    Ok((Token::number(n, Loc(start, pos)), pos))
+        .map_err(|_| LexError::number_too_large(Loc(start, pos)))?;
+    Ok((TokenKind::Number(n), pos))
 }
 
-fn skip_spaces(input: &[u8], pos: usize) -> Result<((), usize), LexError> {
+fn lex_ident(input: &[u8], pos: usize) -> Result<(TokenKind<&str>, usize), LexError> {
+    use std::str::from_utf8;
+
+    // 英字か_で始まり、英数字か_が続く
+    let start = pos;
+    let pos = recognize_many(input, pos, |b| b.is_ascii_alphanumeric() || b == b'_');
+
+    let name = from_utf8(&input[start..pos]).unwrap(); // ASCIIだけなので常に成功する
+    Ok((TokenKind::Ident(name), pos))
+}
+
+fn lex_spaces(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
     // 空白が含まれているか判定するのでbyte文字列には' '（スペース）を含める
-    let pos = recognize_many(input, pos, |b| b" \n\t".contains(&b));
-    Ok(((), pos))
+    let pos = recognize_many(input, start, |b| b" \n\t".contains(&b));
+    Ok((TokenKind::Whitespace, pos))
+}
+
+/// `#` または `//` から行末までを読む。改行自体は空白として扱う
+fn lex_line_comment(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
+    let pos = recognize_many(input, start, |b| b != b'\n');
+    Ok((TokenKind::Comment, pos))
+}
+
+/// `/* ... */` を読む。ブロックコメントは入れ子にできる
+fn lex_block_comment(input: &[u8], start: usize) -> Result<(TokenKind<&str>, usize), LexError> {
+    let (_, mut pos) = consume_byte(input, start, b'/')?;
+    pos = consume_byte(input, pos, b'*')?.1;
+    let mut depth = 1;
+
+    while depth > 0 {
+        match (input.get(pos), input.get(pos + 1)) {
+            (Some(b'/'), Some(b'*')) => {
+                depth += 1;
+                pos += 2;
+            },
+            (Some(b'*'), Some(b'/')) => {
+                depth -= 1;
+                pos += 2;
+            },
+            (Some(_), _) => pos += 1,
+            // 閉じられないまま入力が終わったらコメントの開始位置を指す
+            (None, _) => return Err(LexError::unclosed_comment(Loc(start, start + 2))),
+        }
+    }
+    Ok((TokenKind::Comment, pos))
 }
 
 fn recognize_many(input: &[u8], mut pos: usize, mut f: impl FnMut(u8) -> bool) -> usize {
@@ -201,5 +403,55 @@ fn test_lexer() {
             Token::minus(Loc(12, 13)),
             Token::number(10, Loc(13, 15)),
         ])
+    );
+    assert_eq!(
+        lex("x_1*y"),
+        Ok(vec![
+            Token::ident("x_1", Loc(0, 3)),
+            Token::asterisk(Loc(3, 4)),
+            Token::ident("y", Loc(4, 5)),
+        ])
+    );
+    // u64に収まらない数値リテラルは数字の列全体を指すエラーにする
+    assert_eq!(lex("18446744073709551615"), Ok(vec![Token::number(u64::MAX, Loc(0, 20))]));
+    assert_eq!(lex("1 + 99999999999999999999"), Err(LexError::number_too_large(Loc(4, 24))));
+    // 小数はどの方言でも受け付けない
+    assert_eq!(lex("2 * 1.25"), Err(LexError::float_literal(Loc(4, 8))));
+}
+
+#[test]
+fn test_lexer_comment() {
+    assert_eq!(
+        lex("1 # one\n+ /* two /* nested */ */ 2 // end"),
+        Ok(vec![
+            Token::number(1, Loc(0, 1)),
+            Token::plus(Loc(8, 9)),
+            Token::number(2, Loc(33, 34)),
+        ])
+    );
+    assert_eq!(
+        lex_lossless("1/2 /**/"),
+        Ok(vec![
+            Token::number(1, Loc(0, 1)),
+            Token::slash(Loc(1, 2)),
+            Token::number(2, Loc(2, 3)),
+            Token::whitespace(Loc(3, 4)),
+            Token::comment(Loc(4, 8)),
+        ])
+    );
+    assert_eq!(
+        lex("1 + /* /* */ 2"),
+        Err(LexError::unclosed_comment(Loc(4, 6)))
     )
 }
+
+#[test]
+fn test_lexer_stream() {
+    let mut lexer = Lexer::new("1 + $ 2").map(|r| r.map(span::Token::into_owned));
+    assert_eq!(lexer.next(), Some(Ok(Token::number(1, Loc(0, 1)))));
+    assert_eq!(lexer.next(), Some(Ok(Token::plus(Loc(2, 3)))));
+    assert_eq!(lexer.next(), Some(Err(LexError::invalid_char('$', Loc(4, 5)))));
+    assert_eq!(lexer.next(), None);
+    // ASCII以外の文字のエラーは文字全体を指す
+    assert_eq!(lex("1 + é"), Err(LexError::invalid_char('é', Loc(4, 6))));
+}
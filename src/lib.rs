@@ -1,15 +1,71 @@
 mod utils;
+pub use utils::{Annot, Loc};
+
 // 字句解析関係
 pub mod lexer;
 
+// 入力を借用するトークンと区間
+pub mod span;
+
 // 構文解析関係
 pub mod parser;
 
+// 構文解析器の演算子表
+pub mod operator;
+
+// 構文解析の方言の設定
+pub mod options;
+
 // エラー処理
 pub mod error;
 
+// 構文解析と評価の資源の上限
+pub mod limits;
+
 // インタプリタ
 pub mod interpreter;
 
+// 定数畳み込みと簡約
+pub mod optimizer;
+
+// 記号微分
+pub mod derivative;
+
+// アリーナに確保するAST
+pub mod arena;
+
+// 三番地コードの中間表現
+pub mod ir;
+
+// バイトコードへのコンパイラ
+pub mod bytecode;
+
+// バイトコードのファイル形式
+pub mod bytecode_file;
+
+// バイトコードを実行する仮想機械
+pub mod vm;
+
+// C言語のコード生成
+pub mod c_codegen;
+
+// WebAssemblyテキスト形式のコード生成
+pub mod wat;
+
+// x86-64アセンブリのコード生成
+pub mod x86_64;
+
+// 中置記法への整形
+pub mod printer;
+
+// ASTの可視化
+pub mod dump;
+
 // 逆ポーランド記法
-mod reverse_polish;
+pub mod reverse_polish;
+
+// ポーランド記法
+pub mod polish;
+
+// 記法の相互変換
+pub mod notation;
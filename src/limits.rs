@@ -0,0 +1,41 @@
+use crate::parser::DEFAULT_MAX_DEPTH;
+
+/// 信頼できない入力を構文解析・評価するときの資源の上限
+///
+/// 構文解析では入力の長さ、トークン数、ASTの節点数、入れ子の深さを、
+/// 評価では計算の手数を制限する。上限を超えるとそれぞれ専用のエラーになる。
+/// 既定値は入れ子の深さ以外は無制限
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EvalLimits {
+    /// 入力のバイト数
+    pub max_input_len: usize,
+    /// コメントと空白を除いたトークン数
+    pub max_tokens: usize,
+    /// ASTの節点数
+    pub max_nodes: usize,
+    /// 入れ子の深さ。中置記法では括弧、前置演算子、右結合の演算子の入れ子だけを数え、
+    /// `1 + 1 + …` のような左結合の演算子や後置演算子の連なりは節点数だけで制限する。
+    /// 逆ポーランド記法とポーランド記法では読み込み中のスタックの高さを数える
+    pub max_depth: usize,
+    /// 評価で訪れる節点の数
+    pub max_steps: usize,
+}
+
+impl EvalLimits {
+    /// 入れ子の深さ以外は制限しない
+    pub fn unlimited() -> Self {
+        EvalLimits {
+            max_input_len: usize::MAX,
+            max_tokens: usize::MAX,
+            max_nodes: usize::MAX,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_steps: usize::MAX,
+        }
+    }
+}
+
+impl Default for EvalLimits {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
@@ -1,13 +1,117 @@
-use std::io;
+use std::{io, process, str::FromStr};
 
-use parser::{parser::Ast, error::show_trace};
+use parser::{parser::{Ast, AstKind}, error::show_trace};
 use parser::interpreter::Interpreter;
+use parser::printer::{format_source, InfixPrinter};
+use parser::derivative::derive;
+use parser::notation::{convert, Notation};
+use parser::dump::{to_dot, to_sexp, to_tree, tokens_table, tokens_jsonl};
+use parser::lexer::lex;
+use parser::error::Error;
+use parser::reverse_polish::RpnCompiler;
+use parser::options::{ParserOptions, DIALECTS};
 
 fn main() {
-    use std::io::{stdin, BufRead, BufReader};
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // サブコマンドが指定されていなければ式を評価する
+    match args.first().map(String::as_str) {
+        Some("fmt") => process::exit(fmt(&args[1..])),
+        Some("convert") => process::exit(convert_notation(&args[1..])),
+        _ => process::exit(run(&args)),
+    }
+}
+
+/// 式について何を表示するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    /// トークン列の表
+    Tokens,
+    /// トークン列のJSON Lines
+    TokensJsonl,
+    /// 逆ポーランド記法
+    Rpn,
+    /// 評価した値
+    Value,
+    /// ASTのDOT形式
+    AstDot,
+    /// ASTのS式
+    Sexp,
+    /// ASTの木構造
+    Tree,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(Emit::Tokens),
+            "tokens-jsonl" => Ok(Emit::TokensJsonl),
+            "rpn" => Ok(Emit::Rpn),
+            "value" => Ok(Emit::Value),
+            // astはtreeの別名
+            "ast" | "tree" => Ok(Emit::Tree),
+            "ast-dot" => Ok(Emit::AstDot),
+            "sexp" => Ok(Emit::Sexp),
+            _ => Err(format!("unknown emit kind '{}'", s)),
+        }
+    }
+}
+
+/// `parser [--emit=KIND] [--dialect=NAME] [EXPR...]`
+/// EXPRがあればそれぞれを処理し、なければREPLを起動する
+fn run(args: &[String]) -> i32 {
+    const USAGE: &str = "usage: parser [--emit=tokens|tokens-jsonl|ast|ast-dot|sexp|tree|rpn|value] \
+                         [--dialect=default|c-like|calculator] [EXPR...]";
+
+    let mut emit = Emit::Value;
+    let mut opts = ParserOptions::default();
+    let mut exprs = Vec::new();
+    for arg in args {
+        if let Some(name) = arg.strip_prefix("--dialect=") {
+            match ParserOptions::dialect(name) {
+                Some(o) => opts = o,
+                None => {
+                    eprintln!("unknown dialect '{}' (expected one of {})\n{}", name, DIALECTS.join(", "), USAGE);
+                    return 2;
+                },
+            }
+            continue;
+        }
+        match arg.strip_prefix("--emit=") {
+            Some(kind) => match kind.parse() {
+                Ok(kind) => emit = kind,
+                Err(e) => {
+                    eprintln!("{}\n{}", e, USAGE);
+                    return 2;
+                },
+            },
+            None if arg.starts_with("--") => {
+                eprintln!("unknown option '{}'\n{}", arg, USAGE);
+                return 2;
+            },
+            None => exprs.push(arg),
+        }
+    }
 
     // インタプリタを用意しておく
     let mut interp = Interpreter::new();
+    if exprs.is_empty() {
+        repl(&mut interp, emit, &opts);
+        return 0;
+    }
+
+    let mut status = 0;
+    for expr in exprs {
+        if !process_line(expr, &mut interp, emit, &opts) {
+            status = 1;
+        }
+    }
+    status
+}
+
+fn repl(interp: &mut Interpreter, emit: Emit, opts: &ParserOptions) {
+    use std::io::{stdin, BufRead, BufReader};
 
     let stdin = stdin();
     let stdin = stdin.lock();
@@ -18,29 +122,248 @@ fn main() {
         prompt("> ").unwrap();
         // ユーザの入力を取得する
         if let Some(Ok(line)) = lines.next() {
-            let ast = match line.parse::<Ast>() {
-                Ok(ast) => ast,
-                Err(e) => {
-                    e.show_diagnostic(&line);
-                    show_trace(e);
-                    continue
-                },
-            };
-            // インタプリタでevalする
-            let n = match interp.eval(&ast) {
-                Ok(n) => n,
+            // `:diff x <expr>` なら式をxで微分して表示する
+            if let Some(args) = line.strip_prefix(":diff ") {
+                diff(args.trim_start(), opts);
+                continue
+            }
+            // `:let x = <expr>` なら式の値を変数xに束縛する
+            if let Some(args) = line.strip_prefix(":let ") {
+                bind(args.trim_start(), interp, opts);
+                continue
+            }
+            process_line(&line, interp, emit, opts);
+        } else {
+            break;
+        }
+    }
+}
+
+/// 1行の式を処理して結果を表示する。エラーがあれば診断を表示してfalseを返す
+fn process_line(line: &str, interp: &mut Interpreter, emit: Emit, opts: &ParserOptions) -> bool {
+    // トークン列は構文解析の前に表示する
+    if let Emit::Tokens | Emit::TokensJsonl = emit {
+        let tokens = match lex(line) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                let e = Error::from(e);
+                e.show_diagnostic(line);
+                show_trace(e);
+                return false
+            },
+        };
+        if emit == Emit::Tokens {
+            print!("{}", tokens_table(&tokens));
+        } else {
+            print!("{}", tokens_jsonl(&tokens));
+        }
+        return true
+    }
+
+    let ast = match Ast::parse_with(opts, line) {
+        Ok(ast) => ast,
+        Err(e) => {
+            e.show_diagnostic(line);
+            show_trace(e);
+            return false
+        },
+    };
+
+    match emit {
+        Emit::Value => {
+            // インタプリタでevalする。組は `(3, 12)` のように表示する
+            let v = match interp.eval_value(&ast) {
+                Ok(v) => v,
                 Err(e) => {
-                    e.show_diagnostic(&line);
+                    e.show_diagnostic(line);
                     show_trace(e);
-                    continue
+                    return false
                 },
             };
+            println!("{}", v);
+        },
+        Emit::AstDot => print!("{}", to_dot(&ast)),
+        Emit::Sexp => println!("{}", to_sexp(&ast)),
+        Emit::Tree => print!("{}", to_tree(&ast)),
+        Emit::Rpn => println!("{}", RpnCompiler::new().compile(&ast)),
+        Emit::Tokens | Emit::TokensJsonl => unreachable!(),
+    }
+    true
+}
+
+/// REPLの `:diff x <expr>` コマンド
+fn diff(args: &str, opts: &ParserOptions) {
+    let (var, expr) = match args.split_once(char::is_whitespace) {
+        Some((var, expr)) => (var, expr.trim_start()),
+        None => {
+            eprintln!("usage: :diff VAR EXPR");
+            return
+        },
+    };
+
+    let ast = match Ast::parse_with(opts, expr) {
+        Ok(ast) => ast,
+        Err(e) => {
+            e.show_diagnostic(expr);
+            show_trace(e);
+            return
+        },
+    };
+    match derive(&ast, var) {
+        Ok(d) => match InfixPrinter::with_options(opts).print(&d) {
+            Ok(d) => println!("{}", d),
+            Err(e) => show_trace(e),
+        },
+        Err(e) => {
+            e.show_diagnostic(expr);
+            show_trace(e);
+        },
+    }
+}
+
+/// REPLの `:let x = <expr>` コマンド。式を評価して変数に束縛する
+/// 束縛できなければ診断を表示してfalseを返し、変数は変えない
+fn bind(args: &str, interp: &mut Interpreter, opts: &ParserOptions) -> bool {
+    // 変数名は式と同じ字句の規則で読む
+    let var = args.split_once('=').and_then(|(var, expr)| match Ast::parse_with(opts, var) {
+        Ok(Ast { value: AstKind::Var(var), .. }) => Some((var, expr.trim_start())),
+        _ => None,
+    });
+    let (var, expr) = match var {
+        Some(var) => var,
+        None => {
+            eprintln!("usage: :let VAR = EXPR");
+            return false
+        },
+    };
 
-            println!("{:?}", n);
+    let ast = match Ast::parse_with(opts, expr) {
+        Ok(ast) => ast,
+        Err(e) => {
+            e.show_diagnostic(expr);
+            show_trace(e);
+            return false
+        },
+    };
+    match interp.eval(&ast) {
+        Ok(n) => {
+            interp.set_var(&var, n);
+            println!("{} = {}", var, n);
+            true
+        },
+        Err(e) => {
+            e.show_diagnostic(expr);
+            show_trace(e);
+            false
+        },
+    }
+}
+
+/// `parser fmt [--check] [--dialect=NAME] FILE...`
+/// ファイルを整形して上書きする。--checkなら整形済みかどうかだけを調べる
+fn fmt(args: &[String]) -> i32 {
+    use std::fs;
+
+    const USAGE: &str = "usage: parser fmt [--check] [--dialect=default|c-like|calculator] FILE...";
+
+    let mut check = false;
+    let mut opts = ParserOptions::default();
+    let mut files = Vec::new();
+    for arg in args {
+        if arg == "--check" {
+            check = true;
+        } else if let Some(name) = arg.strip_prefix("--dialect=") {
+            match ParserOptions::dialect(name) {
+                Some(o) => opts = o,
+                None => {
+                    eprintln!("unknown dialect '{}' (expected one of {})\n{}", name, DIALECTS.join(", "), USAGE);
+                    return 2;
+                },
+            }
+        } else if arg.starts_with("--") {
+            eprintln!("unknown option '{}'\n{}", arg, USAGE);
+            return 2;
         } else {
-            break;
+            files.push(arg);
         }
     }
+    if files.is_empty() {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+
+    let mut status = 0;
+    for path in files {
+        let input = match fs::read_to_string(path) {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                status = 1;
+                continue
+            },
+        };
+        let output = match format_source(&input, &opts) {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("{}:", path);
+                show_trace(e);
+                status = 1;
+                continue
+            },
+        };
+
+        if output == input {
+            continue
+        }
+        if check {
+            eprintln!("{}: not formatted", path);
+            status = 1;
+        } else if let Err(e) = fs::write(path, output) {
+            eprintln!("{}: {}", path, e);
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `parser convert FROM TO [EXPR]`
+/// 式の記法を変換する。EXPRがなければ標準入力の各行を変換する
+fn convert_notation(args: &[String]) -> i32 {
+    use std::io::{stdin, BufRead};
+
+    const USAGE: &str = "usage: parser convert (infix|rpn|prefix) (infix|rpn|prefix) [EXPR]";
+
+    let (from, to) = match args {
+        [from, to, ..] => match (from.parse::<Notation>(), to.parse::<Notation>()) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("{}\n{}", e, USAGE);
+                return 2;
+            },
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        },
+    };
+
+    let inputs: Vec<String> = match args.get(2) {
+        Some(expr) => vec![expr.clone()],
+        None => stdin().lock().lines().map_while(Result::ok).collect(),
+    };
+
+    let mut status = 0;
+    for input in inputs {
+        match convert(&input, from, to) {
+            Ok(output) => println!("{}", output),
+            Err(e) => {
+                e.show_diagnostic(&input);
+                show_trace(e);
+                status = 1;
+            },
+        }
+    }
+    status
 }
 
 /// プロンプトを表示してユーザの入力を促す
@@ -49,7 +372,28 @@ fn prompt(s: &str) -> io::Result<()> {
 
     let stdout = stdout();
     let mut stdout = stdout.lock();
-    
-    stdout.write(s.as_bytes())?;
+
+    stdout.write_all(s.as_bytes())?;
     stdout.flush()
 }
+
+#[test]
+fn test_bind() {
+    let opts = ParserOptions::default();
+    let mut interp = Interpreter::new();
+    let eval = |interp: &mut Interpreter, s: &str| interp.eval(&s.parse::<Ast>().unwrap()).ok();
+
+    assert!(bind("x = 1 + 2", &mut interp, &opts));
+    assert_eq!(eval(&mut interp, "x * 2"), Some(6));
+    // 束縛済みの変数を使って束縛し直せる
+    assert!(bind("x=x^2", &mut interp, &opts));
+    assert_eq!(eval(&mut interp, "x"), Some(9));
+
+    // 変数名でないもの、評価できない式、組は束縛せず、変数も変えない
+    assert!(!bind("1 = 2", &mut interp, &opts));
+    assert!(!bind("x 3", &mut interp, &opts));
+    assert!(!bind("x = 1 / 0", &mut interp, &opts));
+    assert!(!bind("y = (1, 2)", &mut interp, &opts));
+    assert_eq!(eval(&mut interp, "x"), Some(9));
+    assert_eq!(eval(&mut interp, "y"), None);
+}
@@ -0,0 +1,78 @@
+use std::{fmt, str::FromStr};
+
+use crate::parser::Ast;
+use crate::printer::InfixPrinter;
+use crate::reverse_polish::{RpnCompiler, parse_rpn};
+use crate::polish::{PolishCompiler, parse_polish};
+use crate::error::Error;
+
+/// 式の記法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Notation {
+    /// 中置記法 `1 + 2`
+    Infix,
+    /// 逆ポーランド記法（後置記法） `1 2 +`
+    Postfix,
+    /// ポーランド記法（前置記法） `+ 1 2`
+    Prefix,
+}
+
+impl Notation {
+    /// 指定した記法の式を読み込む
+    pub fn read(self, input: &str) -> Result<Ast, Error> {
+        match self {
+            Notation::Infix => input.parse(),
+            Notation::Postfix => Ok(parse_rpn(input)?),
+            Notation::Prefix => Ok(parse_polish(input)?),
+        }
+    }
+
+    /// ASTを指定した記法で書き出す。中置記法は既定の方言で書く
+    pub fn write(self, ast: &Ast) -> Result<String, Error> {
+        match self {
+            Notation::Infix => Ok(InfixPrinter::new().print(ast)?),
+            Notation::Postfix => Ok(RpnCompiler::new().compile(ast)),
+            Notation::Prefix => Ok(PolishCompiler::new().compile(ast)),
+        }
+    }
+}
+
+impl FromStr for Notation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "infix" => Ok(Notation::Infix),
+            "rpn" | "postfix" => Ok(Notation::Postfix),
+            "polish" | "prefix" => Ok(Notation::Prefix),
+            _ => Err(format!("unknown notation '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Notation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notation::Infix => write!(f, "infix"),
+            Notation::Postfix => write!(f, "rpn"),
+            Notation::Prefix => write!(f, "prefix"),
+        }
+    }
+}
+
+/// 式をある記法から別の記法に変換する
+pub fn convert(input: &str, from: Notation, to: Notation) -> Result<String, Error> {
+    let ast = from.read(input)?;
+    to.write(&ast)
+}
+
+#[test]
+fn test_convert() {
+    use self::Notation::*;
+
+    let infix = "(1 + 2) * -3";
+    let rpn = convert(infix, Infix, Postfix).unwrap();
+    assert_eq!(rpn, "1 2 + 3 neg *");
+    let prefix = convert(&rpn, Postfix, Prefix).unwrap();
+    assert_eq!(prefix, "* + 1 2 neg 3");
+    assert_eq!(convert(&prefix, Prefix, Infix).unwrap(), infix);
+}
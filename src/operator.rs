@@ -0,0 +1,306 @@
+use crate::utils::Loc;
+use crate::lexer::TokenKind;
+use crate::parser::{Ast, AstKind, UniOp, UniOpKind, BinOp, BinOpKind};
+
+/// 二項演算子の結合性
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+/// 演算子を被演算子のどこに置くか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fixity {
+    /// 前置
+    Prefix,
+    /// 中置
+    Infix(Assoc),
+    /// 後置
+    Postfix,
+}
+
+/// 演算子が作る式
+#[derive(Debug, Clone)]
+pub enum OpAction {
+    /// 組み込みの単項演算
+    UniOp(UniOpKind),
+    /// 組み込みの二項演算
+    BinOp(BinOpKind),
+    /// 演算子マクロ。被演算子と式全体の位置から、組み込みの演算だけでできた式を作る
+    MacroUniOp(fn(Ast, Loc) -> Ast),
+    /// 二項の演算子マクロ
+    MacroBinOp(fn(Ast, Ast, Loc) -> Ast),
+}
+
+/// 演算子表の1行
+#[derive(Debug, Clone)]
+pub struct OpDef {
+    /// 演算子の綴り。記号1文字か識別子
+    pub symbol: String,
+    pub fixity: Fixity,
+    /// 優先順位。大きいほど強く結合する
+    pub prec: u8,
+    pub action: OpAction,
+}
+
+/// 構文解析器が使う演算子表
+///
+/// 既定の表は次の通り
+///
+/// | 優先順位 | 演算子 | 種類 |
+/// |---|---|---|
+/// | 10 | `+` `-` | 中置・左結合 |
+/// | 20 | `*` `/` | 中置・左結合 |
+/// | 30 | `+` `-` `!` `~` | 前置 |
+/// | 35 | `^` | 中置・右結合 |
+/// | 40 | `!` `%` | 後置 |
+///
+/// 前置演算子はいくつでも重ねられる（`--5`, `-+5`, `!~x`）。
+/// `^` は前置演算子より強く結合するので `-2^2` は `-(2^2)` になり、
+/// 右辺には前置演算子を書ける（`2^-1`）。前置の `!` は論理否定、`~` はビット反転、
+/// 後置の `!` は階乗になる
+///
+/// `!` は階乗、`%` は百分率で、`x%` は x / 100 を0の方向に丸めた整数になる。
+/// ただし `+` `-` `*` `/` の右辺に百分率を直接書くと、左辺に対する割合として
+/// 丸める前の値で計算する
+///
+/// | 式 | 意味 |
+/// |---|---|
+/// | `a + b%` | `a + (a * b)%` |
+/// | `a - b%` | `a - (a * b)%` |
+/// | `a * b%` | `(a * b)%` |
+/// | `a / b%` | `a * 100 / b` |
+///
+/// `macro_prefix` `macro_infix` `macro_postfix` で登録する演算子マクロは、新しい種類の節点を作らず、
+/// 組み込みの演算を組み合わせた式に構文解析の時点で書き換える。そのため
+///
+/// - どのバックエンドでもそのまま扱えるが、整形や変換で書き戻すと書き換えた後の式になる
+/// - 書き換えた式で被演算子を複数回使うと、その被演算子は複製されて2回以上評価される
+///   （`a mod b` を `a - a / b * b` にすると `a` と `b` を2回ずつ計算する）
+/// - 綴りは記号1文字か識別子に限る。`**` のような複数の記号の並びは1つの演算子にならない
+///
+/// 暗黙の乗算（`2(3 + 4)`, `(1 + 2)(3 + 4)`, `2x`）は既定では無効で、
+/// `implicit_mult` で優先順位を指定すると有効になる。
+/// 式の後ろに `(` が続くか、数値の後ろに識別子が続くと乗算として読む。
+/// ただし識別子の直後の `(`（`x(2)`）は関数呼び出しと紛らわしいのでエラーになる。
+/// `IMPLICIT_MULT_PREC` を指定すると `*` `/` より強く前置演算子より弱く結合するので、
+/// `1 / 2x` は `1 / (2 * x)`、`6/2(3)` は `6 / (2 * 3)` = 1、`-2(3)` は `(-2) * 3` になる
+#[derive(Debug, Clone)]
+pub struct OpTable {
+    defs: Vec<OpDef>,
+    // 暗黙の乗算の優先順位。Noneなら暗黙の乗算を認めない
+    implicit_mult: Option<u8>,
+}
+
+/// 暗黙の乗算の標準の優先順位。既定の表の `*` `/` と前置演算子の間
+pub const IMPLICIT_MULT_PREC: u8 = 25;
+
+impl OpTable {
+    /// 演算子を1つも持たない表を作る
+    pub fn empty() -> Self {
+        OpTable { defs: Vec::new(), implicit_mult: None }
+    }
+
+    /// 演算子を登録する。綴りと置き方が同じ演算子があれば置き換える
+    pub fn add(&mut self, def: OpDef) -> &mut Self {
+        self.defs.retain(|d| !(d.symbol == def.symbol && same_place(d.fixity, def.fixity)));
+        self.defs.push(def);
+        self
+    }
+
+    pub fn prefix(&mut self, symbol: &str, prec: u8, op: UniOpKind) -> &mut Self {
+        self.add(OpDef { symbol: symbol.to_string(), fixity: Fixity::Prefix, prec, action: OpAction::UniOp(op) })
+    }
+
+    pub fn infix(&mut self, symbol: &str, prec: u8, assoc: Assoc, op: BinOpKind) -> &mut Self {
+        self.add(OpDef { symbol: symbol.to_string(), fixity: Fixity::Infix(assoc), prec, action: OpAction::BinOp(op) })
+    }
+
+    pub fn postfix(&mut self, symbol: &str, prec: u8, op: UniOpKind) -> &mut Self {
+        self.add(OpDef { symbol: symbol.to_string(), fixity: Fixity::Postfix, prec, action: OpAction::UniOp(op) })
+    }
+
+    pub fn macro_prefix(&mut self, symbol: &str, prec: u8, f: fn(Ast, Loc) -> Ast) -> &mut Self {
+        self.add(OpDef { symbol: symbol.to_string(), fixity: Fixity::Prefix, prec, action: OpAction::MacroUniOp(f) })
+    }
+
+    pub fn macro_infix(&mut self, symbol: &str, prec: u8, assoc: Assoc, f: fn(Ast, Ast, Loc) -> Ast) -> &mut Self {
+        self.add(OpDef {
+            symbol: symbol.to_string(),
+            fixity: Fixity::Infix(assoc),
+            prec,
+            action: OpAction::MacroBinOp(f),
+        })
+    }
+
+    pub fn macro_postfix(&mut self, symbol: &str, prec: u8, f: fn(Ast, Loc) -> Ast) -> &mut Self {
+        self.add(OpDef { symbol: symbol.to_string(), fixity: Fixity::Postfix, prec, action: OpAction::MacroUniOp(f) })
+    }
+
+    /// 暗黙の乗算を優先順位precの左結合の演算子として有効にする。Noneなら無効にする
+    pub fn implicit_mult(&mut self, prec: Option<u8>) -> &mut Self {
+        self.implicit_mult = prec;
+        self
+    }
+
+    /// 綴りがsymbolで置き方がfixityと同じ演算子を取り除く
+    pub fn remove(&mut self, symbol: &str, fixity: Fixity) -> &mut Self {
+        self.defs.retain(|d| !(d.symbol == symbol && same_place(d.fixity, fixity)));
+        self
+    }
+
+    /// 登録されている演算子
+    pub fn defs(&self) -> &[OpDef] {
+        &self.defs
+    }
+
+    /// 暗黙の乗算の優先順位。無効ならNone
+    pub fn implicit_mult_prec(&self) -> Option<u8> {
+        self.implicit_mult
+    }
+
+    /// トークンを前置演算子として引く
+    pub(crate) fn find_prefix(&self, tok: &TokenKind<&str>) -> Option<&OpDef> {
+        self.find(tok, |f| f == Fixity::Prefix)
+    }
+
+    /// トークンを中置演算子として引く
+    pub(crate) fn find_infix(&self, tok: &TokenKind<&str>) -> Option<&OpDef> {
+        self.find(tok, |f| matches!(f, Fixity::Infix(_)))
+    }
+
+    /// トークンを後置演算子として引く
+    pub(crate) fn find_postfix(&self, tok: &TokenKind<&str>) -> Option<&OpDef> {
+        self.find(tok, |f| f == Fixity::Postfix)
+    }
+
+    fn find(&self, tok: &TokenKind<&str>, fixity: impl Fn(Fixity) -> bool) -> Option<&OpDef> {
+        self.defs.iter().find(|d| fixity(d.fixity) && spelled(tok, &d.symbol))
+    }
+}
+
+impl Default for OpTable {
+    fn default() -> Self {
+        let mut table = Self::empty();
+        table
+            .infix("+", 10, Assoc::Left, BinOpKind::Add)
+            .infix("-", 10, Assoc::Left, BinOpKind::Sub)
+            .infix("*", 20, Assoc::Left, BinOpKind::Mult)
+            .infix("/", 20, Assoc::Left, BinOpKind::Div)
+            .prefix("+", 30, UniOpKind::Plus)
+            .prefix("-", 30, UniOpKind::Minus)
+            .prefix("!", 30, UniOpKind::Not)
+            .prefix("~", 30, UniOpKind::BitNot)
+            .infix("^", 35, Assoc::Right, BinOpKind::Pow)
+            .postfix("!", 40, UniOpKind::Factorial)
+            .postfix("%", 40, UniOpKind::Percent);
+        table
+    }
+}
+
+impl OpDef {
+    /// 被演算子を子に持つ1つの節点ではなく、演算子マクロで書き換えた式を作るか
+    pub(crate) fn rewrites(&self) -> bool {
+        matches!(self.action, OpAction::MacroUniOp(_) | OpAction::MacroBinOp(_))
+    }
+
+    /// 単項演算子を被演算子に適用した式を作る。opは演算子のトークンの位置
+    pub(crate) fn apply_unary(&self, op: Loc, e: Ast) -> Ast {
+        let loc = op.merge(&e.loc);
+        match self.action {
+            OpAction::UniOp(ref kind) => Ast::uniop(UniOp::new(kind.clone(), op), e, loc),
+            OpAction::MacroUniOp(f) => f(e, loc),
+            OpAction::BinOp(_) | OpAction::MacroBinOp(_) => unreachable!(),
+        }
+    }
+
+    /// 二項演算子を被演算子に適用した式を作る。opは演算子のトークンの位置
+    /// r_enclosedは右辺全体が括弧で囲まれているか
+    pub(crate) fn apply_binary(&self, op: Loc, l: Ast, r: Ast, r_enclosed: bool) -> Ast {
+        let loc = l.loc.merge(&r.loc);
+        match self.action {
+            OpAction::BinOp(ref kind) => match (percent_of(kind), r.value) {
+                // 四則演算の右辺の百分率だけを、左辺に対する割合を表す1つの節点にする
+                // 括弧で囲んだ百分率 `(b%)` はそのままにする
+                (Some(kind), AstKind::UniOp { op: ref pct, e }) if pct.value == UniOpKind::Percent && !r_enclosed => {
+                    Ast::binop(BinOp::new(kind, op), l, e.into_inner(), loc)
+                },
+                (_, value) => Ast::binop(BinOp::new(kind.clone(), op), l, Ast::new(value, r.loc), loc),
+            },
+            OpAction::MacroBinOp(f) => f(l, r, loc),
+            OpAction::UniOp(_) | OpAction::MacroUniOp(_) => unreachable!(),
+        }
+    }
+}
+
+/// 右辺が百分率 `b%` のとき、左辺に対する割合として計算する演算。割合を取るのは四則演算だけ
+fn percent_of(kind: &BinOpKind) -> Option<BinOpKind> {
+    match kind {
+        BinOpKind::Add => Some(BinOpKind::AddPercent),
+        BinOpKind::Sub => Some(BinOpKind::SubPercent),
+        BinOpKind::Mult => Some(BinOpKind::MultPercent),
+        BinOpKind::Div => Some(BinOpKind::DivPercent),
+        _ => None,
+    }
+}
+
+/// 演算子の置き方が同じか。中置演算子は結合性が違っても同じ置き方とする
+fn same_place(a: Fixity, b: Fixity) -> bool {
+    matches!(
+        (a, b),
+        (Fixity::Prefix, Fixity::Prefix) | (Fixity::Infix(_), Fixity::Infix(_)) | (Fixity::Postfix, Fixity::Postfix)
+    )
+}
+
+/// トークンの綴りがsymbolか
+fn spelled(tok: &TokenKind<&str>, symbol: &str) -> bool {
+    use self::TokenKind::*;
+
+    match tok {
+        Plus => symbol == "+",
+        Minus => symbol == "-",
+        Asterisk => symbol == "*",
+        Slash => symbol == "/",
+        Symbol(c) => symbol.len() == c.len_utf8() && symbol.starts_with(*c),
+        Ident(name) => symbol == *name,
+        Number(_) | LParen | RParen | Comma | Comment | Whitespace => false,
+    }
+}
+
+#[test]
+fn test_op_table() {
+    use crate::lexer::Lexer;
+    use crate::parser::parse_with_table;
+
+    fn lex(s: &str) -> impl Iterator<Item = crate::span::Token<'_>> {
+        Lexer::new(s).map(Result::unwrap)
+    }
+
+    // a mod b を a - a / b * b に書き換える剰余演算子
+    fn rem(l: Ast, r: Ast, loc: Loc) -> Ast {
+        let q = Ast::binop(BinOp::div(loc.clone()), l.clone(), r.clone(), loc.clone());
+        let m = Ast::binop(BinOp::mult(loc.clone()), q, r, loc.clone());
+        Ast::binop(BinOp::sub(loc.clone()), l, m, loc)
+    }
+
+    let mut table = OpTable::default();
+    table
+        .macro_infix("mod", 20, Assoc::Left, rem)
+        .macro_infix("!", 20, Assoc::Left, rem)
+        // - を右結合にする
+        .infix("-", 10, Assoc::Right, BinOpKind::Sub)
+        .infix("minus", 10, Assoc::Left, BinOpKind::Sub);
+
+    let parse = |s: &str| parse_with_table(lex(s), &table).unwrap();
+    let ast = |s: &str| s.parse::<Ast>().unwrap();
+    let same = |a: &Ast, b: &Ast| crate::dump::to_sexp(a) == crate::dump::to_sexp(b);
+
+    assert!(same(&parse("7 mod 3"), &ast("7 - 7 / 3 * 3")));
+    // 後置演算子と中置演算子の両方にある記号は後置演算子として読む
+    assert!(same(&parse("3 ! - 1"), &ast("3! - 1")));
+    assert!(same(&parse("1 - 2 - 3"), &ast("1 - (2 - 3)")));
+    assert!(same(&parse("1 minus 2 minus 3"), &ast("1 - 2 - 3")));
+    // 登録されていない記号は演算子にならない
+    assert!(parse_with_table(lex("1 @ 2"), &table).is_err());
+}
@@ -0,0 +1,256 @@
+use crate::utils::Loc;
+use crate::parser::{Ast, AstKind, UniOp, UniOpKind, BinOp, BinOpKind, Visit};
+use crate::interpreter::Interpreter;
+use crate::error::{Warning, WarningKind, InterpreterErrorKind};
+
+/// 定数畳み込みと代数的な簡約を行う最適化器を表すデータ型
+/// 畳み込みはInterpreterと同じ意味で計算し、実行時エラーになる部分式は畳み込まずに警告する
+#[derive(Default)]
+pub struct Optimizer {
+    interp: Interpreter,
+    warnings: Vec<Warning>,
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 最適化の過程で見つかった警告
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// 深い式でもスタックが溢れないよう、再帰せずに子から順に畳み込む
+    pub fn optimize(&mut self, expr: &Ast) -> Ast {
+        use self::AstKind::*;
+
+        self.warnings.clear();
+        // 畳み込んだ子の式
+        let mut folded: Vec<Ast> = Vec::new();
+        for visit in expr.walk() {
+            let e = match visit {
+                Visit::Exit(e) => e,
+                Visit::Enter(_) | Visit::Between(..) => continue,
+            };
+            let loc = e.loc.clone();
+            let ast = match e.value {
+                Num(_) | Var(_) => e.clone(),
+                UniOp { ref op, .. } => {
+                    let child = folded.pop().unwrap();
+                    self.fold_uniop(op, child, loc)
+                },
+                BinOp { ref op, .. } => {
+                    let r = folded.pop().unwrap();
+                    let l = folded.pop().unwrap();
+                    self.fold_binop(op, l, r, loc)
+                },
+                Tuple(ref es) => {
+                    let es = folded.split_off(folded.len() - es.len());
+                    Ast::tuple(es, loc)
+                },
+            };
+            folded.push(ast);
+        }
+        folded.pop().unwrap()
+    }
+
+    fn fold_uniop(&mut self, op: &UniOp, e: Ast, loc: Loc) -> Ast {
+        use self::AstKind::*;
+
+        if let Some(n) = const_value(&e) {
+            match self.interp.eval_uniop(op, n) {
+                Ok(n) => return const_ast(n, loc),
+                Err(err) => {
+                    self.warn(err, loc.clone());
+                    return Ast::uniop(op.clone(), e, loc);
+                },
+            }
+        }
+        let mut e = e;
+        match (&op.value, &mut e.value) {
+            // 組に演算子を適用するとエラーになるので、消して組を残すことはしない
+            (_, Tuple(_)) => Ast::uniop(op.clone(), e, loc),
+            // +x => x
+            (UniOpKind::Plus, _) => e,
+            // --x => x
+            (UniOpKind::Minus, UniOp { op: inner, e: x })
+                if inner.value == UniOpKind::Minus && !is_tuple(x) =>
+            {
+                std::mem::replace(&mut **x, Ast::num(0, Loc(0, 0)))
+            },
+            _ => Ast::uniop(op.clone(), e, loc),
+        }
+    }
+
+    fn fold_binop(&mut self, op: &BinOp, l: Ast, r: Ast, loc: Loc) -> Ast {
+        match (const_value(&l), const_value(&r)) {
+            (Some(ln), Some(rn)) => match self.interp.eval_binop(op, ln, rn) {
+                Ok(n) => const_ast(n, loc),
+                Err(e) => {
+                    self.warn(e, loc.clone());
+                    Ast::binop(op.clone(), l, r, loc)
+                },
+            },
+            (lv, rv) => simplify_binop(op, l, lv, r, rv, loc),
+        }
+    }
+
+    fn warn(&mut self, e: InterpreterErrorKind, loc: Loc) {
+        let kind = match e {
+            InterpreterErrorKind::DivisionByZero => WarningKind::DivisionByZero,
+            InterpreterErrorKind::Overflow => WarningKind::Overflow,
+            InterpreterErrorKind::NegativeFactorial => WarningKind::NegativeFactorial,
+            // 変数と組は畳み込みの対象にならず、演算子1つの評価は手数の上限に関係しない
+            InterpreterErrorKind::UnboundVariable(_)
+            | InterpreterErrorKind::StepLimitExceeded
+            | InterpreterErrorKind::UnexpectedTuple
+            | InterpreterErrorKind::InvalidBytecode(_) => unreachable!(),
+        };
+        self.warnings.push(Warning::new(kind, loc));
+    }
+}
+
+/// 片方だけが定数の二項演算の恒等式を簡約する
+fn simplify_binop(op: &BinOp, l: Ast, lv: Option<i64>, r: Ast, rv: Option<i64>, loc: Loc) -> Ast {
+    use self::BinOpKind::*;
+
+    // 組が被演算子ならエラーになる式なので、恒等式で組を残したり消したりしない
+    if is_tuple(&l) || is_tuple(&r) {
+        return Ast::binop(op.clone(), l, r, loc);
+    }
+    match (&op.value, lv, rv) {
+        // x + 0, 0 + x, x - 0 => x
+        (Add, _, Some(0)) | (Sub, _, Some(0)) => l,
+        (Add, Some(0), _) => r,
+        // 0 - x => -x
+        (Sub, Some(0), _) => Ast::uniop(UniOp::minus(op.loc.clone()), r, loc),
+        // x * 1, 1 * x, x / 1, x ^ 1 => x
+        (Mult, _, Some(1)) | (Div, _, Some(1)) | (Pow, _, Some(1)) => l,
+        (Mult, Some(1), _) => r,
+        // x xor 0, 0 xor x => x
+        (BitXor, _, Some(0)) => l,
+        (BitXor, Some(0), _) => r,
+        // x * 0, 0 * x => 0
+        // 消える側が実行時エラーを起こしうるときは畳み込まない
+        (Mult, _, Some(0)) if !may_fail(&l) => r,
+        (Mult, Some(0), _) if !may_fail(&r) => l,
+        _ => Ast::binop(op.clone(), l, r, loc),
+    }
+}
+
+/// 評価すると実行時エラーになりうるか
+/// 変数は値が与えられている前提で、失敗しないものとして扱う。
+/// 検査付きの演算（加減乗除とその百分率版、べき乗、符号反転、階乗）は、定数に畳み込めなかった時点で
+/// 被演算子に変数かエラーになる式を含むので、オーバーフローしうるものとして扱う
+fn may_fail(e: &Ast) -> bool {
+    let mut walk = e.walk();
+    while let Some(visit) = walk.next() {
+        let e = match visit {
+            Visit::Enter(e) => e,
+            Visit::Between(..) | Visit::Exit(_) => continue,
+        };
+        // 定数は失敗しない
+        if const_value(e).is_some() {
+            walk.skip_children();
+            continue;
+        }
+        let fails = match e.value {
+            // 定数でない数値はi64に収まらない
+            AstKind::Num(_) => true,
+            AstKind::Var(_) => false,
+            AstKind::UniOp { ref op, .. } => matches!(op.value, UniOpKind::Minus | UniOpKind::Factorial),
+            AstKind::BinOp { ref op, .. } => op.value != BinOpKind::BitXor,
+            // 組は演算子の被演算子になると実行時エラーになる
+            AstKind::Tuple(_) => true,
+        };
+        if fails {
+            return true;
+        }
+    }
+    false
+}
+
+/// 組を表すASTか。変数の値は整数だけなので、組になるのは組の節点だけ
+fn is_tuple(e: &Ast) -> bool {
+    matches!(e.value, AstKind::Tuple(_))
+}
+
+/// 定数を表すASTならその値を返す
+/// 評価するとオーバーフローするもの（i64::MAXより大きい数値）は定数として扱わない
+fn const_value(e: &Ast) -> Option<i64> {
+    e.literal_value()
+}
+
+/// 定数をASTにする。Numは非負の値しか持てないので負の値は符号反転で表す
+/// i64::MINも -9223372036854775808 と書ける
+fn const_ast(n: i64, loc: Loc) -> Ast {
+    match u64::try_from(n) {
+        Ok(n) => Ast::num(n, loc),
+        Err(_) => Ast::uniop(UniOp::minus(loc.clone()), Ast::num(n.unsigned_abs(), loc.clone()), loc),
+    }
+}
+
+#[test]
+fn test_optimizer() {
+    use crate::printer::InfixPrinter;
+
+    let mut optimizer = Optimizer::new();
+    let mut printer = InfixPrinter::new();
+    let mut optimize = |input: &str| {
+        let ast = input.parse::<Ast>().unwrap();
+        printer.print(&optimizer.optimize(&ast)).unwrap()
+    };
+
+    assert_eq!(optimize("7 * 1 + 0 - (2 * 3)"), "1");
+    assert_eq!(optimize("2 - 3 * 4"), "-10");
+    assert_eq!(optimize("(1 / 0) * (3 - 2) + 0"), "1 / 0");
+    assert_eq!(optimize("-(-(1 / (2 - 2)))"), "1 / 0");
+    assert_eq!(optimize("+(1 / 0) - (4 - 4)"), "1 / 0");
+    assert_eq!(optimize("x * 1 + 0 - (2 * 3)"), "x - 6");
+    assert_eq!(optimize("0 - x * (3 - 3)"), "0");
+    assert_eq!(optimize("(1 / x) * 0"), "1 / x * 0");
+    // 変数を含む検査付きの演算はオーバーフローしうるので消さない
+    assert_eq!(optimize("(x * x) * 0"), "x * x * 0");
+    assert_eq!(optimize("0 * -x"), "0 * -x");
+    assert_eq!(optimize("!~x * 0"), "0");
+    assert_eq!(optimize("(9223372036854775807 + 1) * 1"), "9223372036854775807 + 1");
+    // 組を被演算子にする式はエラーのまま残す
+    assert_eq!(optimize("(1, x) * 1 + 0, +(2, 3), --(4, 5)"), "(1, x) * 1, +(2, 3), --(4, 5)");
+
+    let ast = "1 + 1 / (2 - 2)".parse::<Ast>().unwrap();
+    let optimized = optimizer.optimize(&ast);
+    assert_eq!(optimized.loc, Loc(0, 14));
+    assert_eq!(optimizer.warnings(), [Warning::new(WarningKind::DivisionByZero, Loc(4, 14))]);
+}
+
+#[test]
+fn test_optimizer_literals() {
+    use crate::printer::InfixPrinter;
+
+    let mut optimizer = Optimizer::new();
+    let mut optimize = |input: &str| {
+        let ast = input.parse::<Ast>().unwrap();
+        let optimized = optimizer.optimize(&ast);
+        (InfixPrinter::new().print(&optimized).unwrap(), optimizer.warnings().to_vec())
+    };
+
+    // i64::MINはそのまま負号と2^63で書ける
+    assert_eq!(optimize("-9223372036854775807 - 1"), ("-9223372036854775808".to_string(), vec![]));
+    assert_eq!(optimize("-9223372036854775808 + 0"), ("-9223372036854775808".to_string(), vec![]));
+    assert_eq!(
+        optimize("--9223372036854775808"),
+        ("--9223372036854775808".to_string(), vec![Warning::new(WarningKind::Overflow, Loc(0, 21))]),
+    );
+    // i64に収まらない数値は定数にせず、消しもしない
+    assert_eq!(optimize("9223372036854775808 * 0"), ("9223372036854775808 * 0".to_string(), vec![]));
+    assert_eq!(optimize("18446744073709551615 - 0"), ("18446744073709551615".to_string(), vec![]));
+}
+
+#[test]
+fn test_optimizer_deep() {
+    // 深い式も再帰せずに畳み込む
+    let ast = format!("x{}", " + 1 * 1".repeat(200_000)).parse::<Ast>().unwrap();
+    let optimized = Optimizer::new().optimize(&ast);
+    assert_eq!(optimized.walk().filter(|v| matches!(v, Visit::Enter(_))).count(), 400_001);
+}
@@ -0,0 +1,94 @@
+use crate::parser::BinOpKind;
+use crate::operator::{Assoc, Fixity, OpTable, IMPLICIT_MULT_PREC};
+
+/// `^` の意味
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Caret {
+    /// べき乗。前置演算子より強く結合する右結合の演算子
+    Pow,
+    /// C言語と同じく、`+` `-` より弱く結合する左結合の排他的論理和
+    Xor,
+}
+
+/// 構文解析器が受け付ける文法（方言）の設定
+///
+/// 既定値は `FromStr for Ast` と同じ文法になる。
+/// 評価器は整数だけを扱うので小数のリテラルを読む設定はなく、`1.5` はどの方言でも
+/// `LexErrorKind::FloatLiteral` の字句解析エラーになる
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ParserOptions {
+    /// `2(3 + 4)` や `2x` を乗算として読むか
+    pub implicit_mult: bool,
+    /// `^` の意味
+    pub caret: Caret,
+    /// 前置の `+` を認めるか
+    pub unary_plus: bool,
+}
+
+/// 名前で選べる方言
+pub const DIALECTS: [&str; 3] = ["default", "c-like", "calculator"];
+
+impl ParserOptions {
+    /// C言語風の方言。`^` は排他的論理和になる
+    pub fn c_like() -> Self {
+        ParserOptions { caret: Caret::Xor, ..Self::default() }
+    }
+
+    /// 電卓風の方言。暗黙の乗算を認める
+    pub fn calculator() -> Self {
+        ParserOptions { implicit_mult: true, ..Self::default() }
+    }
+
+    /// 名前から方言を選ぶ。名前は `DIALECTS` のいずれか
+    pub fn dialect(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "c-like" => Some(Self::c_like()),
+            "calculator" => Some(Self::calculator()),
+            _ => None,
+        }
+    }
+
+    /// この設定で構文解析するための演算子表を作る
+    pub fn op_table(&self) -> OpTable {
+        let mut table = OpTable::default();
+        if self.implicit_mult {
+            table.implicit_mult(Some(IMPLICIT_MULT_PREC));
+        }
+        if self.caret == Caret::Xor {
+            table.infix("^", 5, Assoc::Left, BinOpKind::BitXor);
+        }
+        if !self.unary_plus {
+            table.remove("+", Fixity::Prefix);
+        }
+        table
+    }
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions { implicit_mult: false, caret: Caret::Pow, unary_plus: true }
+    }
+}
+
+#[test]
+fn test_options() {
+    use crate::parser::Ast;
+    use crate::interpreter::Interpreter;
+
+    let eval = |opts: &ParserOptions, s: &str| Interpreter::new().eval(&Ast::parse_with(opts, s).unwrap()).unwrap();
+
+    assert_eq!(eval(&ParserOptions::default(), "1 + 2 ^ 3"), 9);
+    assert_eq!(eval(&ParserOptions::c_like(), "1 + 2 ^ 3"), 0);
+    assert_eq!(eval(&ParserOptions::calculator(), "2(3 + 4)"), 14);
+    assert!(Ast::parse_with(&ParserOptions::default(), "2(3 + 4)").is_err());
+
+    let strict = ParserOptions { unary_plus: false, ..ParserOptions::default() };
+    assert!(Ast::parse_with(&strict, "+1").is_err());
+    assert_eq!(eval(&strict, "-1"), -1);
+
+    for name in DIALECTS {
+        assert!(ParserOptions::dialect(name).is_some());
+    }
+    assert_eq!(ParserOptions::dialect("pascal"), None);
+}
@@ -1,41 +1,302 @@
-use std::{iter::Peekable, str::FromStr};
+use std::{collections::BTreeSet, fmt, hash::{Hash, Hasher}, iter::Peekable, ops::{Deref, DerefMut}, str::FromStr};
 
 use crate::utils::{Annot, Loc};
-use crate::lexer::{Token, TokenKind, lex};
-use crate::error::{Error, ParseError};
+use crate::lexer::{TokenKind, Lexer};
+use crate::span::Token;
+use crate::error::{Error, LexError, ParseError};
+use crate::limits::EvalLimits;
+use crate::operator::{Assoc, Fixity, OpDef, OpTable};
+use crate::options::ParserOptions;
 
 /// ASTを表すデータ型
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum AstKind {
     /// 数値
     Num(u64),
+    /// 変数
+    Var(String),
     /// 単項演算
-    UniOp {op: UniOp, e: Box<Ast>},
+    UniOp {op: UniOp, e: AstBox},
     /// 二項演算
-    BinOp {op: BinOp, l: Box<Ast>, r: Box<Ast>},
+    BinOp {op: BinOp, l: AstBox, r: AstBox},
+    /// カンマで区切った2つ以上の式の組
+    Tuple(Vec<AstBox>),
 }
 
 pub type Ast = Annot<AstKind>;
 
+/// 子の式を持つ箱。`Box<Ast>` と同じく `&**e` で中の式を借りられる
+///
+/// 左結合の演算子の長い連なりのような深い木でもスタックが溢れないよう、
+/// 複製、比較、ハッシュと捨てる処理は子孫を再帰せずに平らなスタックでたどる
+pub struct AstBox(Box<Ast>);
+
+impl AstBox {
+    pub fn new(e: Ast) -> Self {
+        AstBox(Box::new(e))
+    }
+
+    /// 箱から式を取り出す
+    pub fn into_inner(mut self) -> Ast {
+        std::mem::replace(&mut *self.0, Ast::num(0, Loc(0, 0)))
+    }
+}
+
+impl Deref for AstBox {
+    type Target = Ast;
+
+    fn deref(&self) -> &Ast {
+        &self.0
+    }
+}
+
+impl DerefMut for AstBox {
+    fn deref_mut(&mut self) -> &mut Ast {
+        &mut self.0
+    }
+}
+
+/// 子の中身を取り出して平らなスタックに積みながら捨てる
+impl Drop for AstBox {
+    fn drop(&mut self) {
+        // 葉でない子を取り出してstackに積み、代わりに葉を置く
+        fn take_children(e: &mut Ast, stack: &mut Vec<Ast>) {
+            let mut take = |c: &mut AstBox| {
+                if !matches!(c.value, AstKind::Num(_) | AstKind::Var(_)) {
+                    stack.push(std::mem::replace(&mut *c.0, Ast::num(0, Loc(0, 0))));
+                }
+            };
+            match e.value {
+                AstKind::Num(_) | AstKind::Var(_) => (),
+                AstKind::UniOp { ref mut e, .. } => take(e),
+                AstKind::BinOp { ref mut l, ref mut r, .. } => {
+                    take(l);
+                    take(r);
+                },
+                AstKind::Tuple(ref mut es) => es.iter_mut().for_each(take),
+            }
+        }
+
+        let mut stack = Vec::new();
+        take_children(&mut self.0, &mut stack);
+        while let Some(mut e) = stack.pop() {
+            take_children(&mut e, &mut stack);
+        }
+    }
+}
+
+/// 子を作り終えてから親を作るので、深い木でも再帰しない
+impl Clone for AstBox {
+    fn clone(&self) -> Self {
+        // 作り終えて、まだ親に渡していない部分木
+        let mut done: Vec<Ast> = Vec::new();
+        for visit in self.walk() {
+            let Visit::Exit(e) = visit else { continue };
+            let loc = e.loc.clone();
+            let copy = match e.value {
+                AstKind::Num(n) => Ast::num(n, loc),
+                AstKind::Var(ref name) => Ast::var(name, loc),
+                AstKind::UniOp { ref op, .. } => {
+                    let e = done.pop().unwrap();
+                    Ast::uniop(op.clone(), e, loc)
+                },
+                AstKind::BinOp { ref op, .. } => {
+                    let r = done.pop().unwrap();
+                    let l = done.pop().unwrap();
+                    Ast::binop(op.clone(), l, r, loc)
+                },
+                AstKind::Tuple(ref es) => {
+                    let es = done.split_off(done.len() - es.len());
+                    Ast::tuple(es, loc)
+                },
+            };
+            done.push(copy);
+        }
+        AstBox::new(done.pop().unwrap())
+    }
+}
+
+/// 2つの木を並べてたどり、節点ごとに子以外を比べる
+impl PartialEq for AstBox {
+    fn eq(&self, other: &Self) -> bool {
+        let mut stack = vec![(&**self, &**other)];
+        while let Some((a, b)) = stack.pop() {
+            let same = a.loc == b.loc && match (&a.value, &b.value) {
+                (AstKind::Num(m), AstKind::Num(n)) => m == n,
+                (AstKind::Var(x), AstKind::Var(y)) => x == y,
+                (AstKind::UniOp { op: p, .. }, AstKind::UniOp { op: q, .. }) => p == q,
+                (AstKind::BinOp { op: p, .. }, AstKind::BinOp { op: q, .. }) => p == q,
+                (AstKind::Tuple(xs), AstKind::Tuple(ys)) => xs.len() == ys.len(),
+                _ => false,
+            };
+            if !same {
+                return false;
+            }
+            stack.extend(a.children().zip(b.children()));
+        }
+        true
+    }
+}
+
+impl Eq for AstBox {}
+
+/// 節点を行きがけ順に、子以外をハッシュする。組は要素の数も含めるので木の形も区別できる
+impl Hash for AstBox {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for visit in self.walk() {
+            let Visit::Enter(e) = visit else { continue };
+            e.loc.hash(state);
+            std::mem::discriminant(&e.value).hash(state);
+            match e.value {
+                AstKind::Num(n) => n.hash(state),
+                AstKind::Var(ref name) => name.hash(state),
+                AstKind::UniOp { ref op, .. } => op.hash(state),
+                AstKind::BinOp { ref op, .. } => op.hash(state),
+                AstKind::Tuple(ref es) => es.len().hash(state),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for AstBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// 式を再帰せずにたどるときに訪れる場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit<'a> {
+    /// 節点に入る。続いて子を左から順にたどる
+    Enter(&'a Ast),
+    /// 節点のi - 1番目とi番目（0から数える）の子の間
+    Between(&'a Ast, usize),
+    /// 子を全てたどり終えて節点から出る
+    Exit(&'a Ast),
+}
+
+/// 式の節点を深さ優先でたどるイテレータ。明示的なスタックを使うので深い木でも溢れない
+pub struct Walk<'a> {
+    stack: Vec<Visit<'a>>,
+}
+
+impl Walk<'_> {
+    /// 直前に入った節点の子をたどらずに飛ばす。その節点から出るExitは返す
+    pub fn skip_children(&mut self) {
+        while !matches!(self.stack.last(), None | Some(Visit::Exit(_))) {
+            self.stack.pop();
+        }
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Visit<'a>;
+
+    fn next(&mut self) -> Option<Visit<'a>> {
+        let visit = self.stack.pop()?;
+        if let Visit::Enter(e) = visit {
+            self.stack.push(Visit::Exit(e));
+            // 左の子から訪れるよう右の子から積み、子と子の間にBetweenを挟む
+            let n = e.children().count();
+            for (i, c) in (0..n).rev().zip(e.children().rev()) {
+                self.stack.push(Visit::Enter(c));
+                if i > 0 {
+                    self.stack.push(Visit::Between(e, i));
+                }
+            }
+        }
+        Some(visit)
+    }
+}
+
 impl Ast {
     pub fn num(n: u64, loc: Loc) -> Self {
         Self::new(AstKind::Num(n), loc)
     }
 
+    pub fn var(name: &str, loc: Loc) -> Self {
+        Self::new(AstKind::Var(name.to_string()), loc)
+    }
+
     pub fn uniop(op: UniOp, e: Ast, loc: Loc) -> Self {
-        Self::new(AstKind::UniOp {op, e: Box::new(e)}, loc)
+        Self::new(AstKind::UniOp {op, e: AstBox::new(e)}, loc)
     }
 
     pub fn binop(op: BinOp, l: Ast, r: Ast, loc: Loc) -> Self {
         Self::new(
             AstKind::BinOp {
                 op,
-                l: Box::new(l),
-                r: Box::new(r),
+                l: AstBox::new(l),
+                r: AstBox::new(r),
             },
             loc,
         )
     }
+
+    pub fn tuple(es: Vec<Ast>, loc: Loc) -> Self {
+        Self::new(AstKind::Tuple(es.into_iter().map(AstBox::new).collect()), loc)
+    }
+
+    /// 方言を指定して文字列を構文解析する
+    pub fn parse_with(opts: &ParserOptions, s: &str) -> Result<Ast, Error> {
+        parse_with_limits(s, opts, &EvalLimits::default())
+    }
+
+    /// 子を左から順に返す
+    pub fn children(&self) -> impl DoubleEndedIterator<Item = &Ast> {
+        let (pair, rest): ([Option<&AstBox>; 2], &[AstBox]) = match self.value {
+            AstKind::Num(_) | AstKind::Var(_) => ([None, None], &[]),
+            AstKind::UniOp { ref e, .. } => ([Some(e), None], &[]),
+            AstKind::BinOp { ref l, ref r, .. } => ([Some(l), Some(r)], &[]),
+            AstKind::Tuple(ref es) => ([None, None], es),
+        };
+        pair.into_iter().flatten().chain(rest).map(|e| &**e)
+    }
+
+    /// 式を深さ優先でたどる。各節点に入るときと出るときと、子と子の間を順に返す
+    pub fn walk(&self) -> Walk<'_> {
+        Walk { stack: vec![Visit::Enter(self)] }
+    }
+
+    /// 負号を付けた2^63 `-9223372036854775808` か
+    /// 2^63はi64に収まらないが、負号と合わせればi64::MINになるので、どの評価器も1つの値として扱う
+    pub fn is_min_literal(&self) -> bool {
+        matches!(
+            self.value,
+            AstKind::UniOp { ref op, ref e } if op.value == UniOpKind::Minus && matches!(e.value, AstKind::Num(n) if n == 1 << 63)
+        )
+    }
+
+    /// 数値か、負号を付けた数値なら、その値を返す。i64に収まらなければNone
+    /// 負号を付けた2^63はi64::MINになる
+    pub fn literal_value(&self) -> Option<i64> {
+        match self.value {
+            AstKind::Num(n) => i64::try_from(n).ok(),
+            AstKind::UniOp { ref op, ref e } if op.value == UniOpKind::Minus => match e.value {
+                AstKind::Num(n) => 0i64.checked_sub_unsigned(n),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// 式に現れる変数の名前を名前順に返す
+    pub fn vars(&self) -> BTreeSet<String> {
+        self.walk()
+            .filter_map(|visit| match visit {
+                Visit::Enter(Ast { value: AstKind::Var(ref name), .. }) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 式に組が含まれていれば、最も左の組を返す。組を扱えないコンパイラが先に調べる
+    pub fn find_tuple(&self) -> Option<&Ast> {
+        self.walk().find_map(|visit| match visit {
+            Visit::Enter(e) if matches!(e.value, AstKind::Tuple(_)) => Some(e),
+            _ => None,
+        })
+    }
 }
 
 /// 単項演算子を表すデータ型
@@ -45,6 +306,14 @@ pub enum UniOpKind {
     Plus,
     /// 負号
     Minus,
+    /// 階乗（後置の `!`）
+    Factorial,
+    /// 百分率（後置の `%`）。x / 100を0の方向に丸めた値
+    Percent,
+    /// 論理否定（前置の `!`）。0なら1、それ以外なら0
+    Not,
+    /// ビット反転（前置の `~`）
+    BitNot,
 }
 
 pub type UniOp = Annot<UniOpKind>;
@@ -57,6 +326,22 @@ impl UniOp {
     pub fn minus(loc: Loc) -> Self {
         Self::new(UniOpKind::Minus, loc)
     }
+
+    pub fn factorial(loc: Loc) -> Self {
+        Self::new(UniOpKind::Factorial, loc)
+    }
+
+    pub fn percent(loc: Loc) -> Self {
+        Self::new(UniOpKind::Percent, loc)
+    }
+
+    pub fn not(loc: Loc) -> Self {
+        Self::new(UniOpKind::Not, loc)
+    }
+
+    pub fn bit_not(loc: Loc) -> Self {
+        Self::new(UniOpKind::BitNot, loc)
+    }
 }
 
 /// 二項演算子を表すデータ型
@@ -70,6 +355,33 @@ pub enum BinOpKind {
     Mult,
     /// 除算
     Div,
+    /// べき乗。指数が負なら1 / l^(-r)を0の方向に丸めた値
+    Pow,
+    /// ビットごとの排他的論理和
+    BitXor,
+    /// 右辺を左辺に対する百分率とする加算 `a + b%`。a + (a * b)%
+    AddPercent,
+    /// 右辺を左辺に対する百分率とする減算 `a - b%`。a - (a * b)%
+    SubPercent,
+    /// 右辺を百分率とする乗算 `a * b%`。(a * b)%
+    MultPercent,
+    /// 右辺を百分率とする除算 `a / b%`。a * 100 / b
+    DivPercent,
+}
+
+impl BinOpKind {
+    /// 百分率つきの四則演算なら、右辺を百分率にしない元の演算子を返す
+    pub fn percent_base(&self) -> Option<BinOpKind> {
+        use self::BinOpKind::*;
+
+        match self {
+            AddPercent => Some(Add),
+            SubPercent => Some(Sub),
+            MultPercent => Some(Mult),
+            DivPercent => Some(Div),
+            Add | Sub | Mult | Div | Pow | BitXor => None,
+        }
+    }
 }
 
 pub type BinOp = Annot<BinOpKind>;
@@ -90,179 +402,342 @@ impl BinOp {
     pub fn div(loc: Loc) -> Self {
         Self::new(BinOpKind::Div, loc)
     }
+
+    pub fn pow(loc: Loc) -> Self {
+        Self::new(BinOpKind::Pow, loc)
+    }
+
+    pub fn bit_xor(loc: Loc) -> Self {
+        Self::new(BinOpKind::BitXor, loc)
+    }
 }
 
-pub fn parse(tokens: Vec<Token>) -> Result<Ast, ParseError> {
-    // 入力をイテレータにし、Peekableにする
-    let mut tokens = tokens.into_iter().peekable();
+/// 括弧の入れ子の深さの既定の上限
+/// 構文解析は括弧や前置演算子、右結合の演算子の入れ子1段ごとに再帰するので、
+/// 深すぎる入力でスタックが溢れないようにする。
+/// 左結合の演算子や後置演算子の連なりは再帰せずに読むので、節点の数の上限まで長くできる。
+/// ASTをたどる処理はどれも再帰しないので、そうしてできた深い木もそのまま扱える
+pub const DEFAULT_MAX_DEPTH: usize = 256;
 
-    // その後、parse_exprを呼んでエラー処理をする
-    let ret = parse_expr(&mut tokens)?;
+/// 方言を指定して、入力を借用したトークン列を構文解析する
+pub fn parse<'src>(tokens: impl IntoIterator<Item = Token<'src>>, opts: &ParserOptions) -> Result<Ast, ParseError> {
+    parse_with_table(tokens, &opts.op_table())
+}
+
+/// 入れ子の深さの上限を指定して構文解析する
+/// 上限を超えたら `ParseError::TooDeep` を返す
+pub fn parse_with_max_depth<'src>(tokens: impl IntoIterator<Item = Token<'src>>, max_depth: usize) -> Result<Ast, ParseError> {
+    let limits = EvalLimits { max_depth, ..EvalLimits::default() };
+    parse_tokens(&mut tokens.into_iter().peekable(), &OpTable::default(), &limits)
+}
+
+/// 演算子表を指定して構文解析する
+pub fn parse_with_table<'src>(tokens: impl IntoIterator<Item = Token<'src>>, table: &OpTable) -> Result<Ast, ParseError> {
+    parse_tokens(&mut tokens.into_iter().peekable(), table, &EvalLimits::default())
+}
+
+/// 式を1つ読み、トークンが余っていればエラーにする
+/// 入れ子の深さと節点の数をlimitsで制限する
+fn parse_tokens<'src, Tokens>(tokens: &mut Peekable<Tokens>, table: &OpTable, limits: &EvalLimits) -> Result<Ast, ParseError>
+where
+    Tokens: Iterator<Item = Token<'src>>,
+{
+    let mut cx = Context { table, nodes: 0, max_nodes: limits.max_nodes };
+    let (ret, _) = parse_list(tokens, &mut cx, limits.max_depth)?;
     match tokens.next() {
-        Some(tok) => Err(ParseError::RedundantExpression(tok)),
+        Some(tok) => Err(ParseError::RedundantExpression(tok.into_owned())),
         None => Ok(ret),
     }
 }
+/// 字句解析器から1トークンずつ受け取りながら構文解析する
+/// 字句解析のエラーは、構文解析器がそのトークンを読もうとした時点で報告する
+pub fn parse_stream<'src, Tokens>(tokens: Tokens) -> Result<Ast, Error>
+where
+    Tokens: Iterator<Item = Result<Token<'src>, LexError>>,
+{
+    parse_stream_with_limits(tokens, &ParserOptions::default(), &EvalLimits::default())
+}
+
+/// 方言と資源の上限を指定して文字列を構文解析する
+/// 入力の長さは字句解析の前に調べるので、長すぎる入力は読まずに捨てる
+pub fn parse_with_limits(input: &str, opts: &ParserOptions, limits: &EvalLimits) -> Result<Ast, Error> {
+    if input.len() > limits.max_input_len {
+        return Err(ParseError::InputTooLong { len: input.len(), max: limits.max_input_len }.into());
+    }
+    parse_stream_with_limits(Lexer::new(input), opts, limits)
+}
 
-// EXPR
-fn parse_expr<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError> 
+/// 方言と資源の上限を指定して、字句解析器から1トークンずつ受け取りながら構文解析する
+pub fn parse_stream_with_limits<'src, Tokens>(tokens: Tokens, opts: &ParserOptions, limits: &EvalLimits) -> Result<Ast, Error>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: Iterator<Item = Result<Token<'src>, LexError>>,
 {
-    // parse_exprはparse_expr3を呼ぶ
-    parse_expr3(tokens)
+    parse_stream_inner(tokens, &opts.op_table(), limits)
 }
 
-// expr3
-fn parse_expr3<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
+fn parse_stream_inner<'src, Tokens>(tokens: Tokens, table: &OpTable, limits: &EvalLimits) -> Result<Ast, Error>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: Iterator<Item = Result<Token<'src>, LexError>>,
 {
-    fn parse_expr3_op<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<BinOp, ParseError>
+    // 最初のエラーを覚えて、そこで入力を打ち切るイテレータ
+    struct Stream<'a, Tokens> {
+        tokens: Tokens,
+        limits: &'a EvalLimits,
+        // 読んだトークンの数
+        count: usize,
+        error: &'a mut Option<Error>,
+    }
+
+    impl<'src, Tokens> Iterator for Stream<'_, Tokens>
     where
-        Tokens: Iterator<Item = Token>,
+        Tokens: Iterator<Item = Result<Token<'src>, LexError>>,
     {
-        let op = tokens.peek()
-            .ok_or(ParseError::Eof)
-            .and_then(|tok| match tok.value {
-                TokenKind::Plus => Ok(BinOp::add(tok.loc.clone())),
-                TokenKind::Minus => Ok(BinOp::sub(tok.loc.clone())),
-                _ => Err(ParseError::NotOperator(tok.clone())),
-            })?;
-        tokens.next();
-        Ok(op)
+        type Item = Token<'src>;
+
+        fn next(&mut self) -> Option<Token<'src>> {
+            if self.error.is_some() {
+                return None;
+            }
+            let tok = match self.tokens.next()? {
+                Ok(tok) => tok,
+                Err(e) => {
+                    *self.error = Some(e.into());
+                    return None;
+                },
+            };
+            self.count += 1;
+            if self.count > self.limits.max_tokens {
+                *self.error = Some(ParseError::TooManyTokens(tok.into_owned()).into());
+                return None;
+            }
+            Some(tok)
+        }
+    }
+
+    let mut error = None;
+    let mut tokens = Stream { tokens, limits, count: 0, error: &mut error }.peekable();
+    let ret = parse_tokens(&mut tokens, table, limits);
+    // 構文解析器が入力の終わりだと思ったところで字句解析エラーや上限超過が起きていれば、そちらを報告する
+    drop(tokens);
+    match error {
+        Some(e) => Err(e),
+        None => Ok(ret?),
     }
+}
 
-    parse_left_binop(tokens, parse_expr2, parse_expr3_op)
+/// 構文解析の間に持ち回る演算子表と、作った節点の数
+struct Context<'a> {
+    table: &'a OpTable,
+    nodes: usize,
+    max_nodes: usize,
 }
 
-// expr2
-fn parse_expr2<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
+impl Context<'_> {
+    /// 節点をn個作ったことを記録する
+    /// 節点の数が上限を超えたら、節点を作った演算子やトークンの位置opを指すエラーにする
+    fn add_nodes(&mut self, n: usize, op: &Loc) -> Result<(), ParseError> {
+        self.nodes = self.nodes.saturating_add(n);
+        if self.nodes > self.max_nodes {
+            return Err(ParseError::TooManyNodes(op.clone()));
+        }
+        Ok(())
+    }
+
+    /// 単項演算子を適用する
+    /// 演算子マクロは複数の節点を作ったり被演算子を複製したりするので、前後の節点の数の差を数える
+    fn unary(&mut self, def: &OpDef, op: Loc, (e, end): Parsed) -> Result<Parsed, ParseError> {
+        let before = def.rewrites().then(|| count_nodes(&e));
+        let e = def.apply_unary(op.clone(), e);
+        self.add_nodes(before.map_or(1, |n| count_nodes(&e).saturating_sub(n)), &op)?;
+        // 後置演算子を適用した式は演算子で終わる
+        let end = if def.fixity == Fixity::Postfix { End::Op } else { end };
+        Ok((e, end))
+    }
+
+    /// 二項演算子を適用する。defがNoneなら暗黙の乗算
+    /// 右辺の百分率を割合にするときは `%` の節点を捨てるが、作った数には含めたままにする
+    fn binary(&mut self, def: Option<&OpDef>, op: Loc, (l, _): Parsed, (r, end): Parsed) -> Result<Parsed, ParseError> {
+        let Some(def) = def else {
+            self.add_nodes(1, &op)?;
+            let loc = l.loc.merge(&r.loc);
+            return Ok((Ast::binop(BinOp::mult(op), l, r, loc), end));
+        };
+        let before = def.rewrites().then(|| count_nodes(&l) + count_nodes(&r));
+        let e = def.apply_binary(op.clone(), l, r, end == End::Paren);
+        self.add_nodes(before.map_or(1, |n| count_nodes(&e).saturating_sub(n)), &op)?;
+        Ok((e, end))
+    }
+}
+
+/// 式の節点の数。深い木でもスタックが溢れないよう平らなスタックで数える
+fn count_nodes(e: &Ast) -> usize {
+    let mut stack = vec![e];
+    let mut n = 0;
+    while let Some(e) = stack.pop() {
+        n += 1;
+        stack.extend(e.children());
+    }
+    n
+}
+
+/// 読んだ式の最後のトークンの種類
+/// 右辺の百分率を割合にするかと、暗黙の乗算を続けられるかは、式がどう終わるかで決まる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum End {
+    /// 数値
+    Number,
+    /// 識別子
+    Ident,
+    /// 閉じ括弧。式全体を括弧で囲んである
+    Paren,
+    /// 演算子
+    Op,
+}
+
+/// 読んだ式と、その最後のトークンの種類
+type Parsed = (Ast, End);
+
+/// カンマで区切った式の並びを読む。式が1つならその式を、2つ以上なら組を返す
+/// カンマはどの演算子よりも弱く結合する
+fn parse_list<'src, Tokens>(tokens: &mut Peekable<Tokens>, cx: &mut Context, depth: usize) -> Result<Parsed, ParseError>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: Iterator<Item = Token<'src>>,
 {
-    fn parse_expr2_op<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<BinOp, ParseError>
-    where
-        Tokens: Iterator<Item = Token>,
-    {
-        let op = tokens.peek()
-            .ok_or(ParseError::Eof)
-            .and_then(|tok| match tok.value {
-                TokenKind::Asterisk => Ok(BinOp::mult(tok.loc.clone())),
-                TokenKind::Slash => Ok(BinOp::div(tok.loc.clone())),
-                _ => Err(ParseError::NotOperator(tok.clone())),
-            })?;
-        tokens.next();
-        Ok(op)
+    let first = parse_expr(tokens, cx, 0, depth)?;
+    let comma = match tokens.peek() {
+        Some(tok) if tok.kind == TokenKind::Comma => tok.span.loc(),
+        _ => return Ok(first),
+    };
+    let (first, mut end) = first;
+    let mut es = vec![first];
+    while tokens.next_if(|tok| tok.kind == TokenKind::Comma).is_some() {
+        let (e, e_end) = parse_expr(tokens, cx, 0, depth)?;
+        es.push(e);
+        end = e_end;
     }
-
-    parse_left_binop(tokens, parse_expr1, parse_expr2_op)
+    let loc = es[0].loc.merge(&es[es.len() - 1].loc);
+    cx.add_nodes(1, &comma).map(|()| (Ast::tuple(es, loc), end))
 }
 
-fn parse_left_binop<Tokens>(
+/// 優先順位がmin_prec以上の演算子だけからなる式を読む（Prattの方法）
+/// depthは残りの入れ子の深さで、括弧と前置演算子の被演算子、右結合の演算子の右辺を読むときに
+/// 再帰が積み重なるので1つずつ減らす。左結合の演算子と後置演算子はループで読むので減らさない。
+/// 括弧の入れ子1段ごとにこの関数とparse_list、parse_atomのフレームが積まれるので、
+/// 最適化しないビルドでもフレームが大きくならないよう、演算子の適用はand_thenで再帰の結果とまとめて?を1つにする
+fn parse_expr<'src, Tokens>(
     tokens: &mut Peekable<Tokens>,
-    subexpr_parser: fn(&mut Peekable<Tokens>) -> Result<Ast, ParseError>,
-    op_parser: fn(&mut Peekable<Tokens>) -> Result<BinOp, ParseError>,
-) -> Result<Ast, ParseError>
+    cx: &mut Context,
+    min_prec: u8,
+    depth: usize,
+) -> Result<Parsed, ParseError>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: Iterator<Item = Token<'src>>,
 {
-    let mut e = subexpr_parser(tokens)?;
-    loop {
-        match tokens.peek() {
-            Some(_) => {
-                let op = match op_parser(tokens) {
-                    Ok(op) => op,
-                    Err(_) => break,
-                };
-                let r = subexpr_parser(tokens)?;
-                let loc = e.loc.merge(&r.loc);
-                e = Ast::binop(op, e, r, loc)
-            },
-            _ => break,
+    let table = cx.table;
+    // 前置演算子か、ATOM
+    let prefix = tokens.peek().and_then(|tok| table.find_prefix(&tok.kind));
+    let mut e = match prefix {
+        Some(def) => {
+            let tok = tokens.next().unwrap();
+            if depth == 0 {
+                return Err(ParseError::TooDeep(tok.span.loc()));
+            }
+            // 被演算子には前置演算子より弱い中置演算子を含めない
+            parse_expr(tokens, cx, def.prec, depth - 1).and_then(|e| cx.unary(def, tok.span.loc(), e))?
+        },
+        None => parse_atom(tokens, cx, depth)?,
+    };
+
+    // 後置演算子と中置演算子を、優先順位がmin_prec未満のものが来るまで読む
+    // 両方に登録された記号は後置演算子として読む
+    while let Some(tok) = tokens.peek() {
+        if let Some(def) = table.find_postfix(&tok.kind).filter(|def| def.prec >= min_prec) {
+            let tok = tokens.next().unwrap();
+            e = cx.unary(def, tok.span.loc(), e)?;
+            continue;
         }
+        let def = match table.find_infix(&tok.kind).filter(|def| def.prec >= min_prec) {
+            Some(def) => def,
+            None => match table.implicit_mult_prec().filter(|&prec| prec >= min_prec) {
+                // `x(2)` は関数呼び出しのように見えるので、乗算にせずエラーにする
+                Some(_) if tok.kind == TokenKind::LParen && e.1 == End::Ident => {
+                    return Err(ParseError::UnexpectedToken(tokens.next().unwrap().into_owned()));
+                },
+                // 暗黙の乗算。演算子のトークンがないので、右辺の先頭の位置を演算子の位置にする
+                Some(prec) if implicit_mult_follows(e.1, &tok.kind) => {
+                    let start = tok.span.loc().0;
+                    e = parse_expr(tokens, cx, prec + 1, depth).and_then(|r| cx.binary(None, Loc(start, start), e, r))?;
+                    continue;
+                },
+                _ => break,
+            },
+        };
+        let tok = tokens.next().unwrap();
+        // 左結合なら右辺には同じ優先順位の演算子を含めない
+        // 右結合の右辺は同じ優先順位の演算子の分だけ再帰が深くなる
+        let r = match def.fixity {
+            Fixity::Infix(Assoc::Left) => parse_expr(tokens, cx, def.prec + 1, depth),
+            _ if depth == 0 => return Err(ParseError::TooDeep(tok.span.loc())),
+            _ => parse_expr(tokens, cx, def.prec, depth - 1),
+        };
+        e = r.and_then(|r| cx.binary(Some(def), tok.span.loc(), e, r))?;
     }
     Ok(e)
 }
 
-// expr1
-fn parse_expr1<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
-where
-    Tokens: Iterator<Item = Token>,
-{
-    match tokens.peek().map(|tok| tok.value) {
-        Some(TokenKind::Plus) | Some(TokenKind::Minus) => {
-            // ("+" | "-")
-            let op = match tokens.next() {
-                Some(Token {
-                    value: TokenKind::Plus,
-                    loc,
-                }) => UniOp::plus(loc),
-                Some(Token {
-                    value: TokenKind::Minus,
-                    loc,
-                }) => UniOp::minus(loc),
-                _ => unreachable!(),
-            };
-            // ATOM
-            let e = parse_atom(tokens)?;
-            let loc = op.loc.merge(&e.loc);
-            Ok(Ast::uniop(op, e, loc))
-        },
-        // | ATOM
-        _ => parse_atom(tokens),
+/// 最後のトークンがendの式の直後のトークンtokが、暗黙の乗算の右辺の始まりか
+/// 括弧は識別子以外の後ろに続けられ、識別子は数値の直後にだけ続けられる
+fn implicit_mult_follows(end: End, tok: &TokenKind<&str>) -> bool {
+    match tok {
+        TokenKind::LParen => end != End::Ident,
+        TokenKind::Ident(_) => end == End::Number,
+        _ => false,
     }
 }
 
 // atom
-fn parse_atom<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Ast, ParseError>
+fn parse_atom<'src, Tokens>(tokens: &mut Peekable<Tokens>, cx: &mut Context, depth: usize) -> Result<Parsed, ParseError>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: Iterator<Item = Token<'src>>,
 {
-    tokens.next()
-        .ok_or(ParseError::Eof)
-        .and_then(|tok| match tok.value {
-            // UNUMBER
-            TokenKind::Number(n) => Ok(Ast::new(AstKind::Num(n), tok.loc)),
-            // | "(", EXPR3, ")"
-            TokenKind::LParen => {
-                let e = parse_expr(tokens)?;
-                match tokens.next() {
-                    Some(Token {
-                        value: TokenKind::RParen,
-                        ..
-                    }) => Ok(e),
-                    Some(t) => Err(ParseError::RedundantExpression(t)),
-                    _ => Err(ParseError::UnclosedOpenParen(tok)),
-                }
+    let tok = tokens.next().ok_or(ParseError::Eof)?;
+    match tok.kind {
+        // UNUMBER
+        TokenKind::Number(n) => {
+            cx.add_nodes(1, &tok.span.loc()).map(|()| (Ast::num(n, tok.span.loc()), End::Number))
+        },
+        // | IDENT
+        TokenKind::Ident(name) => {
+            cx.add_nodes(1, &tok.span.loc()).map(|()| (Ast::var(name, tok.span.loc()), End::Ident))
+        },
+        // | "(", EXPR, {",", EXPR}, ")"
+        TokenKind::LParen if depth == 0 => Err(ParseError::TooDeep(tok.span.loc())),
+        TokenKind::LParen => {
+            let (e, _) = parse_list(tokens, cx, depth - 1)?;
+            match tokens.next() {
+                Some(Token {
+                    kind: TokenKind::RParen,
+                    ..
+                }) => Ok((e, End::Paren)),
+                Some(t) => Err(ParseError::RedundantExpression(t.into_owned())),
+                _ => Err(ParseError::UnclosedOpenParen(tok.into_owned())),
             }
-            _ => Err(ParseError::NotExpression(tok)),
-        })
+        }
+        _ => Err(ParseError::NotExpression(tok.into_owned())),
+    }
 }
 
 impl FromStr for Ast {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // 内部では字句解析、構文解析の順に実行
-        let tokens = lex(s)?;
-        let ast = parse(tokens)?;
-        Ok(ast)
+        // 字句解析しながら構文解析する
+        parse_stream(Lexer::new(s))
     }
 }
 
 #[test]
 fn test_parser() {
-    // 1 + 2 * 3 - -10
-    let ast = parse(vec![
-        Token::number(1, Loc(0, 1)),
-        Token::plus(Loc(2, 3)),
-        Token::number(2, Loc(4, 5)),
-        Token::asterisk(Loc(6, 7)),
-        Token::number(3, Loc(8, 9)),
-        Token::minus(Loc(10, 11)),
-        Token::minus(Loc(12, 13)),
-        Token::number(10, Loc(13, 15)),
-    ]);
+    let ast = parse(Lexer::new("1 + 2 * 3 - -10").map(Result::unwrap), &ParserOptions::default());
 
     assert_eq!(
         ast,
@@ -288,3 +763,153 @@ fn test_parser() {
         ))
     )
 }
+
+#[test]
+fn test_parse_stream() {
+    use crate::error::LexError;
+    use crate::lexer::Token;
+
+    // 字句解析エラーより前に構文エラーがあれば、そちらが報告される
+    assert_eq!(
+        parse_stream(Lexer::new("1 + ) $")),
+        Err(Error::Parser(ParseError::NotExpression(Token::rparen(Loc(4, 5))))),
+    );
+    // 構文解析器が読み進めた先の字句解析エラーが報告される
+    assert_eq!(
+        parse_stream(Lexer::new("1 + 2 $")),
+        Err(Error::Lexer(LexError::invalid_char('$', Loc(6, 7)))),
+    );
+    assert_eq!(parse_stream(Lexer::new("1 + 2")), Ok(Ast::binop(
+        BinOp::add(Loc(2, 3)),
+        Ast::num(1, Loc(0, 1)),
+        Ast::num(2, Loc(4, 5)),
+        Loc(0, 5),
+    )));
+}
+
+#[test]
+fn test_parser_too_deep() {
+    let input = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+    assert_eq!(
+        input.parse::<Ast>(),
+        Err(Error::Parser(ParseError::TooDeep(Loc(DEFAULT_MAX_DEPTH, DEFAULT_MAX_DEPTH + 1)))),
+    );
+
+    let tokens = || Lexer::new("((1)) + (2)").map(Result::unwrap);
+    assert_eq!(parse_with_max_depth(tokens(), 1), Err(ParseError::TooDeep(Loc(1, 2))));
+    assert!(parse_with_max_depth(tokens(), 2).is_ok());
+
+    // 前置演算子と右結合の演算子は入れ子として数える。上限を超えた演算子を指す
+    let input = format!("{}1", "-".repeat(100_000));
+    assert_eq!(input.parse::<Ast>(), Err(Error::Parser(ParseError::TooDeep(Loc(DEFAULT_MAX_DEPTH, DEFAULT_MAX_DEPTH + 1)))));
+    let input = format!("{}1", "2^".repeat(100_000));
+    let op = 2 * DEFAULT_MAX_DEPTH + 1;
+    assert_eq!(input.parse::<Ast>(), Err(Error::Parser(ParseError::TooDeep(Loc(op, op + 1)))));
+}
+
+#[test]
+fn test_parser_long_chain() {
+    use crate::dump::to_sexp;
+
+    // 左結合の演算子と後置演算子の連なりは深さの上限に関わらず読める
+    let ast = format!("1{}", " + 1".repeat(300)).parse::<Ast>().unwrap();
+    assert_eq!(to_sexp(&ast).len(), 6 * 300 + 1);
+
+    // 深い木も再帰せずに複製・比較・破棄できる
+    let ast = format!("1{}", " + 1".repeat(200_000)).parse::<Ast>().unwrap();
+    assert_eq!(ast.clone(), ast);
+    let ast = format!("x{}", "!".repeat(200_000)).parse::<Ast>().unwrap();
+    assert_eq!(to_sexp(&ast).len(), 4 * 200_000 + 1);
+    drop(ast);
+
+    // 連なりは節点数で制限する
+    let limits = EvalLimits { max_nodes: 100, ..EvalLimits::default() };
+    let input = format!("1{}", " + 1".repeat(300));
+    assert!(matches!(parse_with_limits(&input, &ParserOptions::default(), &limits), Err(Error::Parser(ParseError::TooManyNodes(_)))));
+}
+
+#[test]
+fn test_parser_limits() {
+    use crate::lexer::Token;
+
+    let opts = ParserOptions::default();
+    let limits = EvalLimits { max_input_len: 10, ..EvalLimits::default() };
+    assert_eq!(
+        parse_with_limits("1 + 2 + 3 + 4", &opts, &limits),
+        Err(Error::Parser(ParseError::InputTooLong { len: 13, max: 10 })),
+    );
+    // 上限が文字の途中なら、その文字から指す
+    let input = "123456789é + 1";
+    let e = parse_with_limits(input, &opts, &limits).unwrap_err();
+    assert_eq!(e.diagnostic(input).span.loc(), Loc(9, 15));
+    assert!(e.diagnostic(input).to_string().ends_with("\n         ^^^^^\n"));
+
+    let limits = EvalLimits { max_tokens: 4, ..EvalLimits::default() };
+    assert_eq!(
+        parse_with_limits("(1) + 2", &opts, &limits),
+        Err(Error::Parser(ParseError::TooManyTokens(Token::number(2, Loc(6, 7))))),
+    );
+
+    // 括弧は節点にならない
+    let limits = EvalLimits { max_nodes: 3, ..EvalLimits::default() };
+    assert!(parse_with_limits("((1)) + 2", &opts, &limits).is_ok());
+    assert_eq!(
+        parse_with_limits("1 + 2 * 3", &opts, &limits),
+        Err(Error::Parser(ParseError::TooManyNodes(Loc(6, 7)))),
+    );
+    // 暗黙の乗算は演算子のトークンがなくても節点を作る。方言はlimitsとともに指定する
+    let calc = ParserOptions::calculator();
+    assert!(parse_with_limits("2(3)", &calc, &limits).is_ok());
+    assert_eq!(
+        parse_with_limits("2(3)", &calc, &EvalLimits { max_nodes: 2, ..EvalLimits::default() }),
+        Err(Error::Parser(ParseError::TooManyNodes(Loc(1, 1)))),
+    );
+    // 演算子マクロが作った節点も数える
+    let mut table = OpTable::default();
+    table.macro_postfix("%", 40, |e, loc| Ast::binop(BinOp::mult(loc.clone()), e.clone(), e, loc));
+    let parse = |s| parse_stream_inner(Lexer::new(s), &table, &limits);
+    assert!(parse("1%").is_ok());
+    assert_eq!(parse("1%%"), Err(Error::Parser(ParseError::TooManyNodes(Loc(2, 3)))));
+}
+
+#[test]
+fn test_parser_implicit_mult() {
+    use crate::dump::to_sexp;
+    use crate::operator::IMPLICIT_MULT_PREC;
+
+    fn lex(s: &str) -> impl Iterator<Item = Token<'_>> {
+        Lexer::new(s).map(Result::unwrap)
+    }
+
+    let mut table = OpTable::default();
+    table.implicit_mult(Some(IMPLICIT_MULT_PREC));
+    let sexp = |s: &str| to_sexp(&parse_with_table(lex(s), &table).unwrap());
+
+    assert_eq!(sexp("2(3 + 4)"), "(* 2 (+ 3 4))");
+    assert_eq!(sexp("(1 + 2)(3 + 4)"), "(* (+ 1 2) (+ 3 4))");
+    assert_eq!(sexp("-2x"), "(* (neg 2) x)");
+    assert_eq!(sexp("1 / 2x"), "(/ 1 (* 2 x))");
+    assert_eq!(sexp("2(y)!"), "(* 2 (! y))");
+    assert_eq!(sexp("(x)(y)"), "(* x y)");
+    // 識別子の後ろの識別子は乗算にしない
+    assert!(parse_with_table(lex("x y"), &table).is_err());
+    // 既定では無効
+    assert!(matches!(parse(lex("2(3)"), &ParserOptions::default()), Err(ParseError::RedundantExpression(_))));
+}
+
+#[test]
+fn test_parser_implicit_mult_ambiguity() {
+    use crate::lexer::Token;
+
+    let calc = ParserOptions::calculator();
+    // `2(3)` は `/` より強く結合するので、`6/2(3)` は `6 / (2 * 3)` になる
+    assert_eq!(crate::interpreter::Interpreter::new().eval(&Ast::parse_with(&calc, "6/2(3)").unwrap()), Ok(1));
+    // 識別子の後ろの `(` は関数呼び出しと紛らわしいのでエラーにする
+    assert_eq!(
+        Ast::parse_with(&calc, "x(2)"),
+        Err(Error::Parser(ParseError::UnexpectedToken(Token::lparen(Loc(1, 2))))),
+    );
+    // 数値の後ろの数値は乗算にしない
+    assert!(matches!(Ast::parse_with(&calc, "2 3"), Err(Error::Parser(ParseError::RedundantExpression(_)))));
+}
+
@@ -0,0 +1,159 @@
+use crate::utils::Loc;
+use crate::parser::{Ast, AstKind, UniOp, UniOpKind, BinOp, BinOpKind, Visit};
+use crate::reverse_polish::{words, parse_operand, uniop_from_word, binop_from_word, pop_operand, push_node, tuple_len};
+use crate::limits::EvalLimits;
+use crate::error::{RpnError, RpnErrorKind};
+
+/// ポーランド記法（前置記法）へのコンパイラを表すデータ型
+/// 逆ポーランド記法と同じく単項のマイナスは`neg`、階乗は`!`、百分率は`%`、
+/// 論理否定は`not`、ビット反転は`~`、排他的論理和は`xor`、
+/// 右辺を左辺に対する百分率とする四則演算は`+%`、`-%`、`*%`、`/%`として出力する。
+/// 組は要素の数Nを付けた`tuple:N`の後に要素を並べる
+#[derive(Default)]
+pub struct PolishCompiler;
+
+impl PolishCompiler {
+    pub fn new() -> Self {
+        PolishCompiler
+    }
+
+    /// 深い式でもスタックが溢れないよう、再帰せずに木をたどって出力する
+    pub fn compile(&mut self, expr: &Ast) -> String {
+        use self::AstKind::*;
+
+        let mut buf = String::new();
+        for visit in expr.walk() {
+            match visit {
+                Visit::Enter(e) => match e.value {
+                    Num(n) => buf.push_str(&n.to_string()),
+                    Var(ref name) => buf.push_str(name),
+                    UniOp { ref op, .. } => self.compile_uniop(op, &mut buf),
+                    BinOp { ref op, .. } => {
+                        self.compile_binop(op, &mut buf);
+                        buf.push(' ')
+                    },
+                    Tuple(ref es) => buf.push_str(&format!("tuple:{} ", es.len())),
+                },
+                Visit::Between(..) => buf.push(' '),
+                Visit::Exit(_) => (),
+            }
+        }
+        buf
+    }
+
+    fn compile_uniop(&mut self, op: &UniOp, buf: &mut String) {
+        use self::UniOpKind::*;
+
+        match op.value {
+            // 正号は値を変えないので何も出力しない
+            Plus => (),
+            Minus => buf.push_str("neg "),
+            Factorial => buf.push_str("! "),
+            Percent => buf.push_str("% "),
+            Not => buf.push_str("not "),
+            BitNot => buf.push_str("~ "),
+        }
+    }
+
+    fn compile_binop(&mut self, op: &BinOp, buf: &mut String) {
+        use self::BinOpKind::*;
+
+        match op.value {
+            Add => buf.push('+'),
+            Sub => buf.push('-'),
+            Mult => buf.push('*'),
+            Div => buf.push('/'),
+            Pow => buf.push('^'),
+            BitXor => buf.push_str("xor"),
+            AddPercent => buf.push_str("+%"),
+            SubPercent => buf.push_str("-%"),
+            MultPercent => buf.push_str("*%"),
+            DivPercent => buf.push_str("/%"),
+        }
+    }
+}
+
+/// ポーランド記法の式を既定の資源の上限で読み込んでASTにする
+pub fn parse_polish(input: &str) -> Result<Ast, RpnError> {
+    parse_polish_with_limits(input, &EvalLimits::default())
+}
+
+/// ポーランド記法の式を読み込んでASTにする
+/// 後ろから読めば逆ポーランド記法と同じようにスタックで処理できる。
+/// 語の数を節点数として、スタックの高さを入れ子の深さとしてlimitsで制限する
+pub fn parse_polish_with_limits(input: &str, limits: &EvalLimits) -> Result<Ast, RpnError> {
+    let mut stack: Vec<Ast> = Vec::new();
+    let words: Vec<_> = words(input).collect();
+    for (i, (word, loc)) in words.into_iter().rev().enumerate() {
+        let e = match word {
+            "neg" | "!" | "%" | "not" | "~" => {
+                let e = pop_operand(&mut stack, &loc)?;
+                let op = uniop_from_word(word, loc.clone());
+                let loc = loc.merge(&e.loc);
+                Ast::uniop(op, e, loc)
+            },
+            "+" | "-" | "*" | "/" | "^" | "xor" | "+%" | "-%" | "*%" | "/%" => {
+                // 後ろから読んでいるので先に取り出した方が左辺
+                let l = pop_operand(&mut stack, &loc)?;
+                let r = pop_operand(&mut stack, &loc)?;
+                let op = binop_from_word(word, loc.clone());
+                let loc = loc.merge(&l.loc).merge(&r.loc);
+                Ast::binop(op, l, r, loc)
+            },
+            _ => match tuple_len(word) {
+                // 後ろから読んでいるので先に取り出した方が前の要素
+                Some(n) => {
+                    let mut es = Vec::with_capacity(n);
+                    for _ in 0..n {
+                        es.push(pop_operand(&mut stack, &loc)?);
+                    }
+                    let loc = loc.merge(&es[n - 1].loc);
+                    Ast::tuple(es, loc)
+                },
+                None => parse_operand(word, loc.clone())?,
+            },
+        };
+        push_node(&mut stack, e, i + 1, limits, &loc)?;
+    }
+
+    let e = stack.pop()
+        .ok_or_else(|| RpnError::new(RpnErrorKind::Eof, Loc(input.len(), input.len() + 1)))?;
+    // 式の直後に続く余分な値を指す
+    match stack.last() {
+        Some(rest) => Err(RpnError::new(RpnErrorKind::RedundantOperand, rest.loc.clone())),
+        None => Ok(e),
+    }
+}
+
+#[test]
+fn test_polish() {
+    let ast = "1 + 2 * 3 - -10".parse::<Ast>().unwrap();
+    let polish = PolishCompiler::new().compile(&ast);
+    assert_eq!(polish, "- + 1 * 2 3 neg 10");
+
+    let mut compiler = PolishCompiler::new();
+    assert_eq!(compiler.compile(&parse_polish(&polish).unwrap()), polish);
+
+    assert_eq!(
+        parse_polish("- 1 2 3"),
+        Err(RpnError::new(RpnErrorKind::RedundantOperand, Loc(6, 7)))
+    );
+    assert_eq!(
+        parse_polish("* 1"),
+        Err(RpnError::new(RpnErrorKind::StackUnderflow, Loc(0, 1)))
+    );
+}
+
+#[test]
+fn test_parse_polish_limits() {
+    // 後ろから読むので、前にある演算子の被演算子が積まれたままになる
+    let limits = EvalLimits { max_depth: 2, ..EvalLimits::default() };
+    assert!(parse_polish_with_limits("+ 1 + 2 + 3 4", &limits).is_ok());
+    assert_eq!(parse_polish_with_limits("+ + 1 2 3", &limits), Err(RpnError::new(RpnErrorKind::TooDeep, Loc(4, 5))));
+    let limits = EvalLimits { max_nodes: 3, ..EvalLimits::default() };
+    assert_eq!(parse_polish_with_limits("neg + 1 2", &limits), Err(RpnError::new(RpnErrorKind::TooManyNodes, Loc(0, 3))));
+
+    let input = format!("{}1", "neg ".repeat(200_000));
+    let ast = parse_polish(&input).unwrap();
+    assert_eq!(PolishCompiler::new().compile(&ast), input);
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+
+use crate::parser::{parse, Ast, AstBox, AstKind, UniOpKind, BinOpKind, Visit};
+use crate::lexer::{Lexer, TokenKind};
+use crate::error::{CompileError, Error};
+use crate::operator::{Assoc, Fixity, OpAction, OpDef, OpTable};
+use crate::options::ParserOptions;
+
+/// 中置記法のソースコードへのプリンタを表すデータ型
+/// 方言の演算子表の優先順位と結合性から必要な括弧だけを付け、空白を正規化して出力する
+#[derive(Default)]
+pub struct InfixPrinter {
+    table: OpTable,
+}
+
+/// 節点をどう書くか
+#[derive(Clone, Copy)]
+enum Form<'a> {
+    /// 数値・変数・括弧で囲んだ組
+    Atom,
+    Prefix(&'a OpDef),
+    Postfix(&'a OpDef),
+    Infix(&'a OpDef),
+    /// 百分率つきの四則演算 `l op r%`。中置演算子と右辺に付ける後置の `%`
+    PercentOf(&'a OpDef, &'a OpDef),
+}
+
+/// 出力する断片
+enum Task<'a> {
+    /// 節点を、優先順位がslot以上の演算子だけを括弧なしで受け付ける位置に書く
+    Node(&'a Ast, u8),
+    Text(&'a str),
+}
+
+/// 右側が開いていない式。どの演算子も後ろから取り込めない
+const CLOSED: u16 = u16::MAX;
+
+impl InfixPrinter {
+    /// 既定の方言で書くプリンタを作る
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// optionsの方言で書くプリンタを作る。出力はその方言で読み直すと同じ構文木になる
+    pub fn with_options(options: &ParserOptions) -> Self {
+        InfixPrinter { table: options.op_table() }
+    }
+
+    /// 式を書き出す。方言に綴りのない演算を含むときはエラーになる
+    pub fn print(&mut self, expr: &Ast) -> Result<String, CompileError> {
+        let opens = self.opens(expr)?;
+        let mut buf = String::new();
+        let mut tasks = Vec::new();
+        match expr.value {
+            // 一番外側の組だけは括弧で囲まない
+            AstKind::Tuple(ref es) => push_elems(es, &mut tasks),
+            _ => tasks.push(Task::Node(expr, 0)),
+        }
+
+        while let Some(task) = tasks.pop() {
+            let (e, slot) = match task {
+                Task::Text(s) => {
+                    buf.push_str(s);
+                    continue;
+                },
+                Task::Node(e, slot) => (e, slot),
+            };
+            // 後に書くものから積む
+            match (&e.value, self.form(e)?) {
+                (AstKind::Num(n), _) => buf.push_str(&n.to_string()),
+                (AstKind::Var(name), _) => buf.push_str(name),
+                (AstKind::Tuple(es), _) => {
+                    tasks.push(Task::Text(")"));
+                    push_elems(es, &mut tasks);
+                    tasks.push(Task::Text("("));
+                },
+                (AstKind::UniOp { e: c, .. }, Form::Prefix(def)) => {
+                    let paren = !self.accepts(c, def.prec)?;
+                    push_child(c, def.prec, paren, &mut tasks);
+                    if is_word(&def.symbol) {
+                        tasks.push(Task::Text(" "));
+                    }
+                    tasks.push(Task::Text(&def.symbol));
+                },
+                (AstKind::UniOp { e: c, .. }, Form::Postfix(def)) => {
+                    tasks.push(Task::Text(&def.symbol));
+                    if is_word(&def.symbol) {
+                        tasks.push(Task::Text(" "));
+                    }
+                    let paren = !self.accepts(c, slot)? || u16::from(def.prec) >= opens[&key(c)];
+                    push_child(c, slot, paren, &mut tasks);
+                },
+                (AstKind::BinOp { l, r, .. }, Form::Infix(def)) => {
+                    let r_slot = right_slot(def);
+                    let paren = self.right_paren(e, r, r_slot)?;
+                    push_child(r, r_slot, paren, &mut tasks);
+                    push_infix(def, &mut tasks);
+                    let paren = !self.accepts(l, slot)? || u16::from(def.prec) >= opens[&key(l)];
+                    push_child(l, slot, paren, &mut tasks);
+                },
+                (AstKind::BinOp { l, r, .. }, Form::PercentOf(def, pct)) => {
+                    // 右辺を後置の `%` の被演算子として書く
+                    let r_slot = right_slot(def);
+                    tasks.push(Task::Text(&pct.symbol));
+                    let paren = !self.accepts(r, r_slot)? || u16::from(pct.prec) >= opens[&key(r)];
+                    push_child(r, r_slot, paren, &mut tasks);
+                    push_infix(def, &mut tasks);
+                    let paren = !self.accepts(l, slot)? || u16::from(def.prec) >= opens[&key(l)];
+                    push_child(l, slot, paren, &mut tasks);
+                },
+                _ => unreachable!(),
+            }
+        }
+        Ok(buf)
+    }
+
+    /// 各節点について、後ろに続く演算子を取り込んでしまう最小の優先順位を求める
+    /// 括弧を付けずに書いた右端の被演算子がまだ演算子を受け付けている位置のうち、一番弱いもの
+    fn opens(&self, expr: &Ast) -> Result<HashMap<*const Ast, u16>, CompileError> {
+        let mut opens = HashMap::new();
+        for visit in expr.walk() {
+            let e = match visit {
+                Visit::Exit(e) => e,
+                Visit::Enter(_) | Visit::Between(..) => continue,
+            };
+            let open = match (&e.value, self.form(e)?) {
+                (AstKind::UniOp { e: c, .. }, Form::Prefix(def)) => {
+                    let prec = u16::from(def.prec);
+                    if self.accepts(c, def.prec)? { prec.min(opens[&key(c)]) } else { prec }
+                },
+                (AstKind::BinOp { r, .. }, Form::Infix(def)) => {
+                    let r_slot = right_slot(def);
+                    if self.right_paren(e, r, r_slot)? { u16::from(r_slot) } else { u16::from(r_slot).min(opens[&key(r)]) }
+                },
+                // 右辺の後ろの `%` で閉じるので、`%` を書いた位置だけが開いている
+                (_, Form::PercentOf(def, _)) => u16::from(right_slot(def)),
+                (_, Form::Atom | Form::Postfix(_)) => CLOSED,
+                _ => unreachable!(),
+            };
+            opens.insert(key(e), open);
+        }
+        Ok(opens)
+    }
+
+    /// 二項演算の右辺を括弧で囲むか
+    fn right_paren(&self, e: &Ast, r: &Ast, r_slot: u8) -> Result<bool, CompileError> {
+        // 四則演算の右辺の百分率は、括弧で囲まないと左辺に対する割合になってしまう
+        let percent = matches!(e.value, AstKind::BinOp { ref op, .. } if percent_of(&op.value))
+            && matches!(r.value, AstKind::UniOp { ref op, .. } if op.value == UniOpKind::Percent);
+        Ok(percent || !self.accepts(r, r_slot)?)
+    }
+
+    /// 優先順位がslot以上の演算子だけを受け付ける位置に、括弧なしで節点を書けるか
+    fn accepts(&self, e: &Ast, slot: u8) -> Result<bool, CompileError> {
+        Ok(match self.form(e)? {
+            Form::Atom | Form::Prefix(_) => true,
+            Form::Postfix(def) | Form::Infix(def) | Form::PercentOf(def, _) => def.prec >= slot,
+        })
+    }
+
+    fn form(&self, e: &Ast) -> Result<Form<'_>, CompileError> {
+        let find = |action: &dyn Fn(&OpAction) -> bool, fixity: &dyn Fn(Fixity) -> bool, name| {
+            self.table
+                .defs()
+                .iter()
+                .find(|d| fixity(d.fixity) && action(&d.action))
+                .ok_or_else(|| CompileError::unsupported_operator(name, e.loc.clone()))
+        };
+        let uniop = |kind: &UniOpKind| {
+            let kind = kind.clone();
+            move |a: &OpAction| matches!(a, OpAction::UniOp(k) if *k == kind)
+        };
+
+        Ok(match e.value {
+            AstKind::Num(_) | AstKind::Var(_) | AstKind::Tuple(_) => Form::Atom,
+            AstKind::UniOp { ref op, .. } => match op.value {
+                UniOpKind::Factorial | UniOpKind::Percent => {
+                    Form::Postfix(find(&uniop(&op.value), &|f| f == Fixity::Postfix, uniop_name(&op.value))?)
+                },
+                ref kind => Form::Prefix(find(&uniop(kind), &|f| f == Fixity::Prefix, uniop_name(kind))?),
+            },
+            AstKind::BinOp { ref op, .. } => {
+                let kind = op.value.percent_base().unwrap_or_else(|| op.value.clone());
+                let infix = |f| matches!(f, Fixity::Infix(_));
+                let def = find(&|a| matches!(a, OpAction::BinOp(k) if *k == kind), &infix, binop_name(&kind))?;
+                if op.value.percent_base().is_some() {
+                    let pct = find(&uniop(&UniOpKind::Percent), &|f| f == Fixity::Postfix, "percent")?;
+                    Form::PercentOf(def, pct)
+                } else {
+                    Form::Infix(def)
+                }
+            },
+        })
+    }
+}
+
+fn key(e: &Ast) -> *const Ast {
+    e
+}
+
+/// 中置演算子の右辺の位置が受け付ける優先順位
+fn right_slot(def: &OpDef) -> u8 {
+    match def.fixity {
+        Fixity::Infix(Assoc::Right) => def.prec,
+        _ => def.prec + 1,
+    }
+}
+
+/// 識別子の綴りの演算子か。被演算子との間に空白が要る
+fn is_word(symbol: &str) -> bool {
+    symbol.chars().next().is_some_and(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// 右辺が百分率のとき左辺に対する割合として読まれる演算か
+fn percent_of(kind: &BinOpKind) -> bool {
+    matches!(kind, BinOpKind::Add | BinOpKind::Sub | BinOpKind::Mult | BinOpKind::Div)
+}
+
+fn push_child<'a>(e: &'a Ast, slot: u8, paren: bool, tasks: &mut Vec<Task<'a>>) {
+    if paren {
+        // 括弧の中はどの演算子も受け付ける
+        tasks.push(Task::Text(")"));
+        tasks.push(Task::Node(e, 0));
+        tasks.push(Task::Text("("));
+    } else {
+        tasks.push(Task::Node(e, slot));
+    }
+}
+
+fn push_infix<'a>(def: &'a OpDef, tasks: &mut Vec<Task<'a>>) {
+    tasks.push(Task::Text(" "));
+    tasks.push(Task::Text(&def.symbol));
+    tasks.push(Task::Text(" "));
+}
+
+fn push_elems<'a>(es: &'a [AstBox], tasks: &mut Vec<Task<'a>>) {
+    for (i, e) in es.iter().enumerate().rev() {
+        tasks.push(Task::Node(e, 0));
+        if i > 0 {
+            tasks.push(Task::Text(", "));
+        }
+    }
+}
+
+fn uniop_name(kind: &UniOpKind) -> &'static str {
+    use self::UniOpKind::*;
+
+    match kind {
+        Plus => "unary plus",
+        Minus => "negation",
+        Factorial => "factorial",
+        Percent => "percent",
+        Not => "logical not",
+        BitNot => "bitwise not",
+    }
+}
+
+fn binop_name(kind: &BinOpKind) -> &'static str {
+    use self::BinOpKind::*;
+
+    match kind {
+        Add | AddPercent => "addition",
+        Sub | SubPercent => "subtraction",
+        Mult | MultPercent => "multiplication",
+        Div | DivPercent => "division",
+        Pow => "power",
+        BitXor => "xor",
+    }
+}
+
+/// 1行に1つの式が書かれたソースを、optionsの方言で読んで同じ方言で書き直して整形する
+/// 空行はそのまま残し、コメントは整形後の式の後ろに移す
+pub fn format_source(input: &str, options: &ParserOptions) -> Result<String, Error> {
+    // 字句解析エラーは構文解析エラーより先に報告する
+    let tokens = Lexer::lossless(input).collect::<Result<Vec<_>, _>>()?;
+    let mut printer = InfixPrinter::with_options(options);
+    let mut lines = Vec::new();
+    // 現在の行の式のトークンとコメント
+    let mut code = Vec::new();
+    let mut comments = Vec::new();
+
+    // 1行分のトークンを整形してlinesに追加する
+    let mut flush = |code: &mut Vec<_>, comments: &mut Vec<&str>| -> Result<(), Error> {
+        let mut line = Vec::new();
+        if !code.is_empty() {
+            let ast = parse(std::mem::take(code), options)?;
+            line.push(printer.print(&ast)?);
+        }
+        line.extend(comments.drain(..).map(String::from));
+        lines.push(line.join(" "));
+        Ok(())
+    };
+
+    for tok in tokens {
+        match tok.kind {
+            TokenKind::Comment => comments.push(tok.span.as_str()),
+            TokenKind::Whitespace => {
+                // 改行の数だけ行を確定させる
+                for _ in tok.span.as_str().matches('\n') {
+                    flush(&mut code, &mut comments)?;
+                }
+            },
+            _ => code.push(tok),
+        }
+    }
+    if !code.is_empty() || !comments.is_empty() {
+        flush(&mut code, &mut comments)?;
+    }
+
+    let mut output = lines.join("\n");
+    // 末尾の空行は1つの改行にまとめる
+    let len = output.trim_end_matches('\n').len();
+    output.truncate(len);
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+fn assert_round_trip(options: &ParserOptions, input: &str, expected: &str) {
+    use crate::utils::Loc;
+
+    // 位置情報を無視して比較するために全て消す
+    fn strip(ast: &Ast) -> Ast {
+        use crate::parser::{UniOp, BinOp};
+
+        let loc = Loc(0, 0);
+        match ast.value {
+            AstKind::Num(n) => Ast::num(n, loc),
+            AstKind::Var(ref name) => Ast::var(name, loc),
+            AstKind::UniOp { ref op, ref e } => {
+                Ast::uniop(UniOp::new(op.value.clone(), loc.clone()), strip(e), loc)
+            },
+            AstKind::BinOp { ref op, ref l, ref r } => {
+                Ast::binop(BinOp::new(op.value.clone(), loc.clone()), strip(l), strip(r), loc)
+            },
+            AstKind::Tuple(ref es) => Ast::tuple(es.iter().map(|e| strip(e)).collect(), loc),
+        }
+    }
+
+    let ast = Ast::parse_with(options, input).unwrap();
+    let printed = InfixPrinter::with_options(options).print(&ast).unwrap();
+    assert_eq!(printed, expected, "{}", input);
+    assert_eq!(strip(&Ast::parse_with(options, &printed).unwrap()), strip(&ast), "{}", input);
+}
+
+#[test]
+fn test_printer() {
+    let options = ParserOptions::default();
+    for (input, expected) in [
+        ("1+2*3- -10", "1 + 2 * 3 - -10"),
+        ("((1 + 2)) * 3", "(1 + 2) * 3"),
+        ("1 - (2 - 3)", "1 - (2 - 3)"),
+        ("(1 - 2) - 3", "1 - 2 - 3"),
+        ("8 / (4 / 2) * (3)", "8 / (4 / 2) * 3"),
+        ("-(1 + 2)", "-(1 + 2)"),
+        ("-(-1)", "--1"),
+        ("--!~x", "--!~x"),
+        ("-x * (y - 1)", "-x * (y - 1)"),
+        ("2^3^2", "2 ^ 3 ^ 2"),
+        ("(2^3)^2", "(2 ^ 3) ^ 2"),
+        ("2^-x", "2 ^ -x"),
+        ("1+2,(3,x)", "1 + 2, (3, x)"),
+        ("-(1, 2)", "-(1, 2)"),
+    ] {
+        assert_round_trip(&options, input, expected);
+    }
+}
+
+#[test]
+fn test_printer_prefix_and_postfix() {
+    // 括弧は優先順位で決め、要らない括弧は付けない
+    let options = ParserOptions::default();
+    for (input, expected) in [
+        ("-2^2", "-2 ^ 2"),
+        ("(-2)^2", "(-2) ^ 2"),
+        ("-3!", "-3!"),
+        ("(-3)!", "(-3)!"),
+        ("15%%", "15%%"),
+        ("-(!x)!", "-(!x)!"),
+        ("(2^3)!", "(2 ^ 3)!"),
+        ("-x^y!", "-x ^ y!"),
+    ] {
+        assert_round_trip(&options, input, expected);
+    }
+}
+
+#[test]
+fn test_printer_percent_of() {
+    // 右辺の百分率は書いたとおりに戻し、括弧で囲んだ百分率とは区別する
+    let options = ParserOptions::default();
+    for (input, expected) in [
+        ("200+15%", "200 + 15%"),
+        ("x*(y+1)% - 5%", "x * (y + 1)% - 5%"),
+        ("(a / b%)^2", "(a / b%) ^ 2"),
+        ("a + (b%)", "a + (b%)"),
+        ("200 - -(15%)", "200 - -15%"),
+        ("a * b%%", "a * b%%"),
+        ("a + b * (c%)", "a + b * (c%)"),
+    ] {
+        assert_round_trip(&options, input, expected);
+    }
+}
+
+#[test]
+fn test_printer_dialects() {
+    use crate::options::DIALECTS;
+    use crate::error::CompileErrorKind;
+
+    // どの方言でも、書き出した式をその方言で読み直すと同じ構文木になる
+    for name in DIALECTS {
+        let options = ParserOptions::dialect(name).unwrap();
+        assert_round_trip(&options, "(1 + 2) * -x - y!", "(1 + 2) * -x - y!");
+    }
+    assert_round_trip(&ParserOptions::c_like(), "1^2+3", "1 ^ 2 + 3");
+    assert_round_trip(&ParserOptions::c_like(), "(1^2)*3", "(1 ^ 2) * 3");
+    assert_round_trip(&ParserOptions::calculator(), "2x + 3(y - 1)", "2 * x + 3 * (y - 1)");
+
+    // 方言に綴りのない演算は書けない
+    let pow = "2^3".parse::<Ast>().unwrap();
+    let err = InfixPrinter::with_options(&ParserOptions::c_like()).print(&pow).unwrap_err();
+    assert_eq!(err.value, CompileErrorKind::UnsupportedOperator("power"));
+    let plus = "+x".parse::<Ast>().unwrap();
+    let no_plus = ParserOptions { unary_plus: false, ..ParserOptions::default() };
+    assert!(InfixPrinter::with_options(&no_plus).print(&plus).is_err());
+}
+
+#[test]
+fn test_format_source() {
+    assert_eq!(
+        format_source("1+2 # sum\n\n/* note */\n(3)*4\n\n", &ParserOptions::default()).unwrap(),
+        "1 + 2 # sum\n\n/* note */\n3 * 4\n"
+    );
+    assert_eq!(format_source("2x^3\n", &ParserOptions::calculator()).unwrap(), "2 * x ^ 3\n");
+    assert_eq!(format_source("1^2*3\n", &ParserOptions::c_like()).unwrap(), "1 ^ 2 * 3\n");
+}
@@ -1,44 +1,64 @@
-use crate::parser::{Ast, AstKind, UniOp, UniOpKind, BinOp, BinOpKind};
+use std::collections::HashMap;
+
+use crate::utils::Loc;
+use crate::lexer::is_ident;
+use crate::parser::{Ast, AstKind, UniOp, UniOpKind, BinOp, BinOpKind, Visit};
+use crate::interpreter::{factorial, percent_of, pow, Value};
+use crate::limits::EvalLimits;
+use crate::error::{InterpreterErrorKind, RpnError, RpnErrorKind};
 
 /// 逆ポーランド記法へのコンパイラを表すデータ型
-struct RpnCompiler;
+/// 単項のマイナスは二項の`-`と区別するため`neg`として出力する。階乗は`!`、百分率は`%`、
+/// 論理否定は後置の階乗と区別するため`not`、ビット反転は`~`、
+/// 排他的論理和はべき乗の`^`と区別するため`xor`。
+/// 右辺を左辺に対する百分率とする四則演算は、百分率の節点と区別するため`+%`、`-%`、`*%`、`/%`。
+/// 組は要素を並べた後に、要素の数Nを付けた`tuple:N`を出力する
+#[derive(Default)]
+pub struct RpnCompiler;
 
 impl RpnCompiler {
     pub fn new() -> Self {
         RpnCompiler
     }
 
+    /// 深い式でもスタックが溢れないよう、再帰せずに木をたどって出力する
     pub fn compile(&mut self, expr: &Ast) -> String {
-        let mut buf = String::new();
-        self.compile_inner(expr, &mut buf);
-        buf
-    }
-
-    fn compile_inner(&mut self, expr: &Ast, buf: &mut String) {
         use self::AstKind::*;
 
-        match expr.value {
-            Num(n) => buf.push_str(&n.to_string()),
-            UniOp { ref op, ref e } => {
-                self.compile_uniop(op, buf);
-                self.compile_inner(e, buf)
-            },
-            BinOp { ref op, ref l, ref r } => {
-                self.compile_inner(l, buf);
-                buf.push_str(" ");
-                self.compile_inner(r, buf);
-                buf.push_str(" ");
-                self.compile_binop(op, buf)
+        let mut buf = String::new();
+        for visit in expr.walk() {
+            match visit {
+                Visit::Enter(e) => match e.value {
+                    Num(n) => buf.push_str(&n.to_string()),
+                    Var(ref name) => buf.push_str(name),
+                    UniOp { .. } | BinOp { .. } | Tuple(_) => (),
+                },
+                Visit::Between(..) => buf.push(' '),
+                Visit::Exit(e) => match e.value {
+                    UniOp { ref op, .. } => self.compile_uniop(op, &mut buf),
+                    BinOp { ref op, .. } => {
+                        buf.push(' ');
+                        self.compile_binop(op, &mut buf)
+                    },
+                    Tuple(ref es) => buf.push_str(&format!(" tuple:{}", es.len())),
+                    Num(_) | Var(_) => (),
+                },
             }
         }
+        buf
     }
 
     fn compile_uniop(&mut self, op: &UniOp, buf: &mut String) {
         use self::UniOpKind::*;
 
         match op.value {
-            Plus => buf.push_str("+"),
-            Minus => buf.push_str("-"),
+            // 正号は値を変えないので何も出力しない
+            Plus => (),
+            Minus => buf.push_str(" neg"),
+            Factorial => buf.push_str(" !"),
+            Percent => buf.push_str(" %"),
+            Not => buf.push_str(" not"),
+            BitNot => buf.push_str(" ~"),
         }
     }
 
@@ -46,19 +66,288 @@ impl RpnCompiler {
         use self::BinOpKind::*;
 
         match op.value {
-            Add => buf.push_str("+"),
-            Sub => buf.push_str("-"),
-            Mult => buf.push_str("*"),
-            Div => buf.push_str("/"),
+            Add => buf.push('+'),
+            Sub => buf.push('-'),
+            Mult => buf.push('*'),
+            Div => buf.push('/'),
+            Pow => buf.push('^'),
+            BitXor => buf.push_str("xor"),
+            AddPercent => buf.push_str("+%"),
+            SubPercent => buf.push_str("-%"),
+            MultPercent => buf.push_str("*%"),
+            DivPercent => buf.push_str("/%"),
+        }
+    }
+}
+
+/// 逆ポーランド記法の式を既定の資源の上限で読み込んでASTにする
+pub fn parse_rpn(input: &str) -> Result<Ast, RpnError> {
+    parse_rpn_with_limits(input, &EvalLimits::default())
+}
+
+/// 逆ポーランド記法の式を読み込んでASTにする
+/// 語の数を節点数として、スタックの高さを入れ子の深さとしてlimitsで制限する
+pub fn parse_rpn_with_limits(input: &str, limits: &EvalLimits) -> Result<Ast, RpnError> {
+    let mut stack: Vec<Ast> = Vec::new();
+    for (i, (word, loc)) in words(input).enumerate() {
+        let e = match word {
+            "neg" | "!" | "%" | "not" | "~" => {
+                let e = pop_operand(&mut stack, &loc)?;
+                let op = uniop_from_word(word, loc.clone());
+                let loc = e.loc.merge(&loc);
+                Ast::uniop(op, e, loc)
+            },
+            "+" | "-" | "*" | "/" | "^" | "xor" | "+%" | "-%" | "*%" | "/%" => {
+                let r = pop_operand(&mut stack, &loc)?;
+                let l = pop_operand(&mut stack, &loc)?;
+                let op = binop_from_word(word, loc.clone());
+                let loc = l.loc.merge(&r.loc).merge(&loc);
+                Ast::binop(op, l, r, loc)
+            },
+            _ => match tuple_len(word) {
+                Some(n) => {
+                    let mut es = Vec::with_capacity(n);
+                    for _ in 0..n {
+                        es.push(pop_operand(&mut stack, &loc)?);
+                    }
+                    es.reverse();
+                    let loc = es[0].loc.merge(&loc);
+                    Ast::tuple(es, loc)
+                },
+                None => parse_operand(word, loc.clone())?,
+            },
+        };
+        push_node(&mut stack, e, i + 1, limits, &loc)?;
+    }
+
+    let e = stack.pop()
+        .ok_or_else(|| RpnError::new(RpnErrorKind::Eof, Loc(input.len(), input.len() + 1)))?;
+    match stack.first() {
+        Some(rest) => Err(RpnError::new(RpnErrorKind::RedundantOperand, rest.loc.clone())),
+        None => Ok(e),
+    }
+}
+
+/// 語から作った節点をスタックに積む。語は1つずつ節点になるので、nodesはそれまでに読んだ語の数
+/// 節点数かスタックの高さが上限を超えたら、その語を指すエラーにする
+pub(crate) fn push_node(stack: &mut Vec<Ast>, e: Ast, nodes: usize, limits: &EvalLimits, loc: &Loc) -> Result<(), RpnError> {
+    if nodes > limits.max_nodes {
+        return Err(RpnError::new(RpnErrorKind::TooManyNodes, loc.clone()));
+    }
+    if stack.len() >= limits.max_depth {
+        return Err(RpnError::new(RpnErrorKind::TooDeep, loc.clone()));
+    }
+    stack.push(e);
+    Ok(())
+}
+
+/// 数値か変数の語をASTにする。RPNとポーランド記法で共通
+pub(crate) fn parse_operand(word: &str, loc: Loc) -> Result<Ast, RpnError> {
+    if let Ok(n) = word.parse::<u64>() {
+        return Ok(Ast::num(n, loc));
+    }
+    // 中置記法の識別子と同じ規則で変数名を判定する
+    if is_ident(word) && !matches!(word, "neg" | "not" | "xor") {
+        Ok(Ast::var(word, loc))
+    } else {
+        Err(RpnError::new(RpnErrorKind::InvalidWord(word.to_string()), loc))
+    }
+}
+
+/// 組を作る語 `tuple:N` ならその要素の数Nを返す。組の要素は2つ以上
+pub(crate) fn tuple_len(word: &str) -> Option<usize> {
+    word.strip_prefix("tuple:")
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n >= 2)
+}
+
+/// 単項演算子の語を演算子にする
+pub(crate) fn uniop_from_word(word: &str, loc: Loc) -> UniOp {
+    match word {
+        "neg" => UniOp::minus(loc),
+        "!" => UniOp::factorial(loc),
+        "%" => UniOp::percent(loc),
+        "not" => UniOp::not(loc),
+        "~" => UniOp::bit_not(loc),
+        _ => unreachable!(),
+    }
+}
+
+/// 二項演算子の語を演算子にする
+pub(crate) fn binop_from_word(word: &str, loc: Loc) -> BinOp {
+    match word {
+        "+" => BinOp::add(loc),
+        "-" => BinOp::sub(loc),
+        "*" => BinOp::mult(loc),
+        "/" => BinOp::div(loc),
+        "^" => BinOp::pow(loc),
+        "xor" => BinOp::bit_xor(loc),
+        "+%" => BinOp::new(BinOpKind::AddPercent, loc),
+        "-%" => BinOp::new(BinOpKind::SubPercent, loc),
+        "*%" => BinOp::new(BinOpKind::MultPercent, loc),
+        "/%" => BinOp::new(BinOpKind::DivPercent, loc),
+        _ => unreachable!(),
+    }
+}
+
+/// 演算子の被演算子をスタックから取り出す。足りなければ演算子の位置を指す
+pub(crate) fn pop_operand(stack: &mut Vec<Ast>, op_loc: &Loc) -> Result<Ast, RpnError> {
+    stack.pop()
+        .ok_or_else(|| RpnError::new(RpnErrorKind::StackUnderflow, op_loc.clone()))
+}
+
+/// 逆ポーランド記法を実行するスタックマシンを表すデータ型
+/// RpnCompilerの出力をそのまま実行でき、変数は `set_var` で設定した値を使う
+#[derive(Default)]
+pub struct RpnMachine {
+    // 値とそれを積んだ語の位置情報
+    stack: Vec<(Value, Loc)>,
+    // 変数の値
+    vars: HashMap<String, i64>,
+}
+
+impl RpnMachine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 変数に値を設定する
+    pub fn set_var(&mut self, name: &str, value: i64) {
+        self.vars.insert(name.to_string(), value);
+    }
+
+    /// 数値になる式を実行する。値が組ならその組を作った語を指すエラーにする
+    pub fn eval(&mut self, input: &str) -> Result<i64, RpnError> {
+        match self.run(input)? {
+            (Value::Int(n), _) => Ok(n),
+            (Value::Tuple(_), loc) => Err(RpnError::new(RpnErrorKind::UnexpectedTuple, loc)),
+        }
+    }
+
+    /// 組を含む式を実行する。演算子の被演算子が組ならエラーにする
+    pub fn eval_value(&mut self, input: &str) -> Result<Value, RpnError> {
+        self.run(input).map(|(v, _)| v)
+    }
+
+    // 最後の値とそれを積んだ語の位置情報を返す
+    fn run(&mut self, input: &str) -> Result<(Value, Loc), RpnError> {
+        self.stack.clear();
+        let mut words = words(input).peekable();
+        while let Some((word, loc)) = words.next() {
+            // 2^63はi64に収まらないが、直後の `neg` と合わせてi64::MINにする
+            if word.parse() == Ok(1u64 << 63) && words.peek().is_some_and(|&(w, _)| w == "neg") {
+                let (_, neg) = words.next().unwrap();
+                self.stack.push((Value::Int(i64::MIN), loc.merge(&neg)));
+                continue;
+            }
+            self.eval_word(word, loc)?;
+        }
+
+        let v = self.stack.pop()
+            .ok_or_else(|| RpnError::new(RpnErrorKind::Eof, Loc(input.len(), input.len() + 1)))?;
+        // 最後の値以外が残っていたら、一番下に残った値を指す
+        match self.stack.first() {
+            Some((_, loc)) => Err(RpnError::new(RpnErrorKind::RedundantOperand, loc.clone())),
+            None => Ok(v),
         }
     }
+
+    fn eval_word(&mut self, word: &str, loc: Loc) -> Result<(), RpnError> {
+        let n = match word {
+            "neg" => self.pop(&loc)?
+                .checked_neg()
+                .ok_or_else(|| RpnError::new(RpnErrorKind::Overflow, loc.clone()))?,
+            "!" => factorial(self.pop(&loc)?).map_err(|e| {
+                let kind = match e {
+                    InterpreterErrorKind::NegativeFactorial => RpnErrorKind::NegativeFactorial,
+                    _ => RpnErrorKind::Overflow,
+                };
+                RpnError::new(kind, loc.clone())
+            })?,
+            "%" => self.pop(&loc)? / 100,
+            "not" => (self.pop(&loc)? == 0) as i64,
+            "~" => !self.pop(&loc)?,
+            "+" | "-" | "*" | "/" | "^" | "xor" | "+%" | "-%" | "*%" | "/%" => {
+                let r = self.pop(&loc)?;
+                let l = self.pop(&loc)?;
+                self.eval_binop(word, l, r)
+                    .map_err(|e| RpnError::new(e, loc.clone()))?
+            },
+            _ => match tuple_len(word) {
+                Some(n) => {
+                    if self.stack.len() < n {
+                        return Err(RpnError::new(RpnErrorKind::StackUnderflow, loc));
+                    }
+                    let vs = self.stack.split_off(self.stack.len() - n).into_iter().map(|(v, _)| v).collect();
+                    self.stack.push((Value::Tuple(vs), loc));
+                    return Ok(());
+                },
+                None => match parse_operand(word, loc.clone())?.value {
+                    // i64に収まらない数値はオーバーフローとする
+                    AstKind::Num(n) => i64::try_from(n).map_err(|_| RpnError::new(RpnErrorKind::Overflow, loc.clone()))?,
+                    AstKind::Var(name) => match self.vars.get(&name) {
+                        Some(&n) => n,
+                        None => return Err(RpnError::new(RpnErrorKind::UnboundVariable(name), loc)),
+                    },
+                    _ => unreachable!(),
+                },
+            },
+        };
+        self.stack.push((Value::Int(n), loc));
+        Ok(())
+    }
+
+    /// 演算子の被演算子を取り出す。被演算子は数値でなければならない
+    fn pop(&mut self, loc: &Loc) -> Result<i64, RpnError> {
+        match self.stack.pop() {
+            Some((Value::Int(n), _)) => Ok(n),
+            Some((Value::Tuple(_), _)) => Err(RpnError::new(RpnErrorKind::UnexpectedTuple, loc.clone())),
+            None => Err(RpnError::new(RpnErrorKind::StackUnderflow, loc.clone())),
+        }
+    }
+
+    fn eval_binop(&mut self, op: &str, l: i64, r: i64) -> Result<i64, RpnErrorKind> {
+        let n = match op {
+            "+" => l.checked_add(r),
+            "-" => l.checked_sub(r),
+            "*" => l.checked_mul(r),
+            "/" => {
+                if r == 0 {
+                    return Err(RpnErrorKind::DivisionByZero);
+                }
+                l.checked_div(r)
+            },
+            "xor" => Some(l ^ r),
+            "^" | "+%" | "-%" | "*%" | "/%" => {
+                let n = match op {
+                    "^" => pow(l, r),
+                    "+%" => percent_of(&BinOpKind::AddPercent, l, r),
+                    "-%" => percent_of(&BinOpKind::SubPercent, l, r),
+                    "*%" => percent_of(&BinOpKind::MultPercent, l, r),
+                    _ => percent_of(&BinOpKind::DivPercent, l, r),
+                };
+                return n.map_err(|e| match e {
+                    InterpreterErrorKind::DivisionByZero => RpnErrorKind::DivisionByZero,
+                    _ => RpnErrorKind::Overflow,
+                });
+            },
+            _ => unreachable!(),
+        };
+        n.ok_or(RpnErrorKind::Overflow)
+    }
+}
+
+/// 空白で区切られた語を位置情報と一緒に取り出す
+pub(crate) fn words(input: &str) -> impl Iterator<Item = (&str, Loc)> {
+    input.split_ascii_whitespace().map(move |word| {
+        // wordはinputの部分文字列なのでポインタの差から開始位置がわかる
+        let start = word.as_ptr() as usize - input.as_ptr() as usize;
+        (word, Loc(start, start + word.len()))
+    })
 }
 
 #[test]
 fn test_reverse_polish() {
-    use crate::utils::Loc;
-    use self::RpnCompiler;
-
     // 1 + 2 * 3 - -10
     let ast = Ast::binop(
         BinOp::sub(Loc(10, 11)),
@@ -84,5 +373,112 @@ fn test_reverse_polish() {
 
     let reverse_polish = compiler.compile(&ast);
 
-    assert_eq!(reverse_polish, "1 2 3 * + -10 -")
+    assert_eq!(reverse_polish, "1 2 3 * + 10 neg -")
+}
+
+#[test]
+fn test_rpn_machine() {
+    use crate::interpreter::Interpreter;
+    use crate::options::ParserOptions;
+
+    // RpnCompilerの出力はどれもインタプリタと同じ値かエラーになる
+    let mut compiler = RpnCompiler::new();
+    let mut machine = RpnMachine::new();
+    let mut interp = Interpreter::new();
+    for (name, value) in [("x", 7), ("y", -3)] {
+        machine.set_var(name, value);
+        interp.set_var(name, value);
+    }
+    for (opts, input) in [
+        (ParserOptions::default(), "1 + 2 * 3 - -10"),
+        (ParserOptions::default(), "+4 / -(2 - 4)"),
+        (ParserOptions::default(), "3! + 200 * 15% - x%"),
+        (ParserOptions::default(), "-2^3^2 - !~3"),
+        (ParserOptions::default(), "x + y%, x - y%, x * 50%, x / 50%"),
+        (ParserOptions::default(), "x, (y * 2, -x)"),
+        (ParserOptions::default(), "-9223372036854775808, 9223372036854775807"),
+        (ParserOptions::default(), "1 / (x - 7)"),
+        (ParserOptions::default(), "9223372036854775808"),
+        (ParserOptions::default(), "z + 1"),
+        (ParserOptions::default(), "-(1, 2)"),
+        (ParserOptions::c_like(), "x ^ y ^ 5"),
+    ] {
+        let ast = Ast::parse_with(&opts, input).unwrap();
+        let rpn = compiler.compile(&ast);
+        let expected = interp.eval_value(&ast).map_err(|e| e.value);
+        assert_eq!(machine.eval_value(&rpn).map_err(|e| rpn_kind(e.value)), expected, "{}", rpn);
+    }
+
+    assert_eq!(machine.eval("1 2"), Err(RpnError::new(RpnErrorKind::RedundantOperand, Loc(0, 1))));
+    assert_eq!(machine.eval("1 +"), Err(RpnError::new(RpnErrorKind::StackUnderflow, Loc(2, 3))));
+    assert_eq!(machine.eval("1 2 tuple:2"), Err(RpnError::new(RpnErrorKind::UnexpectedTuple, Loc(4, 11))));
+    assert_eq!(machine.eval("1 2 tuple:2 neg"), Err(RpnError::new(RpnErrorKind::UnexpectedTuple, Loc(12, 15))));
+    assert_eq!(machine.eval("9223372036854775808 neg neg"), Err(RpnError::new(RpnErrorKind::Overflow, Loc(24, 27))));
+    assert_eq!(compiler.compile(&"(-3)!".parse().unwrap()), "3 neg !");
+    assert_eq!(compiler.compile(&"15%".parse().unwrap()), "15 %");
+    assert_eq!(machine.eval("3 neg !"), Err(RpnError::new(RpnErrorKind::NegativeFactorial, Loc(6, 7))));
+}
+
+/// マシンのエラーを、比べやすいようにインタプリタのエラーにする
+#[cfg(test)]
+fn rpn_kind(kind: RpnErrorKind) -> InterpreterErrorKind {
+    match kind {
+        RpnErrorKind::DivisionByZero => InterpreterErrorKind::DivisionByZero,
+        RpnErrorKind::Overflow => InterpreterErrorKind::Overflow,
+        RpnErrorKind::NegativeFactorial => InterpreterErrorKind::NegativeFactorial,
+        RpnErrorKind::UnboundVariable(name) => InterpreterErrorKind::UnboundVariable(name),
+        RpnErrorKind::UnexpectedTuple => InterpreterErrorKind::UnexpectedTuple,
+        kind => panic!("unexpected error {:?}", kind),
+    }
+}
+
+#[test]
+fn test_parse_rpn() {
+    assert_eq!(
+        parse_rpn("1 2 + 10 neg *"),
+        Ok(Ast::binop(
+            BinOp::mult(Loc(13, 14)),
+            Ast::binop(
+                BinOp::add(Loc(4, 5)),
+                Ast::num(1, Loc(0, 1)),
+                Ast::num(2, Loc(2, 3)),
+                Loc(0, 5)
+            ),
+            Ast::uniop(
+                UniOp::minus(Loc(9, 12)),
+                Ast::num(10, Loc(6, 8)),
+                Loc(6, 12)
+            ),
+            Loc(0, 14)
+        ))
+    );
+    let ast = "1+2, 3*4".parse::<Ast>().unwrap();
+    let rpn = RpnCompiler::new().compile(&ast);
+    assert_eq!(rpn, "1 2 + 3 4 * tuple:2");
+    assert_eq!(RpnCompiler::new().compile(&parse_rpn(&rpn).unwrap()), rpn);
+    assert_eq!(parse_rpn("1 tuple:2"), Err(RpnError::new(RpnErrorKind::StackUnderflow, Loc(2, 9))));
+    assert_eq!(parse_rpn("1 2 3 +"), Err(RpnError::new(RpnErrorKind::RedundantOperand, Loc(0, 1))));
+    assert_eq!(parse_rpn("1 * 2"), Err(RpnError::new(RpnErrorKind::StackUnderflow, Loc(2, 3))));
+    assert_eq!(parse_rpn("1 $"), Err(RpnError::new(RpnErrorKind::InvalidWord("$".to_string()), Loc(2, 3))));
+    assert_eq!(parse_rpn("x"), Ok(Ast::var("x", Loc(0, 1))));
+}
+
+#[test]
+fn test_parse_rpn_limits() {
+    // スタックの高さを入れ子の深さとして制限する。上限を超えた語を指す
+    let limits = EvalLimits { max_depth: 2, ..EvalLimits::default() };
+    assert!(parse_rpn_with_limits("1 2 + 3 + 4 +", &limits).is_ok());
+    assert_eq!(parse_rpn_with_limits("1 2 3 + +", &limits), Err(RpnError::new(RpnErrorKind::TooDeep, Loc(4, 5))));
+    let input = format!("{}{}", "1 ".repeat(100_000), "+ ".repeat(99_999));
+    assert!(matches!(parse_rpn(&input), Err(RpnError { value: RpnErrorKind::TooDeep, .. })));
+
+    // 語は1つずつ節点になる
+    let limits = EvalLimits { max_nodes: 3, ..EvalLimits::default() };
+    assert!(parse_rpn_with_limits("1 2 +", &limits).is_ok());
+    assert_eq!(parse_rpn_with_limits("1 2 + neg", &limits), Err(RpnError::new(RpnErrorKind::TooManyNodes, Loc(6, 9))));
+
+    // 左結合の連なりは深さに関わらず読める
+    let input = format!("1{}", " 1 +".repeat(200_000));
+    let ast = parse_rpn(&input).unwrap();
+    assert_eq!(RpnCompiler::new().compile(&ast), input);
 }
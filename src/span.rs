@@ -0,0 +1,175 @@
+use std::{error::Error as StdError, fmt};
+
+use crate::utils::Loc;
+use crate::lexer::{self, Lexer, TokenKind};
+use crate::parser::{Ast, parse_stream};
+use crate::error::Error;
+
+/// 入力文字列の区間
+/// 元の文字列を借用しているので、区間の文字列をいつでも切り出せる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span<'src> {
+    src: &'src str,
+    start: usize,
+    end: usize,
+}
+
+impl<'src> Span<'src> {
+    pub fn new(src: &'src str, loc: Loc) -> Self {
+        Span { src, start: loc.0, end: loc.1 }
+    }
+
+    /// 区間の位置情報
+    pub fn loc(&self) -> Loc {
+        Loc(self.start, self.end)
+    }
+
+    /// 区間の文字列。入力の終わりを越える部分は含めず、文字の途中を指す端は文字の先頭まで戻す
+    pub fn as_str(&self) -> &'src str {
+        let start = floor_char_boundary(self.src, self.start);
+        let end = floor_char_boundary(self.src, self.end);
+        &self.src[start..end.max(start)]
+    }
+
+    /// 元の入力文字列全体
+    pub fn source(&self) -> &'src str {
+        self.src
+    }
+
+    /// 区間の開始位置の行番号と桁番号（どちらも1から数える）
+    pub fn line_col(&self) -> (usize, usize) {
+        let before = &self.src[..floor_char_boundary(self.src, self.start)];
+        let line = before.matches('\n').count() + 1;
+        let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        (line, col)
+    }
+}
+
+impl fmt::Display for Span<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// iがsの文字の途中を指していれば、その文字の先頭まで戻す。sの長さを越える位置は長さにする
+pub(crate) fn floor_char_boundary(s: &str, i: usize) -> usize {
+    let mut i = i.min(s.len());
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// 入力を借用するトークン。識別子は入力の一部をそのまま指し、
+/// 数値のリテラルなども `span.as_str()` で元の文字列が得られる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Token<'src> {
+    pub kind: TokenKind<&'src str>,
+    pub span: Span<'src>,
+}
+
+impl Token<'_> {
+    /// 入力を借用しない `lexer::Token` にする
+    pub fn into_owned(self) -> lexer::Token {
+        lexer::Token::new(self.kind.map_ident(str::to_string), self.span.loc())
+    }
+}
+
+/// 入力の区間を伴うエラー
+/// 入力を借用しているので、入力を渡し直さなくても表示できる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic<'src, E = Error> {
+    pub error: E,
+    pub span: Span<'src>,
+}
+
+impl<E: StdError> Diagnostic<'_, E> {
+    /// 診断メッセージを表示する
+    pub fn show(&self) {
+        eprint!("{}", self);
+    }
+}
+
+/// エラー情報、エラーのある行、その下にエラー位置を指す `^` の3行にする
+impl<E: StdError> fmt::Display for Diagnostic<'_, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let src = self.span.source();
+        let start = floor_char_boundary(src, self.span.start);
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        // 行をまたぐ区間は行末までを指す。入力の終わりを指す区間は1文字ぶん表示する
+        let end = if self.span.end > src.len() { self.span.end } else { self.span.end.min(line_end) };
+
+        match self.error.source() {
+            Some(e) => writeln!(f, "{}", e)?,
+            None => writeln!(f, "{}", self.error)?,
+        }
+        // 字下げと^の数はバイト数ではなく文字数で数える。入力の終わりを越える部分は1バイト1文字とする
+        let indent = src[line_start..start].chars().count();
+        let width = src[start..floor_char_boundary(src, end).max(start)].chars().count() + end.saturating_sub(src.len());
+        writeln!(f, "{}", &src[line_start..line_end])?;
+        writeln!(f, "{}{}", " ".repeat(indent), "^".repeat(width.max(1)))
+    }
+}
+
+impl<E: StdError + 'static> StdError for Diagnostic<'_, E> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// 入力を借用するトークンを1つずつ返すイテレータ
+pub struct Tokens<'src>(Lexer<'src>);
+
+impl<'src> Iterator for Tokens<'src> {
+    type Item = Result<Token<'src>, Diagnostic<'src>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let src = self.0.source();
+        self.0.next().map(|r| r.map_err(|e| Error::from(e).diagnostic(src)))
+    }
+}
+
+/// 入力を借用するトークンを返す字句解析器。コメントと空白は読み飛ばす
+pub fn lex(src: &str) -> Tokens<'_> {
+    Tokens(Lexer::new(src))
+}
+
+/// コメントと空白もトークンとして返す、入力を借用する字句解析器
+pub fn lex_lossless(src: &str) -> Tokens<'_> {
+    Tokens(Lexer::lossless(src))
+}
+
+/// 構文解析する。エラーは入力を借用した診断メッセージとして返す
+pub fn parse(src: &str) -> Result<Ast, Diagnostic<'_>> {
+    parse_stream(Lexer::new(src)).map_err(|e| e.diagnostic(src))
+}
+
+#[test]
+fn test_span() {
+    let src = "1 + x_1\n  * 007";
+    let tokens: Vec<Token> = lex(src).collect::<Result<_, _>>().unwrap();
+    assert_eq!(tokens[2].kind, TokenKind::Ident("x_1"));
+    assert_eq!(tokens[4].span.as_str(), "007");
+    assert_eq!(tokens[4].span.line_col(), (2, 5));
+    // 識別子は入力をそのまま指している
+    match tokens[2].kind {
+        TokenKind::Ident(name) => assert_eq!(name.as_ptr(), src[4..].as_ptr()),
+        _ => unreachable!(),
+    }
+    assert_eq!(tokens[2].into_owned(), lexer::Token::ident("x_1", Loc(4, 7)));
+
+    let e = lex("1 $").find_map(Result::err).unwrap();
+    assert_eq!(e.span.loc(), Loc(2, 3));
+    assert_eq!(e.to_string(), "2-3: invalid char '$'\n1 $\n  ^\n");
+
+    // ASCII以外の文字のエラーも文字の途中で切らない
+    let e = lex("1 + é").find_map(Result::err).unwrap();
+    assert_eq!(e.span.as_str(), "é");
+    assert_eq!(Span::new("é", Loc(1, 2)).as_str(), "é");
+    assert_eq!(e.to_string(), "4-6: invalid char 'é'\n1 + é\n    ^\n");
+
+    let e = parse("1 +\n2 )").unwrap_err();
+    // 2行目だけを表示する
+    assert_eq!(e.to_string(), "6-7: expression after ')' is redundant\n2 )\n  ^\n");
+}
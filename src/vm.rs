@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use crate::utils::Loc;
+use crate::bytecode::{Chunk, Instr};
+use crate::error::{InterpreterError, InterpreterErrorKind};
+use crate::interpreter::{factorial, percent_of, pow};
+use crate::parser::BinOpKind;
+
+/// バイトコードを実行するスタックマシンを表すデータ型
+/// 再帰を使わないので深くネストした式でもネイティブスタックを消費しない
+#[derive(Default)]
+pub struct Vm {
+    stack: Vec<i64>,
+    // 変数の値
+    vars: HashMap<String, i64>,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 変数に値を設定する
+    pub fn set_var(&mut self, name: &str, value: i64) {
+        self.vars.insert(name.to_string(), value);
+    }
+
+    /// バイトコードを検査してから実行する
+    /// 検査に通らないバイトコードは `InvalidBytecode` になり、問題のある命令の位置を指す
+    pub fn run(&mut self, chunk: &Chunk) -> Result<i64, InterpreterError> {
+        chunk.verify().map_err(|e| {
+            let loc = chunk.spans.get(e.loc.0).cloned().unwrap_or(Loc(0, 0));
+            InterpreterError::new(InterpreterErrorKind::InvalidBytecode(e.value), loc)
+        })?;
+        self.stack.clear();
+
+        for (pc, instr) in chunk.code.iter().enumerate() {
+            let n = match *instr {
+                Instr::Const(idx) => chunk.consts[idx as usize],
+                Instr::Load(idx) => {
+                    let name = &chunk.names[idx as usize];
+                    self.vars.get(name).copied().ok_or_else(|| {
+                        let kind = InterpreterErrorKind::UnboundVariable(name.clone());
+                        InterpreterError::new(kind, chunk.spans[pc].clone())
+                    })?
+                },
+                Instr::Neg => self.pop().checked_neg().ok_or_else(|| {
+                    InterpreterError::new(InterpreterErrorKind::Overflow, chunk.spans[pc].clone())
+                })?,
+                Instr::Fact => factorial(self.pop())
+                    .map_err(|e| InterpreterError::new(e, chunk.spans[pc].clone()))?,
+                Instr::Percent => self.pop() / 100,
+                Instr::Not => (self.pop() == 0) as i64,
+                Instr::BitNot => !self.pop(),
+                _ => {
+                    let r = self.pop();
+                    let l = self.pop();
+                    // 実行時エラーはスパンテーブルを引いてソース上の位置に対応付ける
+                    self.eval_binop(instr, l, r)
+                        .map_err(|e| InterpreterError::new(e, chunk.spans[pc].clone()))?
+                },
+            };
+            self.stack.push(n);
+        }
+        Ok(self.pop())
+    }
+
+    fn pop(&mut self) -> i64 {
+        // 検査済みのバイトコードでは常に値が積まれている
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn eval_binop(&mut self, instr: &Instr, l: i64, r: i64) -> Result<i64, InterpreterErrorKind> {
+        use self::Instr::*;
+
+        // Interpreterと同じく結果がi64に収まらなければオーバーフローとする
+        let n = match instr {
+            Add => l.checked_add(r),
+            Sub => l.checked_sub(r),
+            Mul => l.checked_mul(r),
+            Div => {
+                if r == 0 {
+                    return Err(InterpreterErrorKind::DivisionByZero);
+                }
+                l.checked_div(r)
+            },
+            Pow => return pow(l, r),
+            Xor => Some(l ^ r),
+            AddPct => return percent_of(&BinOpKind::AddPercent, l, r),
+            SubPct => return percent_of(&BinOpKind::SubPercent, l, r),
+            MulPct => return percent_of(&BinOpKind::MultPercent, l, r),
+            DivPct => return percent_of(&BinOpKind::DivPercent, l, r),
+            _ => unreachable!(),
+        };
+        n.ok_or(InterpreterErrorKind::Overflow)
+    }
+}
+
+#[test]
+fn test_vm() {
+    use crate::parser::Ast;
+    use crate::bytecode::BytecodeCompiler;
+    use crate::interpreter::Interpreter;
+
+    let mut compiler = BytecodeCompiler::new();
+    let mut vm = Vm::new();
+    let mut interp = Interpreter::new();
+    vm.set_var("x", 5);
+    interp.set_var("x", 5);
+    for input in ["1 + 2 * 3 - -10", "-(1 + 2)", "+4 / -(2 - 4)", "(10 - 3) - 2 * x", "-x^3 + !x - ~x"] {
+        let ast = input.parse::<Ast>().unwrap();
+        assert_eq!(vm.run(&compiler.compile(&ast).unwrap()), interp.eval(&ast));
+    }
+
+    let ast = "1 + 2 / (3 - 3)".parse::<Ast>().unwrap();
+    assert_eq!(
+        vm.run(&compiler.compile(&ast).unwrap()),
+        Err(InterpreterError::new(InterpreterErrorKind::DivisionByZero, Loc(4, 14)))
+    );
+
+    // 手で組み立てた壊れたバイトコードはパニックせずにエラーになる
+    use crate::error::FormatErrorKind;
+    let mut chunk = compiler.compile(&"1 - x".parse::<Ast>().unwrap()).unwrap();
+    chunk.code[1] = Instr::Load(7);
+    assert_eq!(
+        vm.run(&chunk),
+        Err(InterpreterError::new(InterpreterErrorKind::InvalidBytecode(FormatErrorKind::NameOutOfRange(7)), Loc(4, 5)))
+    );
+    let chunk = Chunk { code: vec![Instr::Add], spans: vec![Loc(0, 1)], ..Chunk::default() };
+    assert_eq!(
+        vm.run(&chunk),
+        Err(InterpreterError::new(InterpreterErrorKind::InvalidBytecode(FormatErrorKind::StackUnderflow), Loc(0, 1)))
+    );
+    assert!(vm.run(&Chunk { code: vec![Instr::Add], ..Chunk::default() }).is_err());
+}
+
+#[test]
+fn test_vm_literals() {
+    use crate::parser::Ast;
+    use crate::bytecode::BytecodeCompiler;
+    use crate::interpreter::Interpreter;
+
+    let mut compiler = BytecodeCompiler::new();
+    for input in ["-9223372036854775808", "9223372036854775808", "--9223372036854775808", "18446744073709551615 * 0"] {
+        let ast = input.parse::<Ast>().unwrap();
+        assert_eq!(Vm::new().run(&compiler.compile(&ast).unwrap()), Interpreter::new().eval(&ast), "{}", input);
+    }
+    let chunk = compiler.compile(&"-9223372036854775808".parse::<Ast>().unwrap()).unwrap();
+    assert_eq!((chunk.consts, chunk.code), (vec![i64::MIN], vec![Instr::Const(0)]));
+}
+
+#[test]
+fn test_vm_deep() {
+    use crate::parser::{Ast, UniOp, UniOpKind};
+    use crate::bytecode::BytecodeCompiler;
+
+    // ~~...~1 のように深くネストした式もスタックを溢れさせずにコンパイルして実行できる
+    let mut ast = Ast::num(1, Loc(0, 1));
+    for _ in 0..100_000 {
+        ast = Ast::uniop(UniOp::new(UniOpKind::BitNot, Loc(0, 0)), ast, Loc(0, 0));
+    }
+    let chunk = BytecodeCompiler::new().compile(&ast).unwrap();
+    assert_eq!(chunk.code.len(), 100_001);
+    assert_eq!(Vm::new().run(&chunk), Ok(1));
+}
@@ -0,0 +1,279 @@
+use crate::parser::{Ast, AstKind, UniOpKind, BinOpKind, Visit};
+use crate::error::CompileError;
+
+/// WebAssemblyのテキスト形式（WAT）へのコンパイラを表すデータ型
+///
+/// 式を `eval` 関数を公開するモジュールにする。`eval` の引数は式に現れる変数を
+/// 名前順に並べたi64で、結果もi64で返す。
+/// ゼロ除算とオーバーフローはトラップせず、公開したグローバル変数 `error` に
+/// Interpreterで最初に起きるエラーのコード（1: ゼロ除算, 2: オーバーフロー, 3: 負の数の階乗）を残す。
+/// エラーがなければ `error` は0になる。組を含む式は `CompileErrorKind::UnsupportedTuple` のエラーになる
+#[derive(Default)]
+pub struct WatCompiler;
+
+impl WatCompiler {
+    pub fn new() -> Self {
+        WatCompiler
+    }
+
+    pub fn compile(&mut self, expr: &Ast) -> Result<String, CompileError> {
+        if let Some(t) = expr.find_tuple() {
+            return Err(CompileError::unsupported_tuple("WebAssembly", t.loc.clone()));
+        }
+        let mut buf = String::new();
+        buf.push_str(PRELUDE);
+        buf.push_str("  (func (export \"eval\")");
+        for var in &expr.vars() {
+            buf.push_str(&format!(" (param ${} i64)", var));
+        }
+        buf.push_str(" (result i64)\n");
+        buf.push_str("    (global.set $error (i32.const 0))\n");
+        self.compile_inner(expr, 2, &mut buf);
+        buf.push_str(")\n)\n");
+        Ok(buf)
+    }
+
+    /// 式をi64の値を積む畳み込み形式の命令として出力する。depthは一番外側の命令の字下げ
+    /// 深い式でもスタックが溢れないよう、再帰せずに木をたどって出力する
+    fn compile_inner(&mut self, expr: &Ast, depth: usize, buf: &mut String) {
+        use self::AstKind::*;
+
+        // 各節点の子の命令の字下げ。正号は命令を出力しないので子も同じ字下げになる
+        let mut depths = vec![depth];
+        let mut walk = expr.walk();
+        while let Some(visit) = walk.next() {
+            match visit {
+                Visit::Enter(e) => {
+                    let depth = *depths.last().unwrap();
+                    let indent = "  ".repeat(depth);
+                    let plus = matches!(e.value, UniOp { ref op, .. } if op.value == UniOpKind::Plus);
+                    depths.push(if plus { depth } else { depth + 1 });
+                    // 負の数のリテラルは検査せずにそのまま書ける
+                    if let (UniOp { .. }, Some(n)) = (&e.value, e.literal_value()) {
+                        buf.push_str(&format!("{}(i64.const {})", indent, n));
+                        walk.skip_children();
+                        continue;
+                    }
+                    match e.value {
+                        Num(n) => match i64::try_from(n) {
+                            Ok(n) => buf.push_str(&format!("{}(i64.const {})", indent, n)),
+                            // i64に収まらない数値は、評価した時点でオーバーフローとする
+                            Err(_) => buf.push_str(&format!("{}(call $overflow)", indent)),
+                        },
+                        Var(ref name) => buf.push_str(&format!("{}(local.get ${})", indent, name)),
+                        UniOp { ref op, .. } => match op.value {
+                            UniOpKind::Plus => (),
+                            UniOpKind::Minus => buf.push_str(&format!("{}(call $neg\n", indent)),
+                            UniOpKind::Factorial => buf.push_str(&format!("{}(call $fact\n", indent)),
+                            UniOpKind::Not => buf.push_str(&format!("{}(i64.extend_i32_u (i64.eqz\n", indent)),
+                            UniOpKind::BitNot => buf.push_str(&format!("{}(i64.xor\n", indent)),
+                            UniOpKind::Percent => buf.push_str(&format!("{}(i64.div_s\n", indent)),
+                        },
+                        BinOp { ref op, .. } => {
+                            // 排他的論理和は失敗しないので、検査なしの命令をそのまま使う
+                            let call = match op.value {
+                                BinOpKind::Add => "call $add",
+                                BinOpKind::Sub => "call $sub",
+                                BinOpKind::Mult => "call $mul",
+                                BinOpKind::Div => "call $div",
+                                BinOpKind::Pow => "call $pow",
+                                BinOpKind::BitXor => "i64.xor",
+                                BinOpKind::AddPercent => "call $add_pct",
+                                BinOpKind::SubPercent => "call $sub_pct",
+                                BinOpKind::MultPercent => "call $mul_pct",
+                                BinOpKind::DivPercent => "call $div_pct",
+                            };
+                            buf.push_str(&format!("{}({}\n", indent, call));
+                        },
+                        // compileで先に調べている
+                        Tuple(_) => unreachable!(),
+                    }
+                },
+                Visit::Between(..) => buf.push('\n'),
+                Visit::Exit(e) => {
+                    depths.pop();
+                    let indent = "  ".repeat(*depths.last().unwrap());
+                    match e.value {
+                        UniOp { .. } if e.literal_value().is_some() => (),
+                        UniOp { ref op, .. } => match op.value {
+                            UniOpKind::Plus => (),
+                            UniOpKind::Minus | UniOpKind::Factorial => buf.push(')'),
+                            UniOpKind::Not => buf.push_str("))"),
+                            UniOpKind::BitNot => buf.push_str(&format!("\n{}  (i64.const -1))", indent)),
+                            UniOpKind::Percent => buf.push_str(&format!("\n{}  (i64.const 100))", indent)),
+                        },
+                        BinOp { .. } => buf.push(')'),
+                        Num(_) | Var(_) | Tuple(_) => (),
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// 検査付きの演算を定義するモジュールの前半部分
+const PRELUDE: &str = "\
+(module
+  (global $error (export \"error\") (mut i32) (i32.const 0))
+
+  ;; keep only the first error that occurred
+  (func $fail (param $code i32)
+    (if (i32.eqz (global.get $error))
+      (then (global.set $error (local.get $code)))))
+
+  (func $add (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (local.set $n (i64.add (local.get $l) (local.get $r)))
+    ;; overflow if the sign of the result differs from both operands
+    (if (i64.lt_s (i64.and (i64.xor (local.get $l) (local.get $n))
+                           (i64.xor (local.get $r) (local.get $n)))
+                  (i64.const 0))
+      (then (call $fail (i32.const 2))))
+    (local.get $n))
+
+  (func $sub (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (local.set $n (i64.sub (local.get $l) (local.get $r)))
+    ;; overflow if the operand signs differ and the result sign differs from the left
+    (if (i64.lt_s (i64.and (i64.xor (local.get $l) (local.get $r))
+                           (i64.xor (local.get $l) (local.get $n)))
+                  (i64.const 0))
+      (then (call $fail (i32.const 2))))
+    (local.get $n))
+
+  (func $mul (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (local.set $n (i64.mul (local.get $l) (local.get $r)))
+    ;; overflow if dividing back does not give the operand; check -1 * MIN first since its division traps
+    (if (i64.eq (local.get $l) (i64.const -1))
+      (then
+        (if (i64.eq (local.get $r) (i64.const 0x8000000000000000))
+          (then (call $fail (i32.const 2)))))
+      (else
+        (if (i64.ne (local.get $l) (i64.const 0))
+          (then
+            (if (i64.ne (i64.div_s (local.get $n) (local.get $l)) (local.get $r))
+              (then (call $fail (i32.const 2))))))))
+    (local.get $n))
+
+  (func $div (param $l i64) (param $r i64) (result i64)
+    (if (i64.eqz (local.get $r))
+      (then
+        (call $fail (i32.const 1))
+        (return (i64.const 0))))
+    (if (i32.and (i64.eq (local.get $l) (i64.const 0x8000000000000000))
+                 (i64.eq (local.get $r) (i64.const -1)))
+      (then
+        (call $fail (i32.const 2))
+        (return (i64.const 0))))
+    (i64.div_s (local.get $l) (local.get $r)))
+
+  ;; arithmetic with a percent right operand, checked in the same order as a +- (a * b)%, (a * b)% and a * 100 / b
+  (func $mul_pct (param $l i64) (param $r i64) (result i64)
+    (i64.div_s (call $mul (local.get $l) (local.get $r)) (i64.const 100)))
+
+  (func $add_pct (param $l i64) (param $r i64) (result i64)
+    (call $add (local.get $l) (call $mul_pct (local.get $l) (local.get $r))))
+
+  (func $sub_pct (param $l i64) (param $r i64) (result i64)
+    (call $sub (local.get $l) (call $mul_pct (local.get $l) (local.get $r))))
+
+  (func $div_pct (param $l i64) (param $r i64) (result i64)
+    (call $div (call $mul (local.get $l) (i64.const 100)) (local.get $r)))
+
+  ;; a literal that does not fit in i64
+  (func $overflow (result i64)
+    (call $fail (i32.const 2))
+    (i64.const 0))
+
+  (func $neg (param $n i64) (result i64)
+    (if (i64.eq (local.get $n) (i64.const 0x8000000000000000))
+      (then
+        (call $fail (i32.const 2))
+        (return (i64.const 0))))
+    (i64.sub (i64.const 0) (local.get $n)))
+
+  (func $fact (param $n i64) (result i64)
+    (local $i i64)
+    (local $r i64)
+    (if (i64.lt_s (local.get $n) (i64.const 0))
+      (then
+        (call $fail (i32.const 3))
+        (return (i64.const 0))))
+    (local.set $i (i64.const 2))
+    (local.set $r (i64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i64.gt_s (local.get $i) (local.get $n)))
+        ;; check that r * i does not exceed the maximum before multiplying
+        (if (i64.gt_s (local.get $r) (i64.div_s (i64.const 0x7fffffffffffffff) (local.get $i)))
+          (then
+            (call $fail (i32.const 2))
+            (return (i64.const 0))))
+        (local.set $r (i64.mul (local.get $r) (local.get $i)))
+        (local.set $i (i64.add (local.get $i) (i64.const 1)))
+        (br $next)))
+    (local.get $r))
+
+  (func $pow (param $l i64) (param $r i64) (result i64)
+    (local $n i64)
+    (if (i64.eqz (local.get $l))
+      (then
+        (if (i64.lt_s (local.get $r) (i64.const 0))
+          (then
+            (call $fail (i32.const 1))
+            (return (i64.const 0))))
+        (return (i64.extend_i32_u (i64.eqz (local.get $r))))))
+    (if (i64.eq (local.get $l) (i64.const 1))
+      (then (return (i64.const 1))))
+    (if (i64.eq (local.get $l) (i64.const -1))
+      (then
+        (return (select (i64.const -1) (i64.const 1)
+                        (i32.wrap_i64 (i64.and (local.get $r) (i64.const 1)))))))
+    ;; a negative exponent gives 1 / l^(-r), which truncates to 0
+    (if (i64.lt_s (local.get $r) (i64.const 0))
+      (then (return (i64.const 0))))
+    (local.set $n (i64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i64.eqz (local.get $r)))
+        ;; overflow if dividing back does not give the operand; |l| >= 2 so the division cannot trap
+        (if (i64.ne (i64.div_s (i64.mul (local.get $n) (local.get $l)) (local.get $l)) (local.get $n))
+          (then
+            (call $fail (i32.const 2))
+            (return (i64.const 0))))
+        (local.set $n (i64.mul (local.get $n) (local.get $l)))
+        (local.set $r (i64.sub (local.get $r) (i64.const 1)))
+        (br $next)))
+    (local.get $n))
+
+";
+
+#[test]
+fn test_wat() {
+    let mut compiler = WatCompiler::new();
+    for (input, golden) in [
+        ("1 + 2 * 3 - -10", include_str!("../golden/wat/constant.wat")),
+        ("(x + 1) * y / (x - y)", include_str!("../golden/wat/vars.wat")),
+        ("x! + y%", include_str!("../golden/wat/postfix.wat")),
+        ("-x ^ y + !~x", include_str!("../golden/wat/unary.wat")),
+    ] {
+        let ast = input.parse::<Ast>().unwrap();
+        assert_eq!(compiler.compile(&ast).unwrap(), golden, "{}", input);
+    }
+}
+
+#[test]
+fn test_wat_literals() {
+    let eval = |input: &str| {
+        let wat = WatCompiler::new().compile(&input.parse::<Ast>().unwrap()).unwrap();
+        wat[wat.find("(func (export \"eval\")").unwrap()..].to_string()
+    };
+    let head = "(func (export \"eval\") (result i64)\n    (global.set $error (i32.const 0))\n";
+    assert_eq!(eval("-9223372036854775808"), format!("{}    (i64.const -9223372036854775808))\n)\n", head));
+    // i64に収まらない数値は、評価した時点でオーバーフローを記録する
+    assert_eq!(
+        eval("18446744073709551615 * 0"),
+        format!("{}    (call $mul\n      (call $overflow)\n      (i64.const 0)))\n)\n", head)
+    );
+}
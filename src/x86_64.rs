@@ -0,0 +1,323 @@
+use std::collections::BTreeSet;
+
+use crate::parser::{Ast, AstKind, UniOpKind, BinOpKind, Visit};
+use crate::error::CompileError;
+
+/// x86-64のアセンブリ（GNU as, AT&T記法）へのコンパイラを表すデータ型
+///
+/// 式を計算して結果を表示する `main` を出力する。式に変数があれば、
+/// 名前順にコマンドライン引数から読み込む。
+/// 値は常に%raxに置き、二項演算の左辺はスタックに退避するスタック型の割り当てを行う。
+/// ゼロ除算とオーバーフローはInterpreterと同じ順序で検査し、
+/// メッセージを標準エラー出力に出して終了コード1で終わる。
+/// 組を含む式は `CompileErrorKind::UnsupportedTuple` のエラーになる
+#[derive(Default)]
+pub struct X86_64Compiler {
+    // 変数の名前とスタック上の位置（%rbpからのオフセット）
+    slots: Vec<(String, usize)>,
+    // ラベルを一意にするための番号
+    labels: usize,
+    // べき乗のサブルーチンを使うか
+    uses_pow: bool,
+}
+
+impl X86_64Compiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn compile(&mut self, expr: &Ast) -> Result<String, CompileError> {
+        if let Some(t) = expr.find_tuple() {
+            return Err(CompileError::unsupported_tuple("x86-64", t.loc.clone()));
+        }
+        let vars: BTreeSet<String> = expr.vars();
+        // -8(%rbp)にargvを置き、その下に変数を並べる
+        self.slots = vars.iter().enumerate().map(|(i, v)| (v.clone(), 16 + 8 * i)).collect();
+        self.labels = 0;
+        self.uses_pow = false;
+        // 関数呼び出しのために%rspを16バイト境界に揃える
+        let frame = (8 * (vars.len() + 1)).div_ceil(16) * 16;
+
+        let mut buf = String::new();
+        buf.push_str("\t.text\n\t.globl\tmain\nmain:\n");
+        buf.push_str("\tpushq\t%rbp\n\tmovq\t%rsp, %rbp\n");
+        buf.push_str(&format!("\tsubq\t${}, %rsp\n", frame));
+        buf.push_str("\tmovq\t%rsi, -8(%rbp)\n");
+        buf.push_str(&format!("\tcmpl\t${}, %edi\n\tjne\t.Lusage\n", vars.len() + 1));
+        for (i, (name, offset)) in self.slots.iter().enumerate() {
+            buf.push_str(&format!("\t# {} = strtoll(argv[{}], NULL, 10)\n", name, i + 1));
+            buf.push_str("\tmovq\t-8(%rbp), %rax\n");
+            buf.push_str(&format!("\tmovq\t{}(%rax), %rdi\n", 8 * (i + 1)));
+            buf.push_str("\txorl\t%esi, %esi\n\tmovl\t$10, %edx\n\tcall\tstrtoll@PLT\n");
+            buf.push_str(&format!("\tmovq\t%rax, -{}(%rbp)\n", offset));
+        }
+
+        self.compile_inner(expr, &mut buf);
+
+        buf.push_str("\tmovq\t%rax, %rsi\n\tleaq\t.Lfmt(%rip), %rdi\n\txorl\t%eax, %eax\n\tcall\tprintf@PLT\n");
+        buf.push_str("\txorl\t%eax, %eax\n\tleave\n\tret\n");
+        buf.push_str(".Ldivzero:\n\tleaq\t.Ldivzero_msg(%rip), %rdi\n\tjmp\t.Lfail\n");
+        buf.push_str(".Loverflow:\n\tleaq\t.Loverflow_msg(%rip), %rdi\n\tjmp\t.Lfail\n");
+        buf.push_str(".Lnegfact:\n\tleaq\t.Lnegfact_msg(%rip), %rdi\n\tjmp\t.Lfail\n");
+        buf.push_str(".Lusage:\n\tleaq\t.Lusage_msg(%rip), %rdi\n");
+        // 途中で退避した値を捨ててからメッセージを表示する
+        buf.push_str(".Lfail:\n");
+        buf.push_str(&format!("\tleaq\t-{}(%rbp), %rsp\n", frame));
+        buf.push_str("\tmovq\tstderr@GOTPCREL(%rip), %rax\n\tmovq\t(%rax), %rsi\n\tcall\tfputs@PLT\n");
+        buf.push_str("\tmovl\t$1, %eax\n\tleave\n\tret\n");
+        if self.uses_pow {
+            buf.push_str(POW);
+        }
+
+        let usage: Vec<&str> = vars.iter().map(String::as_str).collect();
+        buf.push_str("\t.section\t.rodata\n");
+        buf.push_str(".Lfmt:\n\t.string\t\"%ld\\n\"\n");
+        buf.push_str(".Ldivzero_msg:\n\t.string\t\"division by zero\\n\"\n");
+        buf.push_str(".Loverflow_msg:\n\t.string\t\"integer overflow\\n\"\n");
+        buf.push_str(".Lnegfact_msg:\n\t.string\t\"factorial of a negative number\\n\"\n");
+        buf.push_str(&format!(".Lusage_msg:\n\t.string\t\"usage: PROGRAM {}\\n\"\n", usage.join(" ")));
+        buf.push_str("\t.section\t.note.GNU-stack,\"\",@progbits\n");
+        Ok(buf)
+    }
+
+    /// 式の値を%raxに求める命令を出力する
+    /// 深い式でもスタックが溢れないよう、再帰せずに木をたどって評価する順に出力する
+    fn compile_inner(&mut self, expr: &Ast, buf: &mut String) {
+        use self::AstKind::*;
+
+        let mut walk = expr.walk();
+        while let Some(visit) = walk.next() {
+            match visit {
+                // -2^63は負号と合わせてi64::MINを読み込む
+                Visit::Enter(e) if e.is_min_literal() => {
+                    buf.push_str("\tmovabsq\t$-9223372036854775808, %rax\n");
+                    walk.skip_children();
+                },
+                Visit::Enter(e) => match e.value {
+                    Num(n) => match i64::try_from(n) {
+                        // 32ビットに収まらない即値はmovabsqでしか読み込めない
+                        Ok(n) if i32::try_from(n).is_ok() => buf.push_str(&format!("\tmovq\t${}, %rax\n", n)),
+                        Ok(n) => buf.push_str(&format!("\tmovabsq\t${}, %rax\n", n)),
+                        // i64に収まらない数値は、評価した時点でオーバーフローとする
+                        Err(_) => buf.push_str("\tjmp\t.Loverflow\n"),
+                    },
+                    Var(ref name) => {
+                        let offset = self.slots.iter().find(|(v, _)| v == name).map(|(_, o)| *o).unwrap();
+                        buf.push_str(&format!("\tmovq\t-{}(%rbp), %rax\n", offset));
+                    },
+                    UniOp { .. } | BinOp { .. } => (),
+                    // compileで先に調べている
+                    Tuple(_) => unreachable!(),
+                },
+                // 左辺を退避して右辺を計算する
+                Visit::Between(..) => buf.push_str("\tpushq\t%rax\n"),
+                Visit::Exit(e) if e.is_min_literal() => (),
+                Visit::Exit(e) => match e.value {
+                    UniOp { ref op, .. } => self.compile_uniop(&op.value, buf),
+                    BinOp { ref op, .. } => {
+                        // 左辺を%rax、右辺を%rcxに置く
+                        buf.push_str("\tmovq\t%rax, %rcx\n\tpopq\t%rax\n");
+                        self.compile_binop(&op.value, buf)
+                    },
+                    Num(_) | Var(_) | Tuple(_) => (),
+                },
+            }
+        }
+    }
+
+    fn compile_uniop(&mut self, op: &UniOpKind, buf: &mut String) {
+        match op {
+            UniOpKind::Plus => (),
+            UniOpKind::Minus => buf.push_str("\tnegq\t%rax\n\tjo\t.Loverflow\n"),
+            UniOpKind::Factorial => {
+                // %rcxをnから2まで減らしながら掛けていく
+                let (next, done) = (self.label(), self.label());
+                buf.push_str("\ttestq\t%rax, %rax\n\tjs\t.Lnegfact\n");
+                buf.push_str("\tmovq\t%rax, %rcx\n\tmovl\t$1, %eax\n");
+                buf.push_str(&format!("{}:\n\tcmpq\t$1, %rcx\n\tjle\t{}\n", next, done));
+                buf.push_str("\timulq\t%rcx, %rax\n\tjo\t.Loverflow\n");
+                buf.push_str(&format!("\tdecq\t%rcx\n\tjmp\t{}\n{}:\n", next, done));
+            },
+            UniOpKind::Percent => buf.push_str("\tmovl\t$100, %ecx\n\tcqto\n\tidivq\t%rcx\n"),
+            UniOpKind::Not => buf.push_str("\ttestq\t%rax, %rax\n\tsete\t%al\n\tmovzbl\t%al, %eax\n"),
+            UniOpKind::BitNot => buf.push_str("\tnotq\t%rax\n"),
+        }
+    }
+
+    fn compile_binop(&mut self, op: &BinOpKind, buf: &mut String) {
+        match op {
+            BinOpKind::Add => buf.push_str("\taddq\t%rcx, %rax\n\tjo\t.Loverflow\n"),
+            BinOpKind::Sub => buf.push_str("\tsubq\t%rcx, %rax\n\tjo\t.Loverflow\n"),
+            BinOpKind::Mult => buf.push_str("\timulq\t%rcx, %rax\n\tjo\t.Loverflow\n"),
+            BinOpKind::Div => self.div(buf),
+            BinOpKind::BitXor => buf.push_str("\txorq\t%rcx, %rax\n"),
+            BinOpKind::Pow => {
+                self.uses_pow = true;
+                buf.push_str("\tcall\t.Lpow\n");
+            },
+            // a ± b% は a ± (a * b)%、a * b% は (a * b)%。左辺は%rsiに退避しておく
+            BinOpKind::AddPercent | BinOpKind::SubPercent | BinOpKind::MultPercent => {
+                buf.push_str("\tmovq\t%rax, %rsi\n\timulq\t%rcx, %rax\n\tjo\t.Loverflow\n");
+                buf.push_str("\tmovl\t$100, %ecx\n\tcqto\n\tidivq\t%rcx\n");
+                match op {
+                    BinOpKind::AddPercent => buf.push_str("\taddq\t%rsi, %rax\n\tjo\t.Loverflow\n"),
+                    BinOpKind::SubPercent => {
+                        buf.push_str("\tmovq\t%rax, %rcx\n\tmovq\t%rsi, %rax\n");
+                        buf.push_str("\tsubq\t%rcx, %rax\n\tjo\t.Loverflow\n");
+                    },
+                    _ => (),
+                }
+            },
+            // a / b% は a * 100 / b
+            BinOpKind::DivPercent => {
+                buf.push_str("\timulq\t$100, %rax, %rax\n\tjo\t.Loverflow\n");
+                self.div(buf)
+            },
+        }
+    }
+
+    /// %raxを%rcxで割った商を%raxに求める命令を出力する
+    fn div(&mut self, buf: &mut String) {
+        // MIN / -1 はidivが例外を起こすので先に調べる
+        let label = self.label();
+        buf.push_str("\ttestq\t%rcx, %rcx\n\tje\t.Ldivzero\n");
+        buf.push_str(&format!("\tcmpq\t$-1, %rcx\n\tjne\t{}\n", label));
+        buf.push_str("\tmovabsq\t$-9223372036854775808, %rdx\n");
+        buf.push_str("\tcmpq\t%rdx, %rax\n\tje\t.Loverflow\n");
+        buf.push_str(&format!("{}:\n\tcqto\n\tidivq\t%rcx\n", label));
+    }
+
+    fn label(&mut self) -> String {
+        let label = format!(".L{}", self.labels);
+        self.labels += 1;
+        label
+    }
+}
+
+/// 底を%rax、指数を%rcxで受け取り、べき乗を%raxに返すサブルーチン
+/// エラーのときは戻らずに.Lfailへ進み、%rspを戻してから終了する
+const POW: &str = "\
+.Lpow:
+\ttestq\t%rax, %rax
+\tjne\t.Lpow_unit
+\t# 0^0 is 1, 0 to a negative power is a division by zero
+\ttestq\t%rcx, %rcx
+\tjs\t.Ldivzero
+\tsete\t%al
+\tret
+.Lpow_unit:
+\tcmpq\t$1, %rax
+\tje\t.Lpow_ret
+\tcmpq\t$-1, %rax
+\tjne\t.Lpow_frac
+\t# -1 to an odd power is -1, to an even power 1
+\ttestb\t$1, %cl
+\tjne\t.Lpow_ret
+\tnegq\t%rax
+\tret
+.Lpow_frac:
+\t# a negative exponent gives 1 / l^(-r), which truncates to 0
+\ttestq\t%rcx, %rcx
+\tjns\t.Lpow_start
+\txorl\t%eax, %eax
+\tret
+.Lpow_start:
+\tmovq\t%rax, %rdx
+\tmovl\t$1, %eax
+.Lpow_loop:
+\ttestq\t%rcx, %rcx
+\tje\t.Lpow_ret
+\timulq\t%rdx, %rax
+\tjo\t.Loverflow
+\tdecq\t%rcx
+\tjmp\t.Lpow_loop
+.Lpow_ret:
+\tret
+";
+
+#[test]
+fn test_x86_64() {
+    use std::{fs, process::Command};
+    use crate::interpreter::Interpreter;
+
+    // (式, ゴールデンファイル, 試す変数の値)
+    let cases: [(&str, &str, &[&[i64]]); 4] = [
+        ("1 + 2 * 3 - -10", include_str!("../golden/x86_64/constant.s"), &[&[]]),
+        (
+            "(x + 1) * y / (x - y)",
+            include_str!("../golden/x86_64/vars.s"),
+            &[&[5, 2], &[3, 3], &[i64::MAX, 1], &[-7, 4]],
+        ),
+        ("x! + y%", include_str!("../golden/x86_64/postfix.s"), &[&[5, 250], &[-1, 0], &[21, -199], &[i64::MAX, 0]]),
+        (
+            "-x ^ y + !~x",
+            include_str!("../golden/x86_64/unary.s"),
+            &[&[3, 3], &[-2, 63], &[2, 63], &[0, -1], &[-1, -3], &[7, -2], &[i64::MAX, 0]],
+        ),
+    ];
+
+    let mut compiler = X86_64Compiler::new();
+    for (input, golden, _) in &cases {
+        let ast = input.parse::<Ast>().unwrap();
+        assert_eq!(&compiler.compile(&ast).unwrap(), golden, "{}", input);
+    }
+
+    // x86-64のLinuxでアセンブラがあれば、ゴールデンファイルを実際に動かしてInterpreterと結果を比べる
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux"))
+        || Command::new("cc").arg("--version").output().is_err()
+    {
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("parser-x86-64-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (i, (input, golden, values)) in cases.iter().enumerate() {
+        let ast = input.parse::<Ast>().unwrap();
+        let src = dir.join(format!("case{}.s", i));
+        let exe = dir.join(format!("case{}", i));
+        fs::write(&src, golden).unwrap();
+        let status = Command::new("cc").arg("-o").arg(&exe).arg(&src).status().unwrap();
+        assert!(status.success(), "{}", input);
+
+        for values in values.iter() {
+            let mut interp = Interpreter::new();
+            for (var, v) in ast.vars().iter().zip(values.iter()) {
+                interp.set_var(var, *v);
+            }
+            let output = Command::new(&exe).args(values.iter().map(i64::to_string)).output().unwrap();
+            match interp.eval(&ast) {
+                Ok(n) => assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{}\n", n)),
+                Err(e) => {
+                    assert_eq!(output.status.code(), Some(1));
+                    assert_eq!(String::from_utf8(output.stderr).unwrap(), format!("{}\n", e));
+                },
+            }
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_x86_64_literals() {
+    let body = |input: &str| {
+        let asm = X86_64Compiler::new().compile(&input.parse::<Ast>().unwrap()).unwrap();
+        asm[asm.find("jne\t.Lusage\n").unwrap() + 12..asm.find("\tmovq\t%rax, %rsi\n").unwrap()].to_string()
+    };
+    assert_eq!(body("-9223372036854775808"), "\tmovabsq\t$-9223372036854775808, %rax\n");
+    assert_eq!(body("--9223372036854775808"), "\tmovabsq\t$-9223372036854775808, %rax\n\tnegq\t%rax\n\tjo\t.Loverflow\n");
+    // i64に収まらない数値は、評価した時点でオーバーフローとする
+    assert_eq!(body("9223372036854775808"), "\tjmp\t.Loverflow\n");
+}
+
+#[test]
+fn test_x86_64_deep() {
+    use crate::parser::UniOp;
+    use crate::utils::Loc;
+
+    // ~~...~1 のように深くネストした式も再帰せずにコンパイルできる
+    let mut ast = Ast::num(1, Loc(0, 1));
+    for _ in 0..100_000 {
+        ast = Ast::uniop(UniOp::new(UniOpKind::BitNot, Loc(0, 0)), ast, Loc(0, 0));
+    }
+    let asm = X86_64Compiler::new().compile(&ast).unwrap();
+    assert_eq!(asm.matches("\tnotq\t%rax\n").count(), 100_000);
+}